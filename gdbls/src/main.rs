@@ -1,32 +1,224 @@
-use language_model::{CursorPosition, Semantics};
+use language_model::{
+    Completion, Completions, CursorPosition, CursorRange, HighlightKind, PathCompletion,
+    PositionEncoding, SemanticToken, SemanticTokenType, Semantics, SemanticsBuilder, Severity,
+    Symbol, SymbolKind,
+};
 
-use std::{env, error::Error, fs, path::PathBuf};
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    process,
+};
 
-use lsp_server::{Connection, Message, RequestId, Response};
+use crossbeam_channel::SendError;
+use lsp_server::{Connection, ErrorCode, IoThreads, Message, ProtocolError, RequestId, Response};
 use lsp_types::{
-    notification, request, CompletionItem, CompletionOptions, CompletionResponse,
-    GotoDefinitionResponse, InitializeParams, OneOf, ServerCapabilities,
-    TextDocumentSyncCapability, TextDocumentSyncKind,
+    notification, request, CodeAction, CodeActionKind, CodeActionOrCommand,
+    CodeActionProviderCapability, CodeActionResponse, CompletionItem, CompletionItemKind,
+    CompletionOptions, CompletionResponse, CompletionTextEdit, DiagnosticRelatedInformation,
+    DiagnosticSeverity, DidChangeTextDocumentParams, DocumentHighlight, DocumentHighlightKind,
+    DocumentSymbol, DocumentSymbolResponse, Documentation, FoldingRange,
+    FoldingRangeProviderCapability, GotoDefinitionResponse, Hover, HoverContents,
+    HoverProviderCapability, InitializeParams, LinkedEditingRangeServerCapabilities,
+    LinkedEditingRanges, LogMessageParams, MarkupContent, MarkupKind, MessageType, OneOf,
+    ParameterInformation, ParameterLabel, PrepareRenameResponse, PublishDiagnosticsParams,
+    RenameOptions, SemanticTokens, SemanticTokensFullOptions, SemanticTokensLegend,
+    SemanticTokensOptions, SemanticTokensResult, SemanticTokensServerCapabilities,
+    ServerCapabilities, SignatureHelp, SignatureHelpOptions, SignatureInformation,
+    SymbolInformation, TextDocumentContentChangeEvent, TextDocumentIdentifier,
+    TextDocumentSyncCapability, TextDocumentSyncKind, TextEdit, Url, WorkDoneProgressOptions,
+    WorkspaceEdit,
 };
+use serde::{Deserialize, Serialize};
+
+const USAGE: &str = "usage: gdbls [--stdio | --socket <port>]\n       gdbls check <file>";
+
+/// What the command line asks `gdbls` to do.
+#[derive(Debug, PartialEq)]
+enum Mode {
+    /// Run the language server.
+    Serve(Transport),
+    /// Print the diagnostics of the given file, without a client.
+    Check(PathBuf),
+}
+
+/// How the server communicates with the client.
+#[derive(Debug, PartialEq)]
+enum Transport {
+    /// Over stdin and stdout, which is the default.
+    Stdio,
+    /// Over a TCP connection to the given port on localhost, where the client
+    /// must already be listening.
+    Socket(u16),
+}
+
+/// An error which stops the server.
+#[derive(Debug)]
+enum GdblsError {
+    Io(io::Error),
+    /// The connection to the client failed, or the client broke the protocol,
+    /// such as by closing the connection during initialization.
+    Protocol(String),
+    /// The initialize params from the client couldn't be deserialized. Other
+    /// messages with invalid params are reported to the client and skipped.
+    Serde(serde_json::Error),
+}
+
+impl fmt::Display for GdblsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GdblsError::Io(error) => write!(f, "IO error: {}", error),
+            GdblsError::Protocol(message) => write!(f, "protocol error: {}", message),
+            GdblsError::Serde(error) => write!(f, "invalid params: {}", error),
+        }
+    }
+}
+
+impl Error for GdblsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GdblsError::Io(error) => Some(error),
+            GdblsError::Protocol(_) => None,
+            GdblsError::Serde(error) => Some(error),
+        }
+    }
+}
+
+impl From<io::Error> for GdblsError {
+    fn from(error: io::Error) -> Self {
+        GdblsError::Io(error)
+    }
+}
+
+impl From<ProtocolError> for GdblsError {
+    fn from(error: ProtocolError) -> Self {
+        GdblsError::Protocol(error.to_string())
+    }
+}
+
+impl From<SendError<Message>> for GdblsError {
+    fn from(_: SendError<Message>) -> Self {
+        GdblsError::Protocol("the connection to the client is closed".to_owned())
+    }
+}
+
+impl From<serde_json::Error> for GdblsError {
+    fn from(error: serde_json::Error) -> Self {
+        GdblsError::Serde(error)
+    }
+}
+
+/// An open document, as last sent by the client.
+#[derive(Debug, Default)]
+struct Document {
+    /// The version of the last change applied to the text.
+    version: i32,
+    text: String,
+}
+
+/// The `textDocument/inlayHint` request, which `lsp_types` predates.
+enum InlayHintRequest {}
+
+impl request::Request for InlayHintRequest {
+    type Params = InlayHintParams;
+    type Result = Option<Vec<InlayHint>>;
+    const METHOD: &'static str = "textDocument/inlayHint";
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InlayHintParams {
+    text_document: TextDocumentIdentifier,
+    /// The visible part of the document, which hints are needed for.
+    range: lsp_types::Range,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InlayHint {
+    position: lsp_types::Position,
+    label: String,
+    padding_left: bool,
+}
 
 fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
+    let transport = match parse_args(env::args().skip(1)) {
+        Ok(Mode::Serve(transport)) => transport,
+        Ok(Mode::Check(path)) => {
+            let (diagnostics, has_errors) = check(&path, &env::current_dir()?)?;
+            for diagnostic in diagnostics {
+                println!("{}", diagnostic);
+            }
+            process::exit(if has_errors { 1 } else { 0 });
+        }
+        Err(error) => {
+            eprintln!("{}\n{}", error, USAGE);
+            process::exit(2);
+        }
+    };
+
     eprintln!("starting generic LSP server");
 
-    let (connection, io_threads) = Connection::stdio();
+    let (connection, io_threads) = connect(&transport);
 
     let server_capabilities = {
-        let mut cap = ServerCapabilities::default();
-        cap.definition_provider = Some(OneOf::Left(true));
-
-        cap.text_document_sync = Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::Full));
-
-        cap.completion_provider = Some(CompletionOptions::default());
+        let cap = ServerCapabilities {
+            definition_provider: Some(OneOf::Left(true)),
+            text_document_sync: Some(TextDocumentSyncCapability::Kind(
+                TextDocumentSyncKind::Incremental,
+            )),
+            completion_provider: Some(CompletionOptions {
+                trigger_characters: Some(vec!["$".to_owned()]),
+                resolve_provider: Some(true),
+                ..CompletionOptions::default()
+            }),
+            references_provider: Some(OneOf::Left(true)),
+            document_highlight_provider: Some(OneOf::Left(true)),
+            linked_editing_range_provider: Some(LinkedEditingRangeServerCapabilities::Simple(true)),
+            code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+            hover_provider: Some(HoverProviderCapability::Simple(true)),
+            folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+            signature_help_provider: Some(SignatureHelpOptions {
+                trigger_characters: Some(vec![" ".to_owned()]),
+                retrigger_characters: None,
+                work_done_progress_options: WorkDoneProgressOptions::default(),
+            }),
+            document_symbol_provider: Some(OneOf::Left(true)),
+            workspace_symbol_provider: Some(OneOf::Left(true)),
+            rename_provider: Some(OneOf::Right(RenameOptions {
+                prepare_provider: Some(true),
+                work_done_progress_options: WorkDoneProgressOptions::default(),
+            })),
+            semantic_tokens_provider: Some(
+                SemanticTokensServerCapabilities::SemanticTokensOptions(SemanticTokensOptions {
+                    work_done_progress_options: WorkDoneProgressOptions::default(),
+                    legend: semantic_tokens_legend(),
+                    range: None,
+                    full: Some(SemanticTokensFullOptions::Bool(true)),
+                }),
+            ),
+            ..ServerCapabilities::default()
+        };
 
         serde_json::to_value(&cap).unwrap()
     };
 
-    let initialization_params = connection.initialize(server_capabilities)?;
-    main_loop(&connection, initialization_params)?;
+    let (initialize_id, initialization_params) = connection.initialize_start()?;
+    let position_encoding = negotiate_position_encoding(&initialization_params);
+    // `lsp_types` predates position encoding negotiation and inlay hints, so
+    // these are added to the serialized capabilities directly.
+    let mut server_capabilities = server_capabilities;
+    server_capabilities["positionEncoding"] =
+        serde_json::Value::from(position_encoding_kind(position_encoding));
+    server_capabilities["inlayHintProvider"] = serde_json::Value::from(true);
+    connection.initialize_finish(
+        initialize_id,
+        serde_json::json!({ "capabilities": server_capabilities }),
+    )?;
+    main_loop(&connection, initialization_params, position_encoding)?;
     io_threads.join()?;
 
     eprintln!("shutting down server");
@@ -36,171 +228,2308 @@ fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
 fn main_loop(
     connection: &Connection,
     params: serde_json::Value,
-) -> Result<(), Box<dyn Error + Sync + Send>> {
-    let _params: InitializeParams = serde_json::from_value(params).unwrap();
-    eprintln!("starting main loop");
+    position_encoding: PositionEncoding,
+) -> Result<(), GdblsError> {
+    let params: InitializeParams = serde_json::from_value(params)?;
+    log_message(
+        connection,
+        MessageType::Info,
+        "starting main loop".to_owned(),
+    );
 
-    let mut semantics = Semantics::new(env::current_dir()?);
+    let project_root = project_root(&params)?;
+    let mut builder = SemanticsBuilder::new(project_root.clone())
+        .position_encoding(position_encoding)
+        .extra_commands(extra_commands_option(&params));
+    if let Some(tab_width) = tab_width_option(&params) {
+        builder = builder.tab_width(tab_width);
+    }
+    let mut semantics = builder.build();
+    if load_gdbinit_requested(&params) {
+        preload_gdbinit(&mut semantics, &project_root);
+    }
+    // Each open document, which incremental changes are applied to.
+    let mut documents: HashMap<Url, Document> = HashMap::new();
+    // Once the client asks the server to shut down, the only message it should
+    // send is the `exit` notification.
+    let mut shutdown_received = false;
 
     for msg in &connection.receiver {
         match msg {
             Message::Request(req) => {
-                eprintln!("got request: {:?}", req);
+                log_message(
+                    connection,
+                    MessageType::Log,
+                    format!("got request #{}: {}", req.id, req.method),
+                );
 
-                if connection.handle_shutdown(&req)? {
-                    return Ok(());
+                if shutdown_received {
+                    let resp = Response::new_err(
+                        req.id,
+                        ErrorCode::InvalidRequest as i32,
+                        "the server is shutting down".to_owned(),
+                    );
+                    connection.sender.send(Message::Response(resp))?;
+                    continue;
                 }
 
-                let req = match cast_request::<request::GotoDefinition>(req) {
-                    Ok((id, params)) => {
-                        eprintln!("got GotoDefinition request #{}: {:?}", id, params);
-                        let result = match semantics.find_definition(CursorPosition {
-                            file: &params
-                                .text_document_position_params
-                                .text_document
-                                .uri
-                                .to_file_path()
-                                .unwrap(),
+                let req = match cast_request::<request::Shutdown>(connection, req)? {
+                    Cast::Matched((id, ())) => {
+                        log_message(
+                            connection,
+                            MessageType::Info,
+                            format!("got Shutdown request #{}", id),
+                        );
+                        shutdown_received = true;
+                        connection
+                            .sender
+                            .send(Message::Response(Response::new_ok(id, ())))?;
+                        continue;
+                    }
+                    Cast::Other(req) => req,
+                    Cast::Invalid => continue,
+                };
+
+                let req = match cast_request::<request::GotoDefinition>(connection, req)? {
+                    Cast::Matched((id, params)) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!(
+                                "got GotoDefinition request #{} for {}",
+                                id, params.text_document_position_params.text_document.uri
+                            ),
+                        );
+                        let path = match uri_to_path(
+                            &params.text_document_position_params.text_document.uri,
+                        ) {
+                            Some(path) => path,
+                            None => {
+                                let resp = unsupported_uri_response(
+                                    id,
+                                    &params.text_document_position_params.text_document.uri,
+                                );
+                                connection.sender.send(Message::Response(resp))?;
+                                continue;
+                            }
+                        };
+                        let definition = semantics.find_definition(CursorPosition {
+                            file: &path,
                             line: params.text_document_position_params.position.line as usize,
                             column: params.text_document_position_params.position.character
                                 as usize,
-                        }) {
-                            Some(definition_position) => {
-                                let pos = lsp_types::Position {
-                                    line: definition_position.line as u32,
-                                    character: definition_position.column as u32,
-                                };
-                                // We are using an empty range here to indicate a specific
-                                // location.
-                                let range = lsp_types::Range {
-                                    start: pos,
-                                    end: pos,
-                                };
-                                let result =
-                                    Some(GotoDefinitionResponse::from(lsp_types::Location::new(
-                                        lsp_types::Url::from_file_path(definition_position.file)
-                                            .unwrap(),
-                                        range,
-                                    )));
-                                Some(serde_json::to_value(&result).unwrap())
-                            }
-                            None => None,
-                        };
-                        // TODO we must always return either a result or an error
-                        //
-                        // If we don't find the definition, is that supposed to be
-                        // represented as an empty result or as an error?
+                        });
+                        let resp = goto_definition_response(
+                            id,
+                            &path,
+                            semantics.has_file(&path),
+                            definition,
+                        );
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
+                    Cast::Other(req) => req,
+                    Cast::Invalid => continue,
+                };
+                let req = match cast_request::<request::Completion>(connection, req)? {
+                    Cast::Matched((id, params)) => {
+                        let path =
+                            match uri_to_path(&params.text_document_position.text_document.uri) {
+                                Some(path) => path,
+                                None => {
+                                    let resp = unsupported_uri_response(
+                                        id,
+                                        &params.text_document_position.text_document.uri,
+                                    );
+                                    connection.sender.send(Message::Response(resp))?;
+                                    continue;
+                                }
+                            };
+                        let position = params.text_document_position.position;
+                        let completions = semantics.find_completions(CursorPosition {
+                            file: &path,
+                            line: position.line as usize,
+                            column: position.character as usize,
+                        });
+                        let result = completion_items(
+                            completions,
+                            &params.text_document_position.text_document.uri,
+                            position,
+                            position_encoding,
+                        );
                         let resp = Response {
                             id,
-                            result,
+                            result: Some(
+                                serde_json::to_value(CompletionResponse::Array(result)).unwrap(),
+                            ),
                             error: None,
                         };
                         connection.sender.send(Message::Response(resp))?;
                         continue;
                     }
-                    Err(req) => req,
+                    Cast::Other(req) => req,
+                    Cast::Invalid => continue,
                 };
-                let _req = match cast_request::<request::Completion>(req) {
-                    Ok((id, params)) => {
-                        let completions = semantics.find_completions(CursorPosition {
-                            file: &params
-                                .text_document_position
-                                .text_document
-                                .uri
-                                .to_file_path()
-                                .unwrap(),
+                let req = match cast_request::<request::ResolveCompletionItem>(connection, req)? {
+                    Cast::Matched((id, mut item)) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!(
+                                "got ResolveCompletionItem request #{} for `{}`",
+                                id, item.label
+                            ),
+                        );
+                        // The document the item was completed in is stored in its data,
+                        // since the definition it refers to depends on what that
+                        // document sources.
+                        let path = item
+                            .data
+                            .as_ref()
+                            .and_then(|data| data["uri"].as_str())
+                            .and_then(|uri| Url::parse(uri).ok())
+                            .and_then(|uri| uri_to_path(&uri));
+                        if let Some(documentation) = path
+                            .and_then(|path| semantics.command_documentation(&path, &item.label))
+                        {
+                            item.documentation =
+                                Some(Documentation::MarkupContent(MarkupContent {
+                                    kind: MarkupKind::Markdown,
+                                    value: documentation,
+                                }));
+                        }
+                        let resp = Response::new_ok(id, item);
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
+                    Cast::Other(req) => req,
+                    Cast::Invalid => continue,
+                };
+                let req = match cast_request::<request::DocumentHighlightRequest>(connection, req)?
+                {
+                    Cast::Matched((id, params)) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!(
+                                "got DocumentHighlight request #{} for {}",
+                                id, params.text_document_position_params.text_document.uri
+                            ),
+                        );
+                        let path = match uri_to_path(
+                            &params.text_document_position_params.text_document.uri,
+                        ) {
+                            Some(path) => path,
+                            None => {
+                                let resp = unsupported_uri_response(
+                                    id,
+                                    &params.text_document_position_params.text_document.uri,
+                                );
+                                connection.sender.send(Message::Response(resp))?;
+                                continue;
+                            }
+                        };
+                        let highlights = semantics.document_highlights(CursorPosition {
+                            file: &path,
+                            line: params.text_document_position_params.position.line as usize,
+                            column: params.text_document_position_params.position.character
+                                as usize,
+                        });
+                        let result = highlights
+                            .into_iter()
+                            .map(to_lsp_document_highlight)
+                            .collect::<Vec<DocumentHighlight>>();
+                        let resp = Response::new_ok(id, result);
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
+                    Cast::Other(req) => req,
+                    Cast::Invalid => continue,
+                };
+                let req = match cast_request::<request::LinkedEditingRange>(connection, req)? {
+                    Cast::Matched((id, params)) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!(
+                                "got LinkedEditingRange request #{} for {}",
+                                id, params.text_document_position_params.text_document.uri
+                            ),
+                        );
+                        let path = match uri_to_path(
+                            &params.text_document_position_params.text_document.uri,
+                        ) {
+                            Some(path) => path,
+                            None => {
+                                let resp = unsupported_uri_response(
+                                    id,
+                                    &params.text_document_position_params.text_document.uri,
+                                );
+                                connection.sender.send(Message::Response(resp))?;
+                                continue;
+                            }
+                        };
+                        let result = semantics
+                            .linked_editing_ranges(CursorPosition {
+                                file: &path,
+                                line: params.text_document_position_params.position.line as usize,
+                                column: params.text_document_position_params.position.character
+                                    as usize,
+                            })
+                            .map(|ranges| LinkedEditingRanges {
+                                ranges: ranges.into_iter().map(to_lsp_range).collect(),
+                                word_pattern: None,
+                            });
+                        let resp = Response::new_ok(id, result);
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
+                    Cast::Other(req) => req,
+                    Cast::Invalid => continue,
+                };
+                let req = match cast_request::<request::References>(connection, req)? {
+                    Cast::Matched((id, params)) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!(
+                                "got References request #{} for {}",
+                                id, params.text_document_position.text_document.uri
+                            ),
+                        );
+                        let path =
+                            match uri_to_path(&params.text_document_position.text_document.uri) {
+                                Some(path) => path,
+                                None => {
+                                    let resp = unsupported_uri_response(
+                                        id,
+                                        &params.text_document_position.text_document.uri,
+                                    );
+                                    connection.sender.send(Message::Response(resp))?;
+                                    continue;
+                                }
+                            };
+                        let references = semantics.find_references(CursorPosition {
+                            file: &path,
                             line: params.text_document_position.position.line as usize,
                             column: params.text_document_position.position.character as usize,
                         });
-                        let result = completions
-                            .user_provided
+                        // An empty array is returned rather than null when there are
+                        // no references.
+                        let result = references
                             .into_iter()
-                            .chain(completions.built_in.into_iter())
-                            .map(|completion| {
-                                CompletionItem::new_simple(completion.text, String::new())
+                            .map(|reference| {
+                                lsp_types::Location::new(
+                                    lsp_types::Url::from_file_path(reference.start.file).unwrap(),
+                                    to_lsp_range(reference),
+                                )
                             })
-                            .collect::<Vec<CompletionItem>>();
+                            .collect::<Vec<lsp_types::Location>>();
                         let resp = Response {
                             id,
-                            result: Some(
-                                serde_json::to_value(&CompletionResponse::Array(result)).unwrap(),
+                            result: Some(serde_json::to_value(result).unwrap()),
+                            error: None,
+                        };
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
+                    Cast::Other(req) => req,
+                    Cast::Invalid => continue,
+                };
+                let req = match cast_request::<request::HoverRequest>(connection, req)? {
+                    Cast::Matched((id, params)) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!(
+                                "got Hover request #{} for {}",
+                                id, params.text_document_position_params.text_document.uri
                             ),
+                        );
+                        let path = match uri_to_path(
+                            &params.text_document_position_params.text_document.uri,
+                        ) {
+                            Some(path) => path,
+                            None => {
+                                let resp = unsupported_uri_response(
+                                    id,
+                                    &params.text_document_position_params.text_document.uri,
+                                );
+                                connection.sender.send(Message::Response(resp))?;
+                                continue;
+                            }
+                        };
+                        let hover = semantics
+                            .hover(CursorPosition {
+                                file: &path,
+                                line: params.text_document_position_params.position.line as usize,
+                                column: params.text_document_position_params.position.character
+                                    as usize,
+                            })
+                            .map(|hover| Hover {
+                                contents: HoverContents::Markup(MarkupContent {
+                                    kind: MarkupKind::Markdown,
+                                    value: hover.contents,
+                                }),
+                                range: None,
+                            });
+                        // A missing hover is serialized as null.
+                        let resp = Response {
+                            id,
+                            result: Some(serde_json::to_value(hover).unwrap()),
+                            error: None,
+                        };
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
+                    Cast::Other(req) => req,
+                    Cast::Invalid => continue,
+                };
+                let req = match cast_request::<request::SignatureHelpRequest>(connection, req)? {
+                    Cast::Matched((id, params)) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!(
+                                "got SignatureHelp request #{} for {}",
+                                id, params.text_document_position_params.text_document.uri
+                            ),
+                        );
+                        let path = match uri_to_path(
+                            &params.text_document_position_params.text_document.uri,
+                        ) {
+                            Some(path) => path,
+                            None => {
+                                let resp = unsupported_uri_response(
+                                    id,
+                                    &params.text_document_position_params.text_document.uri,
+                                );
+                                connection.sender.send(Message::Response(resp))?;
+                                continue;
+                            }
+                        };
+                        let signature_help = semantics
+                            .signature_help(CursorPosition {
+                                file: &path,
+                                line: params.text_document_position_params.position.line as usize,
+                                column: params.text_document_position_params.position.character
+                                    as usize,
+                            })
+                            .map(|signature_help| SignatureHelp {
+                                signatures: vec![SignatureInformation {
+                                    label: signature_help.label,
+                                    documentation: None,
+                                    parameters: Some(
+                                        signature_help
+                                            .parameters
+                                            .into_iter()
+                                            .map(|parameter| ParameterInformation {
+                                                label: ParameterLabel::Simple(parameter),
+                                                documentation: None,
+                                            })
+                                            .collect(),
+                                    ),
+                                    active_parameter: None,
+                                }],
+                                active_signature: Some(0),
+                                active_parameter: signature_help
+                                    .active_parameter
+                                    .map(|active_parameter| active_parameter as u32),
+                            });
+                        // Missing signature help is serialized as null.
+                        let resp = Response {
+                            id,
+                            result: Some(serde_json::to_value(signature_help).unwrap()),
                             error: None,
                         };
                         connection.sender.send(Message::Response(resp))?;
                         continue;
                     }
-                    Err(req) => req,
+                    Cast::Other(req) => req,
+                    Cast::Invalid => continue,
                 };
+                let req = match cast_request::<request::DocumentSymbolRequest>(connection, req)? {
+                    Cast::Matched((id, params)) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!(
+                                "got DocumentSymbol request #{} for {}",
+                                id, params.text_document.uri
+                            ),
+                        );
+                        let path = match uri_to_path(&params.text_document.uri) {
+                            Some(path) => path,
+                            None => {
+                                let resp = unsupported_uri_response(id, &params.text_document.uri);
+                                connection.sender.send(Message::Response(resp))?;
+                                continue;
+                            }
+                        };
+                        let symbols = semantics
+                            .document_symbols(&path)
+                            .into_iter()
+                            .map(to_lsp_document_symbol)
+                            .collect();
+                        let result = DocumentSymbolResponse::Nested(symbols);
+                        let resp = Response {
+                            id,
+                            result: Some(serde_json::to_value(result).unwrap()),
+                            error: None,
+                        };
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
+                    Cast::Other(req) => req,
+                    Cast::Invalid => continue,
+                };
+                let req = match cast_request::<request::WorkspaceSymbol>(connection, req)? {
+                    Cast::Matched((id, params)) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!("got WorkspaceSymbol request #{} for `{}`", id, params.query),
+                        );
+                        let symbols = semantics
+                            .workspace_symbols(&params.query)
+                            .into_iter()
+                            .map(to_lsp_symbol_information)
+                            .collect::<Vec<SymbolInformation>>();
+                        let resp = Response {
+                            id,
+                            result: Some(serde_json::to_value(symbols).unwrap()),
+                            error: None,
+                        };
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
+                    Cast::Other(req) => req,
+                    Cast::Invalid => continue,
+                };
+                let req = match cast_request::<request::PrepareRenameRequest>(connection, req)? {
+                    Cast::Matched((id, params)) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!(
+                                "got PrepareRename request #{} for {}",
+                                id, params.text_document.uri
+                            ),
+                        );
+                        let path = match uri_to_path(&params.text_document.uri) {
+                            Some(path) => path,
+                            None => {
+                                let resp = unsupported_uri_response(id, &params.text_document.uri);
+                                connection.sender.send(Message::Response(resp))?;
+                                continue;
+                            }
+                        };
+                        let range = semantics
+                            .prepare_rename(CursorPosition {
+                                file: &path,
+                                line: params.position.line as usize,
+                                column: params.position.character as usize,
+                            })
+                            .map(|range| PrepareRenameResponse::Range(to_lsp_range(range)));
+                        // Responding with null tells the editor the rename isn't valid.
+                        let resp = Response {
+                            id,
+                            result: Some(serde_json::to_value(range).unwrap()),
+                            error: None,
+                        };
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
+                    Cast::Other(req) => req,
+                    Cast::Invalid => continue,
+                };
+                let req = match cast_request::<request::CodeActionRequest>(connection, req)? {
+                    Cast::Matched((id, params)) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!(
+                                "got CodeAction request #{} for {}",
+                                id, params.text_document.uri
+                            ),
+                        );
+                        let path = match uri_to_path(&params.text_document.uri) {
+                            Some(path) => path,
+                            None => {
+                                let resp = unsupported_uri_response(id, &params.text_document.uri);
+                                connection.sender.send(Message::Response(resp))?;
+                                continue;
+                            }
+                        };
+                        let position = |position: lsp_types::Position| CursorPosition {
+                            file: &path,
+                            line: position.line as usize,
+                            column: position.character as usize,
+                        };
+                        let result = semantics
+                            .code_actions(CursorRange {
+                                start: position(params.range.start),
+                                end: position(params.range.end),
+                            })
+                            .into_iter()
+                            .map(to_lsp_code_action)
+                            .collect::<CodeActionResponse>();
+                        let resp = Response {
+                            id,
+                            result: Some(serde_json::to_value(result).unwrap()),
+                            error: None,
+                        };
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
+                    Cast::Other(req) => req,
+                    Cast::Invalid => continue,
+                };
+                let req = match cast_request::<InlayHintRequest>(connection, req)? {
+                    Cast::Matched((id, params)) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!(
+                                "got InlayHint request #{} for {}",
+                                id, params.text_document.uri
+                            ),
+                        );
+                        let path = match uri_to_path(&params.text_document.uri) {
+                            Some(path) => path,
+                            None => {
+                                let resp = unsupported_uri_response(id, &params.text_document.uri);
+                                connection.sender.send(Message::Response(resp))?;
+                                continue;
+                            }
+                        };
+                        let position = |position: lsp_types::Position| CursorPosition {
+                            file: &path,
+                            line: position.line as usize,
+                            column: position.character as usize,
+                        };
+                        let result = semantics
+                            .inlay_hints(CursorRange {
+                                start: position(params.range.start),
+                                end: position(params.range.end),
+                            })
+                            .into_iter()
+                            .map(to_lsp_inlay_hint)
+                            .collect::<Vec<InlayHint>>();
+                        let resp = Response {
+                            id,
+                            result: Some(serde_json::to_value(result).unwrap()),
+                            error: None,
+                        };
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
+                    Cast::Other(req) => req,
+                    Cast::Invalid => continue,
+                };
+                let req = match cast_request::<request::Rename>(connection, req)? {
+                    Cast::Matched((id, params)) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!(
+                                "got Rename request #{} for {}",
+                                id, params.text_document_position.text_document.uri
+                            ),
+                        );
+                        let path =
+                            match uri_to_path(&params.text_document_position.text_document.uri) {
+                                Some(path) => path,
+                                None => {
+                                    let resp = unsupported_uri_response(
+                                        id,
+                                        &params.text_document_position.text_document.uri,
+                                    );
+                                    connection.sender.send(Message::Response(resp))?;
+                                    continue;
+                                }
+                            };
+                        let workspace_edit = semantics
+                            .rename(
+                                CursorPosition {
+                                    file: &path,
+                                    line: params.text_document_position.position.line as usize,
+                                    column: params.text_document_position.position.character
+                                        as usize,
+                                },
+                                &params.new_name,
+                            )
+                            .map(to_lsp_workspace_edit);
+                        // A rename which isn't possible is serialized as null.
+                        let resp = Response {
+                            id,
+                            result: Some(serde_json::to_value(workspace_edit).unwrap()),
+                            error: None,
+                        };
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
+                    Cast::Other(req) => req,
+                    Cast::Invalid => continue,
+                };
+                let req = match cast_request::<request::FoldingRangeRequest>(connection, req)? {
+                    Cast::Matched((id, params)) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!(
+                                "got FoldingRange request #{} for {}",
+                                id, params.text_document.uri
+                            ),
+                        );
+                        let path = match uri_to_path(&params.text_document.uri) {
+                            Some(path) => path,
+                            None => {
+                                let resp = unsupported_uri_response(id, &params.text_document.uri);
+                                connection.sender.send(Message::Response(resp))?;
+                                continue;
+                            }
+                        };
+                        let result = semantics
+                            .folding_ranges(&path)
+                            .into_iter()
+                            .map(|folding_range| FoldingRange {
+                                start_line: folding_range.start_line as u32,
+                                start_character: None,
+                                end_line: folding_range.end_line as u32,
+                                end_character: None,
+                                kind: None,
+                            })
+                            .collect::<Vec<FoldingRange>>();
+                        let resp = Response {
+                            id,
+                            result: Some(serde_json::to_value(result).unwrap()),
+                            error: None,
+                        };
+                        connection.sender.send(Message::Response(resp))?;
+                        continue;
+                    }
+                    Cast::Other(req) => req,
+                    Cast::Invalid => continue,
+                };
+                let _req =
+                    match cast_request::<request::SemanticTokensFullRequest>(connection, req)? {
+                        Cast::Matched((id, params)) => {
+                            log_message(
+                                connection,
+                                MessageType::Log,
+                                format!(
+                                    "got SemanticTokensFull request #{} for {}",
+                                    id, params.text_document.uri
+                                ),
+                            );
+                            let path = match uri_to_path(&params.text_document.uri) {
+                                Some(path) => path,
+                                None => {
+                                    let resp =
+                                        unsupported_uri_response(id, &params.text_document.uri);
+                                    connection.sender.send(Message::Response(resp))?;
+                                    continue;
+                                }
+                            };
+                            let result = SemanticTokensResult::Tokens(SemanticTokens {
+                                result_id: None,
+                                data: to_lsp_semantic_tokens(semantics.semantic_tokens(&path)),
+                            });
+                            let resp = Response {
+                                id,
+                                result: Some(serde_json::to_value(result).unwrap()),
+                                error: None,
+                            };
+                            connection.sender.send(Message::Response(resp))?;
+                            continue;
+                        }
+                        Cast::Other(req) => req,
+                        Cast::Invalid => continue,
+                    };
             }
             Message::Response(resp) => {
-                eprintln!("got response: {:?}", resp);
+                log_message(
+                    connection,
+                    MessageType::Log,
+                    format!("got response #{}", resp.id),
+                );
             }
             Message::Notification(notification) => {
-                eprintln!("got notification: {:#?}", notification);
+                log_message(
+                    connection,
+                    MessageType::Log,
+                    format!("got notification: {}", notification.method),
+                );
 
                 let notification =
-                    match cast_notification::<notification::DidOpenTextDocument>(notification) {
-                        Ok(params) => {
-                            eprintln!("got DidOpenTextDocument notification: {:?}", params);
-                            recursively_set_file_text(
-                                &mut semantics,
-                                // This unwrap fails if using file URIs which are not
-                                // file: scheme.
-                                params.text_document.uri.to_file_path().unwrap(),
-                                params.text_document.text,
+                    match cast_notification::<notification::Exit>(connection, notification) {
+                        Cast::Matched(()) => {
+                            log_message(
+                                connection,
+                                MessageType::Info,
+                                "got Exit notification".to_owned(),
                             );
-                            continue;
+                            return Ok(());
                         }
-                        Err(notification) => notification,
+                        Cast::Other(notification) => notification,
+                        Cast::Invalid => continue,
                     };
-                let _notification =
-                    match cast_notification::<notification::DidChangeTextDocument>(notification) {
-                        Ok(mut params) => {
-                            eprintln!("got DidChangeTextDocument notification: {:?}", params);
-                            recursively_set_file_text(
-                                &mut semantics,
-                                // This unwrap fails if using file URIs which are not
-                                // file: scheme.
-                                params.text_document.uri.to_file_path().unwrap(),
-                                // We are assuming here that the client is sending the
-                                // full file, as this is how we initialize our config.
-                                params.content_changes.pop().unwrap().text,
+                if shutdown_received {
+                    continue;
+                }
+
+                let notification = match cast_notification::<notification::DidOpenTextDocument>(
+                    connection,
+                    notification,
+                ) {
+                    Cast::Matched(params) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!(
+                                "got DidOpenTextDocument notification for {}",
+                                params.text_document.uri
+                            ),
+                        );
+                        let path = match uri_to_path(&params.text_document.uri) {
+                            Some(path) => path,
+                            None => {
+                                log_message(
+                                    connection,
+                                    MessageType::Error,
+                                    format!(
+                                        "skipping document with unsupported uri: {}",
+                                        params.text_document.uri
+                                    ),
+                                );
+                                continue;
+                            }
+                        };
+                        documents.insert(
+                            params.text_document.uri,
+                            Document {
+                                version: params.text_document.version,
+                                text: params.text_document.text.clone(),
+                            },
+                        );
+                        recursively_set_file_text(
+                            &mut semantics,
+                            path.clone(),
+                            params.text_document.text,
+                        );
+                        publish_diagnostics(connection, &semantics, &path)?;
+                        continue;
+                    }
+                    Cast::Other(notification) => notification,
+                    Cast::Invalid => continue,
+                };
+                let notification = match cast_notification::<notification::DidChangeTextDocument>(
+                    connection,
+                    notification,
+                ) {
+                    Cast::Matched(params) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!(
+                                "got DidChangeTextDocument notification for {}",
+                                params.text_document.uri
+                            ),
+                        );
+                        let path = match uri_to_path(&params.text_document.uri) {
+                            Some(path) => path,
+                            None => {
+                                log_message(
+                                    connection,
+                                    MessageType::Error,
+                                    format!(
+                                        "skipping document with unsupported uri: {}",
+                                        params.text_document.uri
+                                    ),
+                                );
+                                continue;
+                            }
+                        };
+                        let document = documents
+                            .entry(params.text_document.uri.clone())
+                            .or_default();
+                        if !apply_document_changes(document, params, position_encoding) {
+                            log_message(
+                                connection,
+                                MessageType::Log,
+                                "skipping changes older than the document".to_owned(),
                             );
                             continue;
                         }
-                        Err(notification) => notification,
-                    };
+                        recursively_set_file_text(
+                            &mut semantics,
+                            path.clone(),
+                            document.text.clone(),
+                        );
+                        publish_diagnostics(connection, &semantics, &path)?;
+                        continue;
+                    }
+                    Cast::Other(notification) => notification,
+                    Cast::Invalid => continue,
+                };
+                let _notification = match cast_notification::<notification::DidCloseTextDocument>(
+                    connection,
+                    notification,
+                ) {
+                    Cast::Matched(params) => {
+                        log_message(
+                            connection,
+                            MessageType::Log,
+                            format!(
+                                "got DidCloseTextDocument notification for {}",
+                                params.text_document.uri
+                            ),
+                        );
+                        let path = match uri_to_path(&params.text_document.uri) {
+                            Some(path) => path,
+                            None => {
+                                log_message(
+                                    connection,
+                                    MessageType::Error,
+                                    format!(
+                                        "skipping document with unsupported uri: {}",
+                                        params.text_document.uri
+                                    ),
+                                );
+                                continue;
+                            }
+                        };
+                        documents.remove(&params.text_document.uri);
+                        semantics.remove_file(&path);
+                        // The file is no longer known, so this clears its diagnostics.
+                        publish_diagnostics(connection, &semantics, &path)?;
+                        continue;
+                    }
+                    Cast::Other(notification) => notification,
+                    Cast::Invalid => continue,
+                };
             }
         }
     }
     Ok(())
 }
 
+/// Converts a document URI to a file path. Only `file:` URIs are supported,
+/// since the language model works with files on disk.
+fn uri_to_path(uri: &Url) -> Option<PathBuf> {
+    if uri.scheme() != "file" {
+        return None;
+    }
+
+    uri.to_file_path().ok()
+}
+
+/// Builds the response to a GotoDefinition request.
+///
+/// A position without a definition (for example whitespace, or a built-in
+/// command) is a normal outcome, so it gets a null result. A request for a
+/// document which has not been loaded can't be answered at all, so it gets an
+/// error.
+fn goto_definition_response(
+    id: RequestId,
+    path: &Path,
+    file_loaded: bool,
+    definition: Option<CursorPosition>,
+) -> Response {
+    if !file_loaded {
+        return Response::new_err(
+            id,
+            ErrorCode::InvalidParams as i32,
+            format!("document is not loaded: {}", path.display()),
+        );
+    }
+
+    let result = definition.map(|definition_position| {
+        let pos = lsp_types::Position {
+            line: definition_position.line as u32,
+            character: definition_position.column as u32,
+        };
+        // We are using an empty range here to indicate a specific
+        // location.
+        let range = lsp_types::Range {
+            start: pos,
+            end: pos,
+        };
+        GotoDefinitionResponse::from(lsp_types::Location::new(
+            lsp_types::Url::from_file_path(definition_position.file).unwrap(),
+            range,
+        ))
+    });
+
+    Response::new_ok(id, result)
+}
+
+fn to_lsp_range(range: CursorRange) -> lsp_types::Range {
+    lsp_types::Range {
+        start: lsp_types::Position {
+            line: range.start.line as u32,
+            character: range.start.column as u32,
+        },
+        end: lsp_types::Position {
+            line: range.end.line as u32,
+            character: range.end.column as u32,
+        },
+    }
+}
+
+fn to_lsp_workspace_edit(workspace_edit: language_model::WorkspaceEdit) -> WorkspaceEdit {
+    WorkspaceEdit {
+        changes: Some(
+            workspace_edit
+                .changes
+                .into_iter()
+                .map(|(file, edits)| {
+                    let edits = edits
+                        .into_iter()
+                        .map(|edit| TextEdit {
+                            range: to_lsp_range(edit.range),
+                            new_text: edit.new_text,
+                        })
+                        .collect();
+                    (Url::from_file_path(file).unwrap(), edits)
+                })
+                .collect(),
+        ),
+        ..WorkspaceEdit::default()
+    }
+}
+
+fn to_lsp_code_action(code_action: language_model::CodeAction) -> CodeActionOrCommand {
+    CodeActionOrCommand::CodeAction(CodeAction {
+        title: code_action.title,
+        kind: Some(CodeActionKind::QUICKFIX),
+        edit: Some(to_lsp_workspace_edit(code_action.edit)),
+        ..CodeAction::default()
+    })
+}
+
+fn to_lsp_inlay_hint(inlay_hint: language_model::InlayHint) -> InlayHint {
+    InlayHint {
+        position: lsp_types::Position {
+            line: inlay_hint.position.line as u32,
+            character: inlay_hint.position.column as u32,
+        },
+        label: inlay_hint.label,
+        padding_left: true,
+    }
+}
+
+fn to_lsp_document_highlight(highlight: language_model::DocumentHighlight) -> DocumentHighlight {
+    DocumentHighlight {
+        range: to_lsp_range(highlight.range),
+        kind: Some(match highlight.kind {
+            HighlightKind::Read => DocumentHighlightKind::Read,
+            HighlightKind::Write => DocumentHighlightKind::Write,
+        }),
+    }
+}
+
+// The `deprecated` field is itself deprecated, but it still has to be set.
+#[allow(deprecated)]
+fn to_lsp_document_symbol(symbol: Symbol) -> DocumentSymbol {
+    DocumentSymbol {
+        name: symbol.name,
+        detail: None,
+        kind: to_lsp_symbol_kind(symbol.kind),
+        tags: None,
+        deprecated: None,
+        range: to_lsp_range(symbol.range),
+        selection_range: to_lsp_range(symbol.name_range),
+        children: Some(
+            symbol
+                .children
+                .into_iter()
+                .map(to_lsp_document_symbol)
+                .collect(),
+        ),
+    }
+}
+
+// The `deprecated` field is itself deprecated, but it still has to be set.
+#[allow(deprecated)]
+fn to_lsp_symbol_information(symbol: Symbol) -> SymbolInformation {
+    SymbolInformation {
+        name: symbol.name,
+        kind: to_lsp_symbol_kind(symbol.kind),
+        tags: None,
+        deprecated: None,
+        location: lsp_types::Location::new(
+            Url::from_file_path(symbol.name_range.start.file).unwrap(),
+            to_lsp_range(symbol.name_range),
+        ),
+        container_name: None,
+    }
+}
+
+fn to_lsp_symbol_kind(kind: SymbolKind) -> lsp_types::SymbolKind {
+    match kind {
+        SymbolKind::Function => lsp_types::SymbolKind::Function,
+    }
+}
+
+/// The legend sent to the client, which lists the token types in the order
+/// `semantic_token_type_index` refers to them.
+fn semantic_tokens_legend() -> SemanticTokensLegend {
+    SemanticTokensLegend {
+        token_types: vec![
+            lsp_types::SemanticTokenType::KEYWORD,
+            lsp_types::SemanticTokenType::FUNCTION,
+            lsp_types::SemanticTokenType::COMMENT,
+            lsp_types::SemanticTokenType::STRING,
+            lsp_types::SemanticTokenType::VARIABLE,
+        ],
+        token_modifiers: vec![],
+    }
+}
+
+fn semantic_token_type_index(token_type: SemanticTokenType) -> u32 {
+    match token_type {
+        SemanticTokenType::Keyword => 0,
+        SemanticTokenType::Function => 1,
+        SemanticTokenType::Comment => 2,
+        SemanticTokenType::String => 3,
+        SemanticTokenType::Variable => 4,
+    }
+}
+
+/// Converts semantic tokens, which must be ordered by position, to the LSP
+/// encoding where each token's position is relative to the token before it.
+fn to_lsp_semantic_tokens(tokens: Vec<SemanticToken>) -> Vec<lsp_types::SemanticToken> {
+    let mut previous_line = 0;
+    let mut previous_column = 0;
+
+    tokens
+        .into_iter()
+        .map(|token| {
+            let line = token.range.start.line;
+            let column = token.range.start.column;
+            let delta_start = if line == previous_line {
+                column - previous_column
+            } else {
+                column
+            };
+            let lsp_token = lsp_types::SemanticToken {
+                delta_line: (line - previous_line) as u32,
+                delta_start: delta_start as u32,
+                length: (token.range.end.column - column) as u32,
+                token_type: semantic_token_type_index(token.token_type),
+                token_modifiers_bitset: 0,
+            };
+            previous_line = line;
+            previous_column = column;
+
+            lsp_token
+        })
+        .collect()
+}
+
+/// Converts completions found in the given document to completion items. User
+/// provided completions are marked as functions and built-in completions as
+/// keywords, and each records the document so its documentation can be
+/// resolved later.
+fn completion_items(
+    completions: Completions,
+    uri: &Url,
+    position: lsp_types::Position,
+    position_encoding: PositionEncoding,
+) -> Vec<CompletionItem> {
+    let data = serde_json::json!({ "uri": uri });
+    let item = |completion: Completion, kind| CompletionItem {
+        label: completion.text,
+        kind: Some(kind),
+        data: Some(data.clone()),
+        ..CompletionItem::default()
+    };
+
+    completions
+        .user_provided
+        .into_iter()
+        .map(|completion| item(completion, CompletionItemKind::Function))
+        .chain(
+            completions
+                .built_in
+                .into_iter()
+                .map(|completion| item(completion, CompletionItemKind::Keyword)),
+        )
+        .chain(completions.path.map_or_else(Vec::new, |path_completion| {
+            path_completion_items(&path_completion, position, position_encoding)
+        }))
+        .collect()
+}
+
+/// Builds completion items for the files which could complete the path being
+/// typed. Each item replaces the part of the file name typed so far.
+fn path_completion_items(
+    path_completion: &PathCompletion,
+    position: lsp_types::Position,
+    position_encoding: PositionEncoding,
+) -> Vec<CompletionItem> {
+    let prefix_width = path_completion
+        .file_name_prefix
+        .chars()
+        .map(|character| position_encoding.char_width(character))
+        .sum::<usize>();
+    let start = lsp_types::Position {
+        line: position.line,
+        character: position.character.saturating_sub(prefix_width as u32),
+    };
+
+    list_path_completions(path_completion)
+        .into_iter()
+        .map(|name| CompletionItem {
+            label: name.clone(),
+            kind: Some(if name.ends_with('/') {
+                CompletionItemKind::Folder
+            } else {
+                CompletionItemKind::File
+            }),
+            text_edit: Some(CompletionTextEdit::Edit(TextEdit::new(
+                lsp_types::Range::new(start, position),
+                name,
+            ))),
+            ..CompletionItem::default()
+        })
+        .collect()
+}
+
+/// Lists the directories and GDB scripts in the directory being completed whose
+/// names start with the typed prefix, sorted by name. Directory names end with a
+/// `/`, so completion can continue into them.
+fn list_path_completions(path_completion: &PathCompletion) -> Vec<String> {
+    let entries = match fs::read_dir(&path_completion.directory) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut names = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(&path_completion.file_name_prefix) {
+                return None;
+            }
+
+            if entry.file_type().ok()?.is_dir() {
+                Some(format!("{}/", name))
+            } else if name.ends_with(".gdb") || name.ends_with(".gdbinit") {
+                Some(name)
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<String>>();
+    names.sort();
+
+    names
+}
+
+fn unsupported_uri_response(id: RequestId, uri: &Url) -> Response {
+    Response::new_err(
+        id,
+        ErrorCode::InvalidParams as i32,
+        format!("unsupported document uri: {}", uri),
+    )
+}
+
+/// Applies the changes of a `didChange` notification to the document, unless
+/// they are older than the last changes applied to it, since notifications may
+/// arrive out of order. Returns whether the changes were applied.
+fn apply_document_changes(
+    document: &mut Document,
+    params: DidChangeTextDocumentParams,
+    position_encoding: PositionEncoding,
+) -> bool {
+    if params.text_document.version < document.version {
+        return false;
+    }
+
+    document.version = params.text_document.version;
+    for change in params.content_changes {
+        apply_change(&mut document.text, change, position_encoding);
+    }
+    true
+}
+
+/// Applies a single content change to a document. Changes without a range
+/// replace the entire document.
+fn apply_change(
+    text: &mut String,
+    change: TextDocumentContentChangeEvent,
+    position_encoding: PositionEncoding,
+) {
+    match change.range {
+        Some(range) => {
+            let offset_of = |position: lsp_types::Position| {
+                language_model::offset_of(
+                    text,
+                    position.line as usize,
+                    position.character as usize,
+                    position_encoding,
+                )
+            };
+            let start = offset_of(range.start);
+            let end = offset_of(range.end).max(start);
+            text.replace_range(start..end, &change.text);
+        }
+        None => *text = change.text,
+    }
+}
+
+/// Parses the command line arguments, not including the program name.
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Mode, String> {
+    let mut args = args.peekable();
+    if args.next_if(|arg| arg == "check").is_none() {
+        return parse_transport(args).map(Mode::Serve);
+    }
+
+    match (args.next(), args.next()) {
+        (Some(path), None) => Ok(Mode::Check(PathBuf::from(path))),
+        (None, _) => Err("`check` requires a file".to_owned()),
+        (Some(_), Some(arg)) => Err(format!("unexpected argument `{}`", arg)),
+    }
+}
+
+fn parse_transport(mut args: impl Iterator<Item = String>) -> Result<Transport, String> {
+    let transport = match args.next().as_deref() {
+        None | Some("--stdio") => Transport::Stdio,
+        Some("--socket") => {
+            let port = args.next().ok_or("`--socket` requires a port")?;
+            match port.parse() {
+                Ok(port) => Transport::Socket(port),
+                Err(_) => return Err(format!("invalid port `{}`", port)),
+            }
+        }
+        Some(arg) => return Err(format!("unexpected argument `{}`", arg)),
+    };
+
+    match args.next() {
+        Some(arg) => Err(format!("unexpected argument `{}`", arg)),
+        None => Ok(transport),
+    }
+}
+
+fn connect(transport: &Transport) -> (Connection, IoThreads) {
+    match transport {
+        Transport::Stdio => Connection::stdio(),
+        Transport::Socket(port) => Connection::socket(("127.0.0.1", *port)),
+    }
+}
+
+/// Sends the given message to the client to show in its log. If the client
+/// can't be reached, the message is written to stderr instead.
+fn log_message(connection: &Connection, typ: MessageType, message: String) {
+    let notification = lsp_server::Notification::new(
+        <notification::LogMessage as notification::Notification>::METHOD.to_owned(),
+        LogMessageParams {
+            typ,
+            message: message.clone(),
+        },
+    );
+    if connection
+        .sender
+        .send(Message::Notification(notification))
+        .is_err()
+    {
+        eprintln!("{}", message);
+    }
+}
+
+/// Picks the position encoding to use from those the client supports, given
+/// its initialize params. UTF-8 is preferred since it is how the text is
+/// stored, but UTF-16 is the default which every client must support.
+fn negotiate_position_encoding(params: &serde_json::Value) -> PositionEncoding {
+    let offers_utf8 = params["capabilities"]["general"]["positionEncodings"]
+        .as_array()
+        .is_some_and(|encodings| encodings.iter().any(|encoding| encoding == "utf-8"));
+
+    if offers_utf8 {
+        PositionEncoding::Utf8
+    } else {
+        PositionEncoding::Utf16
+    }
+}
+
+/// The name LSP uses for the given position encoding.
+fn position_encoding_kind(position_encoding: PositionEncoding) -> &'static str {
+    match position_encoding {
+        PositionEncoding::Utf8 => "utf-8",
+        PositionEncoding::Utf16 => "utf-16",
+    }
+}
+
+/// Loads the given file and the files it sources, and returns its diagnostics
+/// formatted like compiler errors, as `file:line:column: severity: message`,
+/// along with whether any of them are errors. Relative paths are resolved
+/// against the project root, but printed as given.
+fn check(path: &Path, project_root: &Path) -> io::Result<(Vec<String>, bool)> {
+    let full_path = project_root.join(path);
+    let text = fs::read_to_string(&full_path)?;
+    let mut semantics = Semantics::new(project_root.to_owned());
+    recursively_set_file_text(&mut semantics, full_path.clone(), text);
+
+    let diagnostics = semantics.diagnostics(&full_path);
+    let has_errors = diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Severity::Error);
+    let lines = diagnostics
+        .into_iter()
+        .map(|diagnostic| {
+            let severity = match diagnostic.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+                Severity::Hint => "hint",
+            };
+            format!(
+                "{}:{}:{}: {}: {}",
+                path.display(),
+                diagnostic.range.start.line + 1,
+                diagnostic.range.start.column + 1,
+                severity,
+                diagnostic.message
+            )
+        })
+        .collect();
+
+    Ok((lines, has_errors))
+}
+
 fn recursively_set_file_text(semantics: &mut Semantics, path: PathBuf, text: String) {
-    let unresolved_paths = semantics.set_file_text(path, text);
+    let unresolved_paths = semantics
+        .set_file_text(path, text)
+        .paths()
+        .map(Path::to_owned)
+        .collect::<Vec<PathBuf>>();
 
-    for path in unresolved_paths.into_iter() {
+    for path in unresolved_paths {
         if let Ok(text) = fs::read_to_string(&path) {
             recursively_set_file_text(semantics, path, text);
         }
     }
 }
 
-fn cast_request<R>(req: lsp_server::Request) -> Result<(RequestId, R::Params), lsp_server::Request>
+/// Returns the directory which relative paths, such as those of `source`
+/// commands, are resolved against. This is the first workspace folder the client
+/// opened, or the current directory if there isn't one.
+fn project_root(params: &InitializeParams) -> Result<PathBuf, GdblsError> {
+    let workspace_folder = params
+        .workspace_folders
+        .iter()
+        .flatten()
+        .find_map(|folder| uri_to_path(&folder.uri));
+
+    match workspace_folder {
+        Some(path) => Ok(path),
+        None => Ok(env::current_dir()?),
+    }
+}
+
+/// Returns true if the client asked, through the `loadGdbinit` initialization
+/// option, for the `.gdbinit` in the project root to be loaded on startup.
+fn load_gdbinit_requested(params: &InitializeParams) -> bool {
+    params
+        .initialization_options
+        .as_ref()
+        .and_then(|options| options["loadGdbinit"].as_bool())
+        .unwrap_or(false)
+}
+
+/// Returns the number of spaces the client asked for each tab to be converted
+/// to, if it set the `tabWidth` initialization option.
+fn tab_width_option(params: &InitializeParams) -> Option<usize> {
+    params
+        .initialization_options
+        .as_ref()
+        .and_then(|options| options["tabWidth"].as_u64())
+        .map(|tab_width| tab_width as usize)
+}
+
+/// Returns the names of the commands the client listed in the `extraCommands`
+/// initialization option, such as those added by GDB plugins. Entries which
+/// aren't strings are ignored.
+fn extra_commands_option(params: &InitializeParams) -> Vec<String> {
+    params
+        .initialization_options
+        .as_ref()
+        .and_then(|options| options["extraCommands"].as_array())
+        .map(|commands| {
+            commands
+                .iter()
+                .filter_map(|command| command.as_str())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Loads the `.gdbinit` in the given project root, along with the files it
+/// sources, so the commands it defines are known before any file is opened.
+/// Nothing is loaded if there is no `.gdbinit`.
+fn preload_gdbinit(semantics: &mut Semantics, project_root: &Path) {
+    let path = project_root.join(".gdbinit");
+    if let Ok(text) = fs::read_to_string(&path) {
+        recursively_set_file_text(semantics, path, text);
+    }
+}
+
+/// Sends the current diagnostics for the given file to the client. This is
+/// sent even when there are no diagnostics, so the client clears any which
+/// were previously published.
+fn publish_diagnostics(
+    connection: &Connection,
+    semantics: &Semantics,
+    path: &Path,
+) -> Result<(), GdblsError> {
+    let diagnostics = semantics
+        .diagnostics(path)
+        .into_iter()
+        .map(|diagnostic| {
+            let range = to_lsp_range(diagnostic.range);
+            let severity = match diagnostic.severity {
+                Severity::Error => DiagnosticSeverity::Error,
+                Severity::Warning => DiagnosticSeverity::Warning,
+                Severity::Hint => DiagnosticSeverity::Hint,
+            };
+            let related_information = diagnostic
+                .related
+                .into_iter()
+                .map(|related| DiagnosticRelatedInformation {
+                    location: lsp_types::Location::new(
+                        Url::from_file_path(related.range.start.file).unwrap(),
+                        to_lsp_range(related.range),
+                    ),
+                    message: related.message,
+                })
+                .collect::<Vec<DiagnosticRelatedInformation>>();
+            lsp_types::Diagnostic {
+                severity: Some(severity),
+                source: Some("gdbls".to_owned()),
+                related_information: if related_information.is_empty() {
+                    None
+                } else {
+                    Some(related_information)
+                },
+                ..lsp_types::Diagnostic::new_simple(range, diagnostic.message)
+            }
+        })
+        .collect();
+    let params = PublishDiagnosticsParams::new(
+        lsp_types::Url::from_file_path(path).unwrap(),
+        diagnostics,
+        None,
+    );
+    let notification = lsp_server::Notification::new(
+        <notification::PublishDiagnostics as notification::Notification>::METHOD.to_owned(),
+        params,
+    );
+    connection
+        .sender
+        .send(Message::Notification(notification))?;
+
+    Ok(())
+}
+
+/// The result of checking whether a message from the client is of a given type.
+enum Cast<T, M> {
+    /// The message is of the type, and these are its params.
+    Matched(T),
+    /// The message is of another type, and is given back.
+    Other(M),
+    /// The message is of the type, but its params don't match it. This has
+    /// already been reported, so the message should be skipped.
+    Invalid,
+}
+
+/// Returns the id and params of the request if it is of the given type, or
+/// gives the request back if it isn't. A request with params which don't match
+/// the type is logged and answered with an `InvalidParams` error, rather than
+/// the panic of `Request::extract`, since it is a mistake of the client which
+/// shouldn't stop the server.
+fn cast_request<R>(
+    connection: &Connection,
+    req: lsp_server::Request,
+) -> Result<Cast<(RequestId, R::Params), lsp_server::Request>, GdblsError>
 where
     R: request::Request,
     R::Params: serde::de::DeserializeOwned,
 {
-    req.extract(R::METHOD)
+    if req.method != R::METHOD {
+        return Ok(Cast::Other(req));
+    }
+
+    match serde_json::from_value(req.params) {
+        Ok(params) => Ok(Cast::Matched((req.id, params))),
+        Err(error) => {
+            let message = format!("invalid params for `{}`: {}", R::METHOD, error);
+            log_message(connection, MessageType::Error, message.clone());
+            let resp = Response::new_err(req.id, ErrorCode::InvalidParams as i32, message);
+            connection.sender.send(Message::Response(resp))?;
+            Ok(Cast::Invalid)
+        }
+    }
 }
 
+/// Returns the params of the notification if it is of the given type, or gives
+/// the notification back if it isn't. A notification with params which don't
+/// match the type is logged and skipped, since there is no way to reply to it.
 fn cast_notification<N>(
+    connection: &Connection,
     notification: lsp_server::Notification,
-) -> Result<N::Params, lsp_server::Notification>
+) -> Cast<N::Params, lsp_server::Notification>
 where
     N: notification::Notification,
     N::Params: serde::de::DeserializeOwned,
 {
-    notification.extract(N::METHOD)
+    if notification.method != N::METHOD {
+        return Cast::Other(notification);
+    }
+
+    match serde_json::from_value(notification.params) {
+        Ok(params) => Cast::Matched(params),
+        Err(error) => {
+            log_message(
+                connection,
+                MessageType::Error,
+                format!("skipping `{}` with invalid params: {}", N::METHOD, error),
+            );
+            Cast::Invalid
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, net::TcpListener, path::PathBuf, process, thread};
+
+    use lsp_types::{
+        CompletionItemKind, DidChangeTextDocumentParams, DidOpenTextDocumentParams,
+        InitializeParams, LogMessageParams, MessageType, TextDocumentContentChangeEvent,
+        TextDocumentItem, Url, VersionedTextDocumentIdentifier,
+    };
+
+    use language_model::{
+        Completion, Completions, CursorPosition, CursorRange, PathCompletion, PositionEncoding,
+        SemanticToken, SemanticTokenType, Semantics,
+    };
+    use lsp_server::{Connection, ErrorCode, Message, Notification, Request, RequestId};
+
+    use super::{
+        apply_change, apply_document_changes, check, completion_items, connect,
+        extra_commands_option, goto_definition_response, list_path_completions,
+        load_gdbinit_requested, main_loop, negotiate_position_encoding, parse_args,
+        parse_transport, preload_gdbinit, project_root, tab_width_option, to_lsp_semantic_tokens,
+        uri_to_path, Document, GdblsError, Mode, Transport,
+    };
+
+    #[test]
+    fn parse_transport_args() {
+        let check = |args: &[&str]| parse_transport(args.iter().map(|arg| arg.to_string()));
+
+        assert_eq!(Ok(Transport::Stdio), check(&[]));
+        assert_eq!(Ok(Transport::Stdio), check(&["--stdio"]));
+        assert_eq!(Ok(Transport::Socket(9257)), check(&["--socket", "9257"]));
+        assert_eq!(
+            Err("`--socket` requires a port".to_owned()),
+            check(&["--socket"])
+        );
+        assert_eq!(
+            Err("invalid port `70000`".to_owned()),
+            check(&["--socket", "70000"])
+        );
+        assert_eq!(
+            Err("unexpected argument `--tcp`".to_owned()),
+            check(&["--tcp"])
+        );
+        assert_eq!(
+            Err("unexpected argument `--stdio`".to_owned()),
+            check(&["--stdio", "--stdio"])
+        );
+    }
+
+    #[test]
+    fn parse_check_args() {
+        let check = |args: &[&str]| parse_args(args.iter().map(|arg| arg.to_string()));
+
+        assert_eq!(
+            Ok(Mode::Check(PathBuf::from("foo.gdb"))),
+            check(&["check", "foo.gdb"])
+        );
+        assert_eq!(Ok(Mode::Serve(Transport::Stdio)), check(&[]));
+        assert_eq!(
+            Ok(Mode::Serve(Transport::Socket(9257))),
+            check(&["--socket", "9257"])
+        );
+        assert_eq!(Err("`check` requires a file".to_owned()), check(&["check"]));
+        assert_eq!(
+            Err("unexpected argument `bar.gdb`".to_owned()),
+            check(&["check", "foo.gdb", "bar.gdb"])
+        );
+    }
+
+    #[test]
+    fn check_reports_missing_end() {
+        let project_root = env::temp_dir().join(format!("gdbls-check-{}", process::id()));
+        fs::create_dir_all(&project_root).unwrap();
+        fs::write(project_root.join("helpers.gdb"), "define say_hi\n").unwrap();
+        fs::write(project_root.join("ok.gdb"), "echo ok\n").unwrap();
+
+        let broken = check(&PathBuf::from("helpers.gdb"), &project_root).unwrap();
+        let ok = check(&PathBuf::from("ok.gdb"), &project_root).unwrap();
+        let missing = check(&PathBuf::from("missing.gdb"), &project_root);
+        fs::remove_dir_all(&project_root).unwrap();
+
+        assert_eq!(
+            (
+                vec!["helpers.gdb:1:1: error: `define` is missing a matching `end`".to_owned()],
+                true
+            ),
+            broken
+        );
+        assert_eq!((vec![], false), ok);
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn connect_over_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let (connection, _io_threads) = connect(&Transport::Socket(port));
+        let (_stream, _) = listener.accept().unwrap();
+
+        drop(connection);
+    }
+
+    #[test]
+    fn negotiate_position_encoding_prefers_utf8() {
+        let check = |general: serde_json::Value, expected: PositionEncoding| {
+            let params = serde_json::json!({
+                "processId": null,
+                "rootUri": null,
+                "capabilities": { "general": general },
+            });
+            // The params must still be valid `InitializeParams`, which the main
+            // loop reads.
+            serde_json::from_value::<InitializeParams>(params.clone()).unwrap();
+            assert_eq!(expected, negotiate_position_encoding(&params));
+        };
+
+        check(
+            serde_json::json!({ "positionEncodings": ["utf-16", "utf-8"] }),
+            PositionEncoding::Utf8,
+        );
+        check(
+            serde_json::json!({ "positionEncodings": ["utf-32", "utf-16"] }),
+            PositionEncoding::Utf16,
+        );
+        check(serde_json::json!({}), PositionEncoding::Utf16);
+    }
+
+    #[test]
+    fn apply_change_position_encodings() {
+        // `é` is two bytes in UTF-8 but a single UTF-16 code unit.
+        let check = |position_encoding| {
+            let mut text = "echo é!\nfoo".to_owned();
+            let position = lsp_types::Position::new(0, 7);
+            let change = TextDocumentContentChangeEvent {
+                range: Some(lsp_types::Range::new(position, position)),
+                range_length: None,
+                text: "x".to_owned(),
+            };
+            apply_change(&mut text, change, position_encoding);
+            text
+        };
+
+        assert_eq!("echo éx!\nfoo", check(PositionEncoding::Utf8));
+        assert_eq!("echo é!x\nfoo", check(PositionEncoding::Utf16));
+    }
+
+    #[test]
+    fn stale_document_changes_are_ignored() {
+        let change = |version: i32, text: &str| DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(
+                Url::parse("file:///home/user/foo.gdb").unwrap(),
+                version,
+            ),
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: text.to_owned(),
+            }],
+        };
+        let mut document = Document {
+            version: 1,
+            text: "echo 1\n".to_owned(),
+        };
+
+        assert!(apply_document_changes(
+            &mut document,
+            change(3, "echo 3\n"),
+            PositionEncoding::Utf16
+        ));
+        assert!(!apply_document_changes(
+            &mut document,
+            change(2, "echo 2\n"),
+            PositionEncoding::Utf16
+        ));
+        assert_eq!(3, document.version);
+        assert_eq!("echo 3\n", document.text);
+    }
+
+    #[test]
+    fn shutdown_then_exit() {
+        let (server, client) = Connection::memory();
+        let params = serde_json::json!({ "processId": null, "rootUri": null, "capabilities": {} });
+        let server_thread =
+            thread::spawn(move || main_loop(&server, params, PositionEncoding::Utf16).is_ok());
+
+        let check_response = |id: i32, method: &str, expected_error: Option<ErrorCode>| {
+            client
+                .sender
+                .send(Message::Request(Request::new(
+                    RequestId::from(id),
+                    method.to_owned(),
+                    serde_json::Value::Null,
+                )))
+                .unwrap();
+            // Skip over the log messages sent while handling the request.
+            match client
+                .receiver
+                .iter()
+                .find(|message| !matches!(message, Message::Notification(_)))
+            {
+                Some(Message::Response(resp)) => {
+                    assert_eq!(RequestId::from(id), resp.id);
+                    assert_eq!(
+                        expected_error.map(|code| code as i32),
+                        resp.error.map(|error| error.code)
+                    );
+                }
+                message => panic!("expected a response, got {:?}", message),
+            }
+        };
+        check_response(1, "shutdown", None);
+        check_response(2, "workspace/symbol", Some(ErrorCode::InvalidRequest));
+
+        client
+            .sender
+            .send(Message::Notification(Notification::new(
+                "exit".to_owned(),
+                serde_json::Value::Null,
+            )))
+            .unwrap();
+        assert!(server_thread.join().unwrap());
+    }
+
+    #[test]
+    fn malformed_initialize_params_are_a_serde_error() {
+        let (server, _client) = Connection::memory();
+        let params = serde_json::json!({ "processId": "not a number", "capabilities": {} });
+
+        let result = main_loop(&server, params, PositionEncoding::Utf16);
+        assert!(matches!(result, Err(GdblsError::Serde(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn malformed_params_are_reported_without_stopping_the_server() {
+        let (server, client) = Connection::memory();
+        let params = serde_json::json!({ "processId": null, "rootUri": null, "capabilities": {} });
+        let server_thread =
+            thread::spawn(move || main_loop(&server, params, PositionEncoding::Utf16).is_ok());
+        let next_response = || {
+            client
+                .receiver
+                .iter()
+                .find_map(|message| match message {
+                    Message::Response(resp) => Some(resp),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        client
+            .sender
+            .send(Message::Request(Request::new(
+                RequestId::from(1),
+                "textDocument/definition".to_owned(),
+                serde_json::json!({ "textDocument": "not a text document" }),
+            )))
+            .unwrap();
+        let mut error_messages = vec![];
+        let resp = loop {
+            match client.receiver.recv().unwrap() {
+                Message::Notification(notification) => {
+                    let params: LogMessageParams =
+                        serde_json::from_value(notification.params).unwrap();
+                    if params.typ == MessageType::Error {
+                        error_messages.push(params.message);
+                    }
+                }
+                Message::Response(resp) => break resp,
+                message => panic!("unexpected message {:?}", message),
+            }
+        };
+        assert_eq!(1, error_messages.len(), "{:?}", error_messages);
+        assert!(error_messages[0].starts_with("invalid params for `textDocument/definition`"));
+        assert_eq!(RequestId::from(1), resp.id);
+        assert_eq!(
+            Some(ErrorCode::InvalidParams as i32),
+            resp.error.map(|error| error.code)
+        );
+
+        // A notification can't be answered, so it is skipped.
+        client
+            .sender
+            .send(Message::Notification(Notification::new(
+                "textDocument/didOpen".to_owned(),
+                serde_json::json!({ "textDocument": 1 }),
+            )))
+            .unwrap();
+
+        client
+            .sender
+            .send(Message::Request(Request::new(
+                RequestId::from(2),
+                "shutdown".to_owned(),
+                serde_json::Value::Null,
+            )))
+            .unwrap();
+        let resp = next_response();
+        assert_eq!(RequestId::from(2), resp.id);
+        assert!(resp.error.is_none(), "{:?}", resp.error);
+
+        client
+            .sender
+            .send(Message::Notification(Notification::new(
+                "exit".to_owned(),
+                serde_json::Value::Null,
+            )))
+            .unwrap();
+        assert!(server_thread.join().unwrap());
+    }
+
+    #[test]
+    fn inlay_hints_show_resolved_source_paths() {
+        let (server, client) = Connection::memory();
+        let params = serde_json::json!({ "processId": null, "rootUri": null, "capabilities": {} });
+        let server_thread =
+            thread::spawn(move || main_loop(&server, params, PositionEncoding::Utf16).is_ok());
+
+        let uri = Url::parse("file:///home/user/scripts/main.gdb").unwrap();
+        client
+            .sender
+            .send(Message::Notification(Notification::new(
+                "textDocument/didOpen".to_owned(),
+                DidOpenTextDocumentParams {
+                    text_document: TextDocumentItem::new(
+                        uri.clone(),
+                        "gdb".to_owned(),
+                        1,
+                        "source lib/common.gdb\n".to_owned(),
+                    ),
+                },
+            )))
+            .unwrap();
+        client
+            .sender
+            .send(Message::Request(Request::new(
+                RequestId::from(1),
+                "textDocument/inlayHint".to_owned(),
+                serde_json::json!({
+                    "textDocument": { "uri": uri },
+                    "range": {
+                        "start": { "line": 0, "character": 0 },
+                        "end": { "line": 1, "character": 0 },
+                    },
+                }),
+            )))
+            .unwrap();
+
+        let result = client
+            .receiver
+            .iter()
+            .find_map(|message| match message {
+                Message::Response(resp) => Some(resp.result),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            Some(serde_json::json!([{
+                "position": { "line": 0, "character": 21 },
+                "label": "/home/user/scripts/lib/common.gdb",
+                "paddingLeft": true,
+            }])),
+            result
+        );
+
+        drop(client);
+        assert!(server_thread.join().unwrap());
+    }
+
+    #[test]
+    fn closed_connection_is_a_protocol_error() {
+        let (server, client) = Connection::memory();
+        let params = serde_json::json!({ "processId": null, "rootUri": null, "capabilities": {} });
+        // Without a receiver, the response to the request can't be sent.
+        drop(client.receiver);
+
+        client
+            .sender
+            .send(Message::Request(Request::new(
+                RequestId::from(1),
+                "shutdown".to_owned(),
+                serde_json::Value::Null,
+            )))
+            .unwrap();
+        let result = main_loop(&server, params, PositionEncoding::Utf16);
+        assert!(
+            matches!(result, Err(GdblsError::Protocol(_))),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn requests_are_logged_to_client() {
+        let (server, client) = Connection::memory();
+        let params = serde_json::json!({ "processId": null, "rootUri": null, "capabilities": {} });
+        let server_thread =
+            thread::spawn(move || main_loop(&server, params, PositionEncoding::Utf16).is_ok());
+
+        client
+            .sender
+            .send(Message::Request(Request::new(
+                RequestId::from(1),
+                "shutdown".to_owned(),
+                serde_json::Value::Null,
+            )))
+            .unwrap();
+        let mut log_messages = vec![];
+        for message in &client.receiver {
+            match message {
+                Message::Notification(notification) => {
+                    assert_eq!("window/logMessage", notification.method);
+                    let params: LogMessageParams =
+                        serde_json::from_value(notification.params).unwrap();
+                    log_messages.push((params.typ, params.message));
+                }
+                Message::Response(_) => break,
+                message => panic!("unexpected message {:?}", message),
+            }
+        }
+        assert!(log_messages.contains(&(MessageType::Info, "starting main loop".to_owned())));
+        assert!(log_messages.contains(&(MessageType::Info, "got Shutdown request #1".to_owned())));
+
+        client
+            .sender
+            .send(Message::Notification(Notification::new(
+                "exit".to_owned(),
+                serde_json::Value::Null,
+            )))
+            .unwrap();
+        assert!(server_thread.join().unwrap());
+    }
+
+    #[test]
+    fn uri_to_path_file_scheme() {
+        let uri = Url::parse("file:///home/user/foo.gdb").unwrap();
+
+        assert_eq!(Some(PathBuf::from("/home/user/foo.gdb")), uri_to_path(&uri));
+    }
+
+    #[test]
+    fn uri_to_path_untitled_scheme() {
+        let uri = Url::parse("untitled:Untitled-1").unwrap();
+
+        assert_eq!(None, uri_to_path(&uri));
+    }
+
+    #[test]
+    fn uri_to_path_http_scheme() {
+        let uri = Url::parse("http://example.com/foo.gdb").unwrap();
+
+        assert_eq!(None, uri_to_path(&uri));
+    }
+
+    #[test]
+    fn goto_definition_response_found() {
+        let path = PathBuf::from("/home/user/foo.gdb");
+        let definition = CursorPosition {
+            file: &path,
+            line: 1,
+            column: 7,
+        };
+
+        let resp = goto_definition_response(RequestId::from(1), &path, true, Some(definition));
+
+        assert!(resp.error.is_none());
+        assert_eq!(
+            "file:///home/user/foo.gdb",
+            resp.result.unwrap()["uri"].as_str().unwrap()
+        );
+    }
+
+    #[test]
+    fn goto_definition_response_no_definition_is_null() {
+        let path = PathBuf::from("/home/user/foo.gdb");
+
+        let resp = goto_definition_response(RequestId::from(1), &path, true, None);
+
+        assert!(resp.error.is_none());
+        assert_eq!(Some(serde_json::Value::Null), resp.result);
+    }
+
+    #[test]
+    fn goto_definition_response_file_not_loaded_is_error() {
+        let path = PathBuf::from("/home/user/foo.gdb");
+
+        let resp = goto_definition_response(RequestId::from(1), &path, false, None);
+
+        assert!(resp.result.is_none());
+        assert_eq!(ErrorCode::InvalidParams as i32, resp.error.unwrap().code);
+    }
+
+    #[test]
+    fn semantic_tokens_are_delta_encoded() {
+        let path = PathBuf::from("/home/user/foo.gdb");
+        let token = |line, start, end, token_type| SemanticToken {
+            range: CursorRange {
+                start: CursorPosition {
+                    file: &path,
+                    line,
+                    column: start,
+                },
+                end: CursorPosition {
+                    file: &path,
+                    line,
+                    column: end,
+                },
+            },
+            token_type,
+        };
+        let tokens = vec![
+            token(1, 0, 6, SemanticTokenType::Keyword),
+            token(1, 7, 13, SemanticTokenType::Function),
+            token(2, 4, 8, SemanticTokenType::Variable),
+            token(2, 9, 13, SemanticTokenType::String),
+        ];
+
+        let data = to_lsp_semantic_tokens(tokens)
+            .into_iter()
+            .map(|token| {
+                (
+                    token.delta_line,
+                    token.delta_start,
+                    token.length,
+                    token.token_type,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![(1, 0, 6, 0), (0, 7, 6, 1), (1, 4, 4, 4), (0, 5, 4, 3)],
+            data
+        );
+    }
+
+    #[test]
+    fn list_path_completions_in_directory() {
+        let directory = env::temp_dir().join(format!("gdbls-path-completions-{}", process::id()));
+        fs::create_dir_all(directory.join("scripts")).unwrap();
+        for file in &["helpers.gdb", "hello.py", ".gdbinit", "other.gdb"] {
+            fs::write(directory.join(file), "").unwrap();
+        }
+
+        let all = list_path_completions(&PathCompletion {
+            directory: directory.clone(),
+            file_name_prefix: String::new(),
+        });
+        let prefixed = list_path_completions(&PathCompletion {
+            directory: directory.clone(),
+            file_name_prefix: "he".to_owned(),
+        });
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(
+            vec![".gdbinit", "helpers.gdb", "other.gdb", "scripts/"],
+            all
+        );
+        assert_eq!(vec!["helpers.gdb"], prefixed);
+    }
+
+    #[test]
+    fn completion_item_kinds() {
+        let uri = Url::parse("file:///home/user/foo.gdb").unwrap();
+        let completions = Completions {
+            built_in: vec![Completion {
+                text: "define".to_owned(),
+            }],
+            user_provided: vec![Completion {
+                text: "say_hi".to_owned(),
+            }],
+            path: None,
+        };
+
+        let items = completion_items(
+            completions,
+            &uri,
+            lsp_types::Position::new(0, 2),
+            PositionEncoding::Utf16,
+        );
+        assert_eq!(
+            vec![
+                ("say_hi", Some(CompletionItemKind::Function)),
+                ("define", Some(CompletionItemKind::Keyword)),
+            ],
+            items
+                .iter()
+                .map(|item| (item.label.as_str(), item.kind))
+                .collect::<Vec<(&str, Option<CompletionItemKind>)>>()
+        );
+        // The document is kept so the item can be resolved later.
+        assert!(items.iter().all(
+            |item| item.data == Some(serde_json::json!({ "uri": "file:///home/user/foo.gdb" }))
+        ));
+    }
+
+    #[test]
+    fn load_gdbinit_is_opt_in() {
+        let check = |initialization_options: serde_json::Value, expected: bool| {
+            let params = serde_json::from_value::<InitializeParams>(serde_json::json!({
+                "processId": null,
+                "rootUri": null,
+                "capabilities": {},
+                "initializationOptions": initialization_options,
+            }))
+            .unwrap();
+            assert_eq!(expected, load_gdbinit_requested(&params));
+        };
+
+        check(serde_json::json!({ "loadGdbinit": true }), true);
+        check(serde_json::json!({ "loadGdbinit": false }), false);
+        check(serde_json::json!({}), false);
+        check(serde_json::Value::Null, false);
+    }
+
+    #[test]
+    fn project_root_is_first_workspace_folder() {
+        let params = |workspace_folders: serde_json::Value| {
+            serde_json::from_value::<InitializeParams>(serde_json::json!({
+                "processId": null,
+                "rootUri": null,
+                "capabilities": {},
+                "workspaceFolders": workspace_folders,
+            }))
+            .unwrap()
+        };
+
+        let workspace_params = params(serde_json::json!([
+            { "uri": "file:///home/user/project", "name": "project" },
+            { "uri": "file:///home/user/other", "name": "other" },
+        ]));
+        let workspace_root = project_root(&workspace_params).unwrap();
+        assert_eq!(PathBuf::from("/home/user/project"), workspace_root);
+        assert_eq!(
+            env::current_dir().unwrap(),
+            project_root(&params(serde_json::Value::Null)).unwrap()
+        );
+
+        // Relative paths are resolved against the workspace folder rather than
+        // the directory the server was started in.
+        let mut semantics = Semantics::new(workspace_root.clone());
+        let unresolved_imports = semantics.set_file_text(
+            workspace_root.join("main.gdb"),
+            "source helpers.gdb\n".to_owned(),
+        );
+        assert_eq!(
+            workspace_root.join("helpers.gdb"),
+            unresolved_imports.paths[0].path
+        );
+    }
+
+    #[test]
+    fn tab_width_initialization_option() {
+        let check = |initialization_options: serde_json::Value, expected: Option<usize>| {
+            let params = serde_json::from_value::<InitializeParams>(serde_json::json!({
+                "processId": null,
+                "rootUri": null,
+                "capabilities": {},
+                "initializationOptions": initialization_options,
+            }))
+            .unwrap();
+            assert_eq!(expected, tab_width_option(&params));
+        };
+
+        check(serde_json::json!({ "tabWidth": 2 }), Some(2));
+        check(serde_json::json!({ "tabWidth": "2" }), None);
+        check(serde_json::json!({}), None);
+        check(serde_json::Value::Null, None);
+    }
+
+    #[test]
+    fn extra_commands_initialization_option() {
+        let check = |initialization_options: serde_json::Value, expected: &[&str]| {
+            let params = serde_json::from_value::<InitializeParams>(serde_json::json!({
+                "processId": null,
+                "rootUri": null,
+                "capabilities": {},
+                "initializationOptions": initialization_options,
+            }))
+            .unwrap();
+            assert_eq!(expected, extra_commands_option(&params));
+        };
+
+        check(
+            serde_json::json!({ "extraCommands": ["telescope", 1, "pwndbg"] }),
+            &["telescope", "pwndbg"],
+        );
+        check(serde_json::json!({ "extraCommands": "telescope" }), &[]);
+        check(serde_json::json!({}), &[]);
+        check(serde_json::Value::Null, &[]);
+    }
+
+    #[test]
+    fn preload_gdbinit_in_project_root() {
+        let project_root = env::temp_dir().join(format!("gdbls-preload-gdbinit-{}", process::id()));
+        let empty_project_root = project_root.join("empty");
+        fs::create_dir_all(&empty_project_root).unwrap();
+        fs::write(project_root.join(".gdbinit"), "source helpers.gdb\n").unwrap();
+        fs::write(
+            project_root.join("helpers.gdb"),
+            "define say_hi\n    echo hi\nend\n",
+        )
+        .unwrap();
+
+        let mut semantics = Semantics::new(project_root.clone());
+        preload_gdbinit(&mut semantics, &project_root);
+        let mut empty_semantics = Semantics::new(empty_project_root.clone());
+        preload_gdbinit(&mut empty_semantics, &empty_project_root);
+        fs::remove_dir_all(&project_root).unwrap();
+
+        assert!(semantics.has_file(&project_root.join(".gdbinit")));
+        assert_eq!(
+            vec!["say_hi"],
+            semantics
+                .definitions_in(&project_root.join("helpers.gdb"))
+                .into_iter()
+                .map(|definition| definition.name)
+                .collect::<Vec<String>>()
+        );
+        assert!(!empty_semantics.has_file(&empty_project_root.join(".gdbinit")));
+    }
+
+    #[test]
+    fn list_path_completions_missing_directory() {
+        let completions = list_path_completions(&PathCompletion {
+            directory: PathBuf::from("/path/which/does/not/exist"),
+            file_name_prefix: String::new(),
+        });
+
+        assert!(completions.is_empty());
+    }
 }
@@ -0,0 +1,155 @@
+pub(crate) mod commands;
+
+/// Built-in GDB commands along with a short description of each.
+const BUILT_INS: &[(&str, &str)] = &[
+    ("define", "Define a new user command."),
+    ("document", "Document a user defined command."),
+    ("echo", "Print a constant string."),
+    ("else", "Begin the alternate branch of an `if` command."),
+    ("end", "End a block of commands."),
+    (
+        "if",
+        "Execute the following commands if the condition is true.",
+    ),
+    ("loop_break", "Exit the innermost `while` loop."),
+    (
+        "loop_continue",
+        "Skip to the next iteration of the innermost `while` loop.",
+    ),
+    ("print", "Print the value of an expression."),
+    (
+        "set",
+        "Evaluate an expression and assign the result, or change a setting.",
+    ),
+    ("show", "Show the value of a setting."),
+    ("source", "Read and execute commands from a file."),
+    (
+        "while",
+        "Execute the following commands while the condition is true.",
+    ),
+];
+
+/// Returns true if the given command name is a GDB built-in command, or an
+/// abbreviation GDB would accept for one. See `resolve_builtin`.
+pub(crate) fn is_built_in(command: &str) -> bool {
+    resolve_builtin(command).is_some()
+}
+
+/// Returns true if the given name is exactly that of a built-in command or
+/// predefined alias, without resolving abbreviations. This is how GDB decides
+/// whether a `define` replaces a built-in command.
+pub(crate) fn is_built_in_name(name: &str) -> bool {
+    commands::COMMANDS.contains(&name)
+}
+
+/// Resolves the given command name to the full name of the built-in command it
+/// refers to, following GDB's rules. A name which exactly matches a command or a
+/// predefined alias refers to that command, for example `b` refers to `break`.
+/// Otherwise GDB accepts any prefix which matches a single command, so `brea`
+/// also refers to `break`, while `de` is ambiguous and resolves to nothing.
+pub(crate) fn resolve_builtin(name: &str) -> Option<&'static str> {
+    if name.is_empty() {
+        return None;
+    }
+    if let Some(&command) = commands::COMMANDS.iter().find(|&&command| command == name) {
+        return Some(unalias(command));
+    }
+
+    let mut matches = commands::COMMANDS
+        .iter()
+        .filter(|command| command.starts_with(name) && !commands::TARGET_SPECIFIC.contains(command))
+        .map(|command| unalias(command));
+    let first = matches.next()?;
+    if matches.all(|command| command == first) {
+        Some(first)
+    } else {
+        None
+    }
+}
+
+/// Returns the command the given built-in command name stands for, if it is a
+/// predefined alias, or the name itself otherwise.
+fn unalias(command: &'static str) -> &'static str {
+    commands::ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == command)
+        .map_or(command, |(_, full_name)| *full_name)
+}
+
+/// Built-in GDB commands which have subcommands, and so may be followed by the
+/// name of a subcommand when defining a command, as in `define target hookpost-foo`.
+const PREFIX_COMMANDS: &[&str] = &[
+    "catch",
+    "compile",
+    "delete",
+    "disable",
+    "enable",
+    "frame",
+    "info",
+    "maintenance",
+    "record",
+    "set",
+    "show",
+    "skip",
+    "target",
+    "thread",
+    "tui",
+    "unset",
+];
+
+/// Returns true if the given word is a built-in GDB prefix command.
+pub(crate) fn is_prefix_command(command: &str) -> bool {
+    PREFIX_COMMANDS.contains(&command)
+}
+
+/// Returns a short description of the given built-in command, if it is known.
+/// Abbreviations are resolved to the command they refer to.
+pub(crate) fn description(command: &str) -> Option<&'static str> {
+    let command = resolve_builtin(command)?;
+    BUILT_INS
+        .iter()
+        .find(|(name, _)| *name == command)
+        .map(|(_, description)| *description)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{description, resolve_builtin};
+
+    #[test]
+    fn resolve_abbreviations() {
+        assert_eq!(Some("break"), resolve_builtin("b"));
+        assert_eq!(Some("break"), resolve_builtin("br"));
+        assert_eq!(Some("break"), resolve_builtin("brea"));
+        assert_eq!(Some("break"), resolve_builtin("break"));
+        assert_eq!(Some("continue"), resolve_builtin("c"));
+        assert_eq!(Some("print"), resolve_builtin("p"));
+        assert_eq!(Some("display"), resolve_builtin("disp"));
+    }
+
+    #[test]
+    fn resolve_exact_match_over_abbreviation() {
+        // `x` is a prefix of nothing else, but `n` is a prefix of several commands
+        // and is still resolved through its alias.
+        assert_eq!(Some("x"), resolve_builtin("x"));
+        assert_eq!(Some("next"), resolve_builtin("n"));
+    }
+
+    #[test]
+    fn resolve_ambiguous_abbreviation() {
+        assert_eq!(None, resolve_builtin("de"));
+        assert_eq!(None, resolve_builtin("tst"));
+    }
+
+    #[test]
+    fn description_of_abbreviation() {
+        assert_eq!(description("print"), description("p"));
+        assert!(description("p").is_some());
+    }
+
+    #[test]
+    fn resolve_unknown_command() {
+        assert_eq!(None, resolve_builtin(""));
+        assert_eq!(None, resolve_builtin("say_hi"));
+    }
+}
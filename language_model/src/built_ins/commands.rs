@@ -0,0 +1,300 @@
+/// The names of GDB's built-in commands, including their common aliases. This
+/// aims to be complete enough that calls to built-in commands are not mistaken
+/// for calls to undefined user commands.
+pub(crate) const COMMANDS: &[&str] = &[
+    "!",
+    "actions",
+    "add-auto-load-safe-path",
+    "add-auto-load-scripts-directory",
+    "add-inferior",
+    "add-symbol-file",
+    "add-symbol-file-from-memory",
+    "advance",
+    "agent-printf",
+    "alias",
+    "append",
+    "apropos",
+    "attach",
+    "awatch",
+    "b",
+    "backtrace",
+    "bookmark",
+    "br",
+    "break",
+    "break-range",
+    "bt",
+    "c",
+    "call",
+    "catch",
+    "cd",
+    "checkpoint",
+    "clear",
+    "clone-inferior",
+    "collect",
+    "commands",
+    "compare-sections",
+    "compile",
+    "complete",
+    "condition",
+    "cont",
+    "continue",
+    "core",
+    "core-file",
+    "d",
+    "define",
+    "define-prefix",
+    "delete",
+    "demangle",
+    "detach",
+    "dir",
+    "directory",
+    "dis",
+    "disable",
+    "disassemble",
+    "disconnect",
+    "display",
+    "document",
+    "dont-repeat",
+    "down",
+    "down-silently",
+    "dprintf",
+    "dump",
+    "e",
+    "echo",
+    "edit",
+    "else",
+    "en",
+    "enable",
+    "end",
+    "eval",
+    "exec-file",
+    "explore",
+    "f",
+    "faas",
+    "fg",
+    "file",
+    "find",
+    "finish",
+    "flushregs",
+    "focus",
+    "forward-search",
+    "frame",
+    "fs",
+    "ftrace",
+    "function",
+    "gcore",
+    "generate-core-file",
+    "goto-bookmark",
+    "gr",
+    "gu",
+    "guile",
+    "guile-repl",
+    "h",
+    "handle",
+    "hbreak",
+    "help",
+    "i",
+    "if",
+    "ignore",
+    "inferior",
+    "info",
+    "init-if-undefined",
+    "inspect",
+    "interpreter-exec",
+    "interrupt",
+    "j",
+    "jit-reader-load",
+    "jit-reader-unload",
+    "jump",
+    "k",
+    "kill",
+    "l",
+    "layout",
+    "list",
+    "load",
+    "loop_break",
+    "loop_continue",
+    "macro",
+    "maint",
+    "maintenance",
+    "make",
+    "mem",
+    "memory-tag",
+    "monitor",
+    "mt",
+    "n",
+    "new-ui",
+    "next",
+    "nexti",
+    "ni",
+    "nosharedlibrary",
+    "output",
+    "ov",
+    "overlay",
+    "p",
+    "passcount",
+    "path",
+    "pi",
+    "pipe",
+    "po",
+    "print",
+    "print-object",
+    "printf",
+    "ptype",
+    "pwd",
+    "py",
+    "python",
+    "python-interactive",
+    "q",
+    "queue-signal",
+    "quit",
+    "r",
+    "rbreak",
+    "rc",
+    "rec",
+    "record",
+    "refresh",
+    "remote",
+    "remove-inferiors",
+    "remove-symbol-file",
+    "restart",
+    "restore",
+    "return",
+    "reverse-continue",
+    "reverse-finish",
+    "reverse-next",
+    "reverse-nexti",
+    "reverse-search",
+    "reverse-step",
+    "reverse-stepi",
+    "rn",
+    "rni",
+    "rs",
+    "rsi",
+    "run",
+    "rwatch",
+    "s",
+    "save",
+    "search",
+    "section",
+    "select-frame",
+    "set",
+    "sharedlibrary",
+    "shell",
+    "show",
+    "si",
+    "signal",
+    "skip",
+    "source",
+    "start",
+    "starti",
+    "step",
+    "stepi",
+    "stepping",
+    "stop",
+    "strace",
+    "symbol-file",
+    "t",
+    "taas",
+    "target",
+    "task",
+    "tbreak",
+    "tcatch",
+    "tdump",
+    "teval",
+    "tfaas",
+    "tfind",
+    "thbreak",
+    "thread",
+    "tp",
+    "trace",
+    "tsave",
+    "tstart",
+    "tstatus",
+    "tstop",
+    "tty",
+    "tui",
+    "tvariable",
+    "u",
+    "undisplay",
+    "unset",
+    "until",
+    "up",
+    "up-silently",
+    "update",
+    "w",
+    "watch",
+    "wh",
+    "whatis",
+    "where",
+    "while",
+    "while-stepping",
+    "winheight",
+    "with",
+    "ws",
+    "x",
+    "|",
+];
+
+/// GDB's predefined aliases, each paired with the command it stands for. Every
+/// alias is also listed in `COMMANDS`.
+pub(crate) const ALIASES: &[(&str, &str)] = &[
+    ("!", "shell"),
+    ("b", "break"),
+    ("br", "break"),
+    ("bt", "backtrace"),
+    ("c", "continue"),
+    ("cont", "continue"),
+    ("core", "core-file"),
+    ("d", "delete"),
+    ("dir", "directory"),
+    ("dis", "disable"),
+    ("e", "edit"),
+    ("en", "enable"),
+    ("f", "frame"),
+    ("fg", "continue"),
+    ("fs", "focus"),
+    ("gcore", "generate-core-file"),
+    ("gr", "guile-repl"),
+    ("gu", "guile"),
+    ("h", "help"),
+    ("i", "info"),
+    ("inspect", "print"),
+    ("j", "jump"),
+    ("k", "kill"),
+    ("l", "list"),
+    ("maint", "maintenance"),
+    ("mt", "maintenance"),
+    ("n", "next"),
+    ("ni", "nexti"),
+    ("ov", "overlay"),
+    ("p", "print"),
+    ("pi", "python-interactive"),
+    ("po", "print-object"),
+    ("py", "python"),
+    ("q", "quit"),
+    ("r", "run"),
+    ("rc", "reverse-continue"),
+    ("rec", "record"),
+    ("rn", "reverse-next"),
+    ("rni", "reverse-nexti"),
+    ("rs", "reverse-step"),
+    ("rsi", "reverse-stepi"),
+    ("s", "step"),
+    ("search", "forward-search"),
+    ("si", "stepi"),
+    ("stepping", "while-stepping"),
+    ("t", "thread"),
+    ("tp", "trace"),
+    ("u", "until"),
+    ("w", "with"),
+    ("wh", "winheight"),
+    ("where", "backtrace"),
+    ("ws", "while-stepping"),
+    ("|", "pipe"),
+];
+
+/// Commands which GDB only provides for some targets. These are left out when
+/// resolving abbreviations, so that for example `brea` is not ambiguous between
+/// `break` and `break-range`.
+pub(crate) const TARGET_SPECIFIC: &[&str] = &["break-range"];
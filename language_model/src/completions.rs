@@ -1,8 +1,14 @@
-use crate::parse::{self, Location};
+use crate::parse::{self, byte_offset, CommandLine, Location};
+
+pub(crate) mod settings;
+pub(crate) mod variables;
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum CompletionPosition<'a> {
-    Command,
+    Command {
+        /// The part of the command at the cursor which comes before the cursor.
+        typed: &'a str,
+    },
     Arg(CompletionPositionArg<'a>),
 }
 
@@ -16,27 +22,40 @@ pub(crate) struct CompletionPositionArg<'a> {
     /// included here, since that is the token we would be trying
     /// to complete.
     pub leading_args: Vec<&'a str>,
+    /// The part of the arg at the cursor which comes before the cursor.
+    pub typed: &'a str,
 }
 
 impl<'a> CompletionPosition<'a> {
-    pub(crate) fn new(script: &'a str, cursor_position: Location) -> Option<Self> {
-        let line = parse::iters::lines(script)
-            .find(|line| line.start_line_in_file == cursor_position.line)?;
-        let mut tokens_before_this = parse::iters::tokens(&line).take_while(|token| {
-            token.location_in_file.column + token.text.len() < cursor_position.column
-        });
-
+    /// Finds what is being completed at the given position, from the command
+    /// lines of the file, as returned by `parse::iters::lines`.
+    pub(crate) fn new(lines: &[CommandLine<'a>], cursor_position: Location) -> Option<Self> {
+        let line = parse::iters::line_at(lines, cursor_position.line)?;
+        let mut tokens_before_this =
+            parse::iters::tokens(&line).take_while(|token| token.end_location() < cursor_position);
+
+        let typed = typed_prefix(&line, cursor_position);
         let res = match tokens_before_this.next() {
             Some(command) => CompletionPosition::Arg(CompletionPositionArg {
                 command: command.text,
                 leading_args: tokens_before_this.map(|t| t.text).collect(),
+                typed,
             }),
-            None => CompletionPosition::Command,
+            None => CompletionPosition::Command { typed },
         };
 
         Some(res)
     }
 
+    /// The text typed so far for the token being completed, which candidates
+    /// should start with.
+    pub(crate) fn typed(&self) -> &'a str {
+        match self {
+            CompletionPosition::Command { typed } => typed,
+            CompletionPosition::Arg(arg) => arg.typed,
+        }
+    }
+
     #[cfg(test)]
     fn into_arg(self) -> Option<CompletionPositionArg<'a>> {
         if let Self::Arg(v) = self {
@@ -47,9 +66,26 @@ impl<'a> CompletionPosition<'a> {
     }
 }
 
+/// Returns the part of the token at the cursor which comes before the cursor,
+/// which is the text typed so far for the token being completed.
+fn typed_prefix<'a>(line: &CommandLine<'a>, cursor_position: Location) -> &'a str {
+    parse::iters::tokens(line)
+        .find(|token| {
+            token.location_in_file.line == cursor_position.line
+                && token.location_in_file.column < cursor_position.column
+                && cursor_position.column <= token.end_location().column
+        })
+        .map_or("", |token| {
+            &token.text[..byte_offset(
+                token.text,
+                cursor_position.column - token.location_in_file.column,
+            )]
+        })
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::test_support::parse_cursor_position;
+    use crate::{parse::iters::lines, test_support::parse_cursor_position};
 
     use super::CompletionPosition;
 
@@ -57,27 +93,36 @@ mod tests {
     fn empty_script() {
         let (script, location) = parse_cursor_position("<|>");
         let completion_position =
-            CompletionPosition::new(&script, location).expect("should resolve completion position");
+            CompletionPosition::new(&lines(&script).collect::<Vec<_>>(), location)
+                .expect("should resolve completion position");
 
-        assert_eq!(CompletionPosition::Command, completion_position);
+        assert_eq!(
+            CompletionPosition::Command { typed: "" },
+            completion_position
+        );
     }
 
     #[test]
     fn if_cursor_on_command_it_is_not_included() {
         let (script, location) = parse_cursor_position("def<|>");
         let completion_position =
-            CompletionPosition::new(&script, location).expect("should resolve completion position");
+            CompletionPosition::new(&lines(&script).collect::<Vec<_>>(), location)
+                .expect("should resolve completion position");
 
-        assert_eq!(CompletionPosition::Command, completion_position);
+        assert_eq!(
+            CompletionPosition::Command { typed: "def" },
+            completion_position
+        );
     }
 
     #[test]
     fn first_and_only_arg() {
         let (script, location) = parse_cursor_position("define <|>");
-        let completion_position_arg = CompletionPosition::new(&script, location)
-            .expect("should resolve completion position")
-            .into_arg()
-            .expect("should resolve as arg");
+        let completion_position_arg =
+            CompletionPosition::new(&lines(&script).collect::<Vec<_>>(), location)
+                .expect("should resolve completion position")
+                .into_arg()
+                .expect("should resolve as arg");
 
         assert_eq!("define", completion_position_arg.command);
         assert!(completion_position_arg.leading_args.is_empty());
@@ -86,10 +131,11 @@ mod tests {
     #[test]
     fn last_arg() {
         let (script, location) = parse_cursor_position("set max-completions <|>");
-        let completion_position_arg = CompletionPosition::new(&script, location)
-            .expect("should resolve completion position")
-            .into_arg()
-            .expect("should resolve as arg");
+        let completion_position_arg =
+            CompletionPosition::new(&lines(&script).collect::<Vec<_>>(), location)
+                .expect("should resolve completion position")
+                .into_arg()
+                .expect("should resolve as arg");
 
         assert_eq!("set", completion_position_arg.command);
         assert_eq!(
@@ -101,10 +147,11 @@ mod tests {
     #[test]
     fn middle_arg() {
         let (script, location) = parse_cursor_position("set <|> max-completions");
-        let completion_position_arg = CompletionPosition::new(&script, location)
-            .expect("should resolve completion position")
-            .into_arg()
-            .expect("should resolve as arg");
+        let completion_position_arg =
+            CompletionPosition::new(&lines(&script).collect::<Vec<_>>(), location)
+                .expect("should resolve completion position")
+                .into_arg()
+                .expect("should resolve as arg");
 
         assert_eq!("set", completion_position_arg.command);
         assert!(completion_position_arg.leading_args.is_empty());
@@ -113,12 +160,27 @@ mod tests {
     #[test]
     fn if_cursor_on_arg_it_is_not_included() {
         let (script, location) = parse_cursor_position("set max-completions<|>");
-        let completion_position_arg = CompletionPosition::new(&script, location)
-            .expect("should resolve completion position")
-            .into_arg()
-            .expect("should resolve as arg");
+        let completion_position_arg =
+            CompletionPosition::new(&lines(&script).collect::<Vec<_>>(), location)
+                .expect("should resolve completion position")
+                .into_arg()
+                .expect("should resolve as arg");
 
         assert_eq!("set", completion_position_arg.command);
         assert!(completion_position_arg.leading_args.is_empty());
     }
+
+    #[test]
+    fn non_ascii_leading_arg() {
+        let (script, location) = parse_cursor_position(r#"echo "😀" $cou<|>"#);
+        let completion_position_arg =
+            CompletionPosition::new(&lines(&script).collect::<Vec<_>>(), location)
+                .expect("should resolve completion position")
+                .into_arg()
+                .expect("should resolve as arg");
+
+        assert_eq!("echo", completion_position_arg.command);
+        assert_eq!(vec![r#""😀""#], completion_position_arg.leading_args);
+        assert_eq!("$cou", completion_position_arg.typed);
+    }
 }
@@ -0,0 +1,79 @@
+/// A GDB setting which can be changed with `set` and displayed with `show`.
+pub(crate) struct Setting {
+    /// The words naming this setting, for example `["print", "pretty"]`.
+    pub name: &'static [&'static str],
+    /// The values this setting accepts, if it has a fixed set of values.
+    pub values: &'static [&'static str],
+}
+
+const ON_OFF: &[&str] = &["on", "off"];
+
+/// Well known GDB settings. This is not an exhaustive list.
+pub(crate) const SETTINGS: &[Setting] = &[
+    Setting {
+        name: &["confirm"],
+        values: ON_OFF,
+    },
+    Setting {
+        name: &["height"],
+        values: &[],
+    },
+    Setting {
+        name: &["listsize"],
+        values: &[],
+    },
+    Setting {
+        name: &["logging"],
+        values: ON_OFF,
+    },
+    Setting {
+        name: &["max-completions"],
+        values: &["unlimited"],
+    },
+    Setting {
+        name: &["pagination"],
+        values: ON_OFF,
+    },
+    Setting {
+        name: &["print", "pretty"],
+        values: ON_OFF,
+    },
+    Setting {
+        name: &["print", "elements"],
+        values: &["unlimited"],
+    },
+    Setting {
+        name: &["verbose"],
+        values: ON_OFF,
+    },
+    Setting {
+        name: &["width"],
+        values: &[],
+    },
+];
+
+/// Returns the next words which could follow `set` or `show` and the given
+/// leading args. Once a full setting name has been given, the setting values
+/// are returned if `include_values` is true.
+pub(crate) fn complete(leading_args: &[&str], include_values: bool) -> Vec<&'static str> {
+    let mut completions = vec![];
+
+    for setting in SETTINGS {
+        if !setting.name.starts_with(leading_args) {
+            continue;
+        }
+
+        let next_words = match setting.name.get(leading_args.len()) {
+            Some(next_word) => std::slice::from_ref(next_word),
+            None if include_values => setting.values,
+            None => &[],
+        };
+        for &word in next_words {
+            if !completions.contains(&word) {
+                completions.push(word);
+            }
+        }
+    }
+
+    completions
+}
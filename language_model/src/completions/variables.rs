@@ -0,0 +1,44 @@
+use crate::parse::Command;
+
+/// Registers and convenience variables which GDB provides.
+pub(crate) const BUILT_IN_VARIABLES: &[&str] = &[
+    "$pc",
+    "$sp",
+    "$fp",
+    "$ps",
+    "$_",
+    "$__",
+    "$_exitcode",
+    "$_siginfo",
+    "$_thread",
+    "$bpnum",
+    "$argc",
+];
+
+/// Operators which join two operands into a condition, such as that of an `if`.
+pub(crate) const CONDITION_OPERATORS: &[&str] = &["==", "!=", "<", "<=", ">", ">=", "&&", "||"];
+
+/// Collects the names of convenience variables assigned with `set $name = ...`
+/// in the given commands, including nested commands.
+pub(crate) fn collect_user_defined<'a>(commands: &[Command<'a>], variables: &mut Vec<&'a str>) {
+    for command in commands {
+        match command {
+            Command::Define { body, .. }
+            | Command::While { body, .. }
+            | Command::Commands { body, .. } => collect_user_defined(body, variables),
+            Command::If {
+                body, else_body, ..
+            } => {
+                collect_user_defined(body, variables);
+                collect_user_defined(else_body, variables);
+            }
+            Command::SetVar { name, .. } => variables.push(name.text),
+            Command::DefinePrefix { .. }
+            | Command::Document { .. }
+            | Command::Embedded { .. }
+            | Command::Shell { .. }
+            | Command::Source { .. }
+            | Command::Other { .. } => {}
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    built_ins, command_name,
+    parse::{Command, Location, Token},
+    Severity,
+};
+
+/// A problem found in a script, in terms of file locations. This is converted
+/// to a public `Diagnostic` by `Semantics::diagnostics`.
+#[derive(Debug)]
+pub(crate) struct RawDiagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub start: Location,
+    pub end: Location,
+    /// Other locations in the same file which are relevant to this problem.
+    pub related: Vec<RawRelatedLocation>,
+}
+
+#[derive(Debug)]
+pub(crate) struct RawRelatedLocation {
+    pub message: String,
+    pub start: Location,
+    pub end: Location,
+}
+
+impl RawDiagnostic {
+    fn error_on_token(token: &Token, message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            message,
+            start: token.location_in_file,
+            end: token.end_location(),
+            related: vec![],
+        }
+    }
+}
+
+/// Collects the syntax errors in the given commands, including nested
+/// commands. The enclosing define is the `define` token of the block containing
+/// the commands, if there is one, since GDB doesn't allow defines to be nested.
+pub(crate) fn syntax_errors(
+    commands: &[Command],
+    enclosing_define: Option<&Token>,
+    diagnostics: &mut Vec<RawDiagnostic>,
+) {
+    for command in commands {
+        match command {
+            Command::Define {
+                define,
+                unexpected,
+                body,
+                end,
+                ..
+            } => {
+                if let Some(enclosing_define) = enclosing_define {
+                    diagnostics.push(RawDiagnostic {
+                        related: vec![RawRelatedLocation {
+                            message: "enclosing `define`".to_owned(),
+                            start: enclosing_define.location_in_file,
+                            end: enclosing_define.end_location(),
+                        }],
+                        ..RawDiagnostic::error_on_token(
+                            define,
+                            "`define` cannot be nested within another `define`".to_owned(),
+                        )
+                    });
+                }
+                if end.is_none() {
+                    diagnostics.push(RawDiagnostic::error_on_token(
+                        define,
+                        "`define` is missing a matching `end`".to_owned(),
+                    ));
+                }
+                if let (Some(first), Some(last)) = (unexpected.first(), unexpected.last()) {
+                    diagnostics.push(RawDiagnostic {
+                        severity: Severity::Error,
+                        message: "`define` takes a single command name".to_owned(),
+                        start: first.location_in_file,
+                        end: last.end_location(),
+                        related: vec![],
+                    });
+                }
+                syntax_errors(body, Some(define), diagnostics);
+            }
+            Command::Document { document, end, .. } => {
+                if end.is_none() {
+                    diagnostics.push(RawDiagnostic::error_on_token(
+                        document,
+                        "`document` is missing a matching `end`".to_owned(),
+                    ));
+                }
+            }
+            Command::Embedded { language, end, .. } => {
+                if end.is_none() {
+                    diagnostics.push(RawDiagnostic::error_on_token(
+                        language,
+                        format!("`{}` is missing a matching `end`", language.text),
+                    ));
+                }
+            }
+            Command::If {
+                r#if,
+                body,
+                else_body,
+                end,
+                ..
+            } => {
+                if end.is_none() {
+                    diagnostics.push(RawDiagnostic::error_on_token(
+                        r#if,
+                        "`if` is missing a matching `end`".to_owned(),
+                    ));
+                }
+                syntax_errors(body, enclosing_define, diagnostics);
+                syntax_errors(else_body, enclosing_define, diagnostics);
+            }
+            Command::While {
+                r#while, body, end, ..
+            } => {
+                if end.is_none() {
+                    diagnostics.push(RawDiagnostic::error_on_token(
+                        r#while,
+                        "`while` is missing a matching `end`".to_owned(),
+                    ));
+                }
+                syntax_errors(body, enclosing_define, diagnostics);
+            }
+            Command::Commands {
+                commands,
+                body,
+                end,
+                ..
+            } => {
+                if end.is_none() {
+                    diagnostics.push(RawDiagnostic::error_on_token(
+                        commands,
+                        "`commands` is missing a matching `end`".to_owned(),
+                    ));
+                }
+                syntax_errors(body, enclosing_define, diagnostics);
+            }
+            // An `else` which belongs to an `if` ends the `if` body, so any
+            // other `else` is parsed as an ordinary command.
+            Command::Other { command, .. } if command.text == "else" => {
+                diagnostics.push(RawDiagnostic::error_on_token(
+                    command,
+                    "`else` without a matching `if`".to_owned(),
+                ));
+            }
+            Command::DefinePrefix { .. }
+            | Command::SetVar { .. }
+            | Command::Shell { .. }
+            | Command::Source { .. }
+            | Command::Other { .. } => {}
+        }
+    }
+}
+
+/// Warns about each top level `define` of a command which was already defined
+/// earlier in the same file. Redefining a command is allowed, but is often a
+/// mistake.
+///
+/// Defines nested within other blocks are not checked, since defining a command
+/// differently depending on a condition is a reasonable thing to do.
+pub(crate) fn duplicate_definitions(commands: &[Command], diagnostics: &mut Vec<RawDiagnostic>) {
+    let mut earlier_definitions: HashMap<String, (Location, Location)> = HashMap::new();
+
+    for command in commands {
+        if let Command::Define { identifier, .. } = command {
+            let (first, last) = match (identifier.first(), identifier.last()) {
+                (Some(first), Some(last)) => (first, last),
+                _ => continue,
+            };
+            let name = command_name(identifier);
+            let range = (first.location_in_file, last.end_location());
+
+            if let Some((earlier_start, earlier_end)) =
+                earlier_definitions.insert(name.clone(), range)
+            {
+                diagnostics.push(RawDiagnostic {
+                    severity: Severity::Warning,
+                    message: format!("`{}` is already defined", name),
+                    start: range.0,
+                    end: range.1,
+                    related: vec![RawRelatedLocation {
+                        message: "previous definition".to_owned(),
+                        start: earlier_start,
+                        end: earlier_end,
+                    }],
+                });
+            }
+        }
+    }
+}
+
+/// Names which the parser always treats as the start or end of a block, so a
+/// user defined command with one of these names can never be called.
+const KEYWORDS: &[&str] = &["if", "else", "while", "end", "define", "source"];
+
+/// Warns about each `define` of a command with the same name as a GDB built-in
+/// command, including in nested commands. GDB asks for confirmation before
+/// replacing a built-in command, and scripts run without confirmation fail.
+///
+/// A `define` of a keyword is an error instead, since the command can't be
+/// called normally.
+pub(crate) fn shadowed_built_ins(commands: &[Command], diagnostics: &mut Vec<RawDiagnostic>) {
+    for command in commands {
+        match command {
+            Command::Define { identifier, .. } => {
+                if let [name] = &identifier[..] {
+                    if KEYWORDS.contains(&name.text) {
+                        diagnostics.push(RawDiagnostic::error_on_token(
+                            name,
+                            format!("`{}` is a keyword and cannot be defined", name.text),
+                        ));
+                    } else if built_ins::is_built_in_name(name.text) {
+                        diagnostics.push(RawDiagnostic {
+                            severity: Severity::Warning,
+                            message: format!("`{}` redefines a built-in command", name.text),
+                            start: name.location_in_file,
+                            end: name.end_location(),
+                            related: vec![],
+                        });
+                    }
+                }
+            }
+            Command::If {
+                body, else_body, ..
+            } => {
+                shadowed_built_ins(body, diagnostics);
+                shadowed_built_ins(else_body, diagnostics);
+            }
+            Command::While { body, .. } => shadowed_built_ins(body, diagnostics),
+            _ => {}
+        }
+    }
+}
+
+/// Hints at each command which is neither a GDB built-in nor one of the given
+/// user defined commands, including in nested commands. Only the first word of
+/// each user defined command name is considered.
+pub(crate) fn undefined_commands(
+    commands: &[Command],
+    user_defined: &HashSet<&str>,
+    diagnostics: &mut Vec<RawDiagnostic>,
+) {
+    for command in commands {
+        match command {
+            Command::Define { body, .. } | Command::While { body, .. } => {
+                undefined_commands(body, user_defined, diagnostics)
+            }
+            Command::If {
+                body, else_body, ..
+            } => {
+                undefined_commands(body, user_defined, diagnostics);
+                undefined_commands(else_body, user_defined, diagnostics);
+            }
+            Command::Commands { body, .. } => {
+                // A breakpoint command list may start with `silent`, which is only
+                // a command in this position.
+                let body = match body.first() {
+                    Some(Command::Other { command, args })
+                        if command.text == "silent" && args.is_empty() =>
+                    {
+                        &body[1..]
+                    }
+                    _ => &body[..],
+                };
+                undefined_commands(body, user_defined, diagnostics);
+            }
+            Command::Other { command, .. } => {
+                if !built_ins::is_built_in(command.text) && !user_defined.contains(command.text) {
+                    diagnostics.push(RawDiagnostic {
+                        severity: Severity::Hint,
+                        message: format!("`{}` is not a known command", command.text),
+                        start: command.location_in_file,
+                        end: command.end_location(),
+                        related: vec![],
+                    });
+                }
+            }
+            Command::DefinePrefix { .. }
+            | Command::Document { .. }
+            | Command::Embedded { .. }
+            | Command::SetVar { .. }
+            | Command::Shell { .. }
+            | Command::Source { .. } => {}
+        }
+    }
+}
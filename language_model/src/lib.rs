@@ -1,355 +1,6023 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    iter::Peekable,
+    ops::Range,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
+mod built_ins;
+
 mod completions;
+
+mod diagnostics;
 use completions::CompletionPosition;
 
 mod parse;
-use parse::{parse, Command};
+use parse::{Command, Location, Token};
+
+mod parsed_file;
+use parsed_file::ParsedFile;
 
 #[cfg(test)]
 mod test_support;
 
+/// Answers queries about the files of a project.
+///
+/// `Semantics` is `Send` and `Sync`, and cloning it is cheap, since each parsed
+/// file is shared between clones rather than copied. A server can hand a clone
+/// to another thread as a snapshot to answer queries from, while it keeps
+/// applying changes to the original. Changes to one never affect the other.
+#[derive(Clone)]
 pub struct Semantics {
     /// All relative imports are assumed to be relative to the project root.
     project_root: PathBuf,
     /// All known files in the project. This struct does no direct file IO, so
-    /// the only known files are ones which have been explicitly added.
-    files: HashMap<PathBuf, String>,
+    /// the only known files are ones which have been explicitly added. Files
+    /// are replaced rather than modified, so they can be shared between clones.
+    files: HashMap<PathBuf, Arc<ParsedFile>>,
+    /// The environment variables used to expand `source` paths, which are
+    /// captured from the process environment on creation.
+    environment: HashMap<String, String>,
+    /// The unit in which columns of the positions passed in and returned are
+    /// measured.
+    position_encoding: PositionEncoding,
+    /// Whether `source` commands are followed into other files.
+    follow_sources: bool,
+    /// The number of spaces a tab is converted to.
+    tab_width: usize,
+    /// Names of commands which are known to GDB in addition to its built-in
+    /// commands, such as those added by plugins.
+    extra_commands: Vec<String>,
 }
 
 impl Semantics {
+    /// Creates a `Semantics` with the default configuration. Use
+    /// `SemanticsBuilder` to configure it further.
     pub fn new(project_root: PathBuf) -> Self {
-        Self {
-            project_root,
-            files: HashMap::new(),
-        }
+        SemanticsBuilder::new(project_root).build()
+    }
+
+    /// Returns true if the given file has been added with `set_file_text`.
+    pub fn has_file(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
     }
 
     /// Sets the text content for a given file path. If the file `source`s any
     /// external files which are not already loaded, those paths are returned
-    /// as UnresolvedPaths.
+    /// as UnresolvedPaths, along with the position of each `source` command. No
+    /// paths are returned if `source` commands are not being followed.
+    ///
+    /// A relative `source` path may refer to a file next to the sourcing file or
+    /// in the project root, so when it differs the path relative to the project
+    /// root is returned as well. See `resolve_source_path`.
     ///
     /// The path must be an absolute path.
-    pub fn set_file_text(&mut self, path: PathBuf, text: String) -> UnresolvedPaths {
-        let unresolved_paths = parse(&text)
-            .into_iter()
-            .filter_map(|command| {
+    pub fn set_file_text(&mut self, path: PathBuf, text: String) -> UnresolvedPaths<'_> {
+        let file = Arc::new(ParsedFile::new(text));
+        let unresolved = file
+            .commands()
+            .iter()
+            .filter(|_| self.follow_sources)
+            .flat_map(|command| {
                 if let Command::Source {
+                    source,
                     file_path: Some(file_path),
                     ..
                 } = command
                 {
-                    let path = PathBuf::from(file_path.text);
-
+                    let path = self.resolve_source_path(file_path.text, &path);
                     if self.files.contains_key(&path) {
-                        None
-                    } else {
-                        let path = self.canonicalize_path(PathBuf::from(file_path.text));
+                        return vec![];
+                    }
 
-                        Some(path)
+                    let mut candidates = vec![(path, source.location_in_file)];
+                    let project_relative = self.canonicalize_path(file_path.text);
+                    if project_relative != candidates[0].0 {
+                        candidates.push((project_relative, source.location_in_file));
                     }
+                    candidates
                 } else {
-                    None
+                    vec![]
                 }
             })
-            .collect();
+            .collect::<Vec<(PathBuf, Location)>>();
+
+        self.files.insert(path.clone(), file);
+
+        // The position of each `source` borrows the path now owned by `files`.
+        let (file, _) = self
+            .files
+            .get_key_value(&path)
+            .expect("file was just inserted");
+        UnresolvedPaths {
+            paths: unresolved
+                .into_iter()
+                .map(|(path, location)| UnresolvedPath {
+                    path,
+                    source: self.encode_position(CursorPosition {
+                        file,
+                        line: location.line,
+                        column: location.column,
+                    }),
+                })
+                .collect(),
+        }
+    }
+
+    /// Removes the given file, which was added with `set_file_text`. Files which
+    /// `source` the removed file are left as they are, but will no longer find
+    /// anything defined in it.
+    pub fn remove_file(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+
+    /// Moves the given file, which was added with `set_file_text`, to a new path.
+    /// Files which `source` the old path are left as they are, but the returned
+    /// edits rewrite each of those `source` commands to refer to the new path.
+    /// The new path is written relative to the directory of the sourcing file
+    /// where possible, and is otherwise absolute.
+    ///
+    /// Relative `source` paths within the moved file itself are not rewritten.
+    pub fn rename_file(&mut self, from: &Path, to: &Path) -> WorkspaceEdit<'_> {
+        let mut sources = vec![];
+        for (file_path, file) in &self.files {
+            for command in file.commands() {
+                if let Command::Source {
+                    file_path: Some(source_path),
+                    ..
+                } = command
+                {
+                    if self.resolve_source_path(source_path.text, file_path) == from {
+                        sources.push((
+                            file_path.clone(),
+                            source_path.location_in_file,
+                            source_path.end_location(),
+                            Self::source_path_text(to, file_path),
+                        ));
+                    }
+                }
+            }
+        }
+
+        if let Some(file) = self.files.remove(from) {
+            self.files.insert(to.to_owned(), file);
+        }
+
+        let mut changes: HashMap<&Path, Vec<TextEdit>> = HashMap::new();
+        for (file_path, start, end, new_text) in sources {
+            let file_path = if file_path == from { to } else { &file_path };
+            let (file_path, _) = self
+                .files
+                .get_key_value(file_path)
+                .expect("sourcing file is known");
+            changes.entry(file_path).or_default().push(TextEdit {
+                range: self.encode_range(CursorRange::new(file_path, start, end)),
+                new_text,
+            });
+        }
+
+        WorkspaceEdit { changes }
+    }
+
+    /// Returns the `source` commands, across all known files, which refer to a
+    /// file that is not known. Unlike `set_file_text`, each `source` is reported
+    /// once, with the path it currently resolves to.
+    pub fn unresolved_imports(&self) -> Vec<UnresolvedPath<'_>> {
+        let mut unresolved = self
+            .files
+            .iter()
+            .flat_map(|(file_path, file)| {
+                self.unresolved_sources(file_path, file.commands())
+                    .into_iter()
+                    .map(move |(path, source, _)| UnresolvedPath {
+                        path,
+                        source: self.encode_position(CursorPosition {
+                            file: file_path,
+                            line: source.location_in_file.line,
+                            column: source.location_in_file.column,
+                        }),
+                    })
+            })
+            .collect::<Vec<UnresolvedPath>>();
+        unresolved.sort_by_key(|unresolved| {
+            (
+                unresolved.source.file,
+                unresolved.source.line,
+                unresolved.source.column,
+            )
+        });
+
+        unresolved
+    }
+
+    pub fn find_definition(&self, cursor_position: CursorPosition) -> Option<CursorPosition<'_>> {
+        let cursor_position = self.decode_position(cursor_position);
+
+        self.find_definition_position(cursor_position)
+            .map(|position| self.encode_position(position))
+    }
+
+    fn find_definition_position(
+        &self,
+        cursor_position: CursorPosition,
+    ) -> Option<CursorPosition<'_>> {
+        let usage = self.usage_at(cursor_position)?;
+
+        // The path of a `source` command refers to the start of the sourced file.
+        if source_path_index(&usage.words) == Some(usage.cursor_index) {
+            let path =
+                self.resolve_source_path(usage.words[usage.cursor_index], cursor_position.file);
+            let (file, _) = self.files.get_key_value(&path)?;
+            return Some(CursorPosition {
+                file,
+                line: 0,
+                column: 0,
+            });
+        }
+
+        // Arguments are "defined" by the user command they are passed to.
+        if is_argument_reference(usage.words[usage.cursor_index]) {
+            let definition = self.enclosing_definition(cursor_position)?;
+            return Some(CursorPosition {
+                file: definition.file,
+                line: definition.define.location_in_file.line,
+                column: definition.define.location_in_file.column,
+            });
+        }
+
+        // Convenience variables are defined by their most recent assignment.
+        if let Some(variable) = variable_name(usage.words[usage.cursor_index]) {
+            return self.find_assignment_in(
+                cursor_position.file,
+                variable,
+                Some(cursor_position.line),
+                &mut HashSet::new(),
+            );
+        }
+
+        // Find most recent definition of that command before the requested position.
+        let definition = self.find_definition_in(
+            cursor_position.file,
+            &usage,
+            Some(cursor_position.line),
+            &mut HashSet::new(),
+        )?;
+
+        Some(CursorPosition {
+            file: definition.file,
+            line: definition.identifier[0].location_in_file.line,
+            column: definition.identifier[0].location_in_file.column,
+        })
+    }
+
+    /// Returns the full definition of the user defined command at the given
+    /// position, or of the command enclosing an argument such as `$arg0`. This
+    /// is the same definition `find_definition` returns the position of.
+    pub fn find_command_definition(
+        &self,
+        cursor_position: CursorPosition,
+    ) -> Option<CommandDefinition<'_>> {
+        let cursor_position = self.decode_position(cursor_position);
+        let usage = self.usage_at(cursor_position)?;
+
+        let definition = if is_argument_reference(usage.words[usage.cursor_index]) {
+            self.enclosing_definition(cursor_position)?
+        } else {
+            self.find_definition_in(
+                cursor_position.file,
+                &usage,
+                Some(cursor_position.line),
+                &mut HashSet::new(),
+            )?
+        };
 
-        self.files.insert(path, text);
+        Some(self.command_definition(&definition))
+    }
+
+    /// Finds the range of each usage and definition of the command at the given
+    /// position, across all known files.
+    pub fn find_references(&self, cursor_position: CursorPosition) -> Vec<CursorRange<'_>> {
+        let cursor_position = self.decode_position(cursor_position);
+        let name = match self.referenced_name(cursor_position) {
+            Some(name) => name,
+            None => return vec![],
+        };
+
+        self.find_reference_ranges(&name)
+            .into_iter()
+            .map(|range| self.encode_range(range))
+            .collect()
+    }
+
+    /// Finds the usages and definitions of the command at the given position
+    /// within the same file, for highlighting them. Unlike `find_references`,
+    /// each is marked as a definition or a usage.
+    pub fn document_highlights(
+        &self,
+        cursor_position: CursorPosition,
+    ) -> Vec<DocumentHighlight<'_>> {
+        let cursor_position = self.decode_position(cursor_position);
+        let (file_path, file) = match self.files.get_key_value(cursor_position.file) {
+            Some(file) => file,
+            None => return vec![],
+        };
+        let name = match self.referenced_name(cursor_position) {
+            Some(name) => name,
+            None => return vec![],
+        };
+
+        let mut locations = vec![];
+        find_references_in(file.commands(), &name, &mut locations);
+        locations.sort_by_key(|(start, _, _)| (start.line, start.column));
+
+        locations
+            .into_iter()
+            .map(|(start, end, kind)| DocumentHighlight {
+                range: self.encode_range(CursorRange::new(file_path, start, end)),
+                kind,
+            })
+            .collect()
+    }
+
+    /// Returns the ranges which should be edited together with the command name at
+    /// the given position. When the cursor is on the name of a `define`, or on a
+    /// call the command makes to itself within its body, these are the name in
+    /// the `define` and each such recursive call.
+    ///
+    /// Returns `None` if the cursor is on neither, or if the command never calls
+    /// itself, since a single range has nothing to be linked to.
+    pub fn linked_editing_ranges(
+        &self,
+        cursor_position: CursorPosition,
+    ) -> Option<Vec<CursorRange<'_>>> {
+        let cursor_position = self.decode_position(cursor_position);
+        let definition = self.enclosing_definition(cursor_position)?;
+        let identifier = definition.identifier;
+        let name = identifier
+            .iter()
+            .map(|token| token.text)
+            .collect::<Vec<&str>>();
+
+        let name_start = identifier[0].location_in_file;
+        let name_end = identifier[identifier.len() - 1].end_location();
+        // Linked ranges must have the same text, so a call to a multi word
+        // command which is spaced differently to the `define`, or continued onto
+        // another line, is left out.
+        let width = |start: &Location, end: &Location| {
+            if start.line == end.line {
+                Some(end.column - start.column)
+            } else {
+                None
+            }
+        };
+        let name_width = width(&name_start, &name_end)?;
+        let mut locations = vec![(name_start, name_end)];
+        let mut calls = vec![];
+        find_references_in(definition.body, &name, &mut calls);
+        locations.extend(
+            calls
+                .into_iter()
+                .filter(|(start, end, kind)| {
+                    *kind == HighlightKind::Read && width(start, end) == Some(name_width)
+                })
+                .map(|(start, end, _)| (start, end)),
+        );
+
+        let location = Location::from(cursor_position);
+        let on_linked_range = locations
+            .iter()
+            .any(|(start, end)| *start <= location && location <= *end);
+        if !on_linked_range || locations.len() < 2 {
+            return None;
+        }
+
+        Some(
+            locations
+                .into_iter()
+                .map(|(start, end)| {
+                    self.encode_range(CursorRange::new(definition.file, start, end))
+                })
+                .collect(),
+        )
+    }
+
+    /// Returns the name of the command referred to at the given position. If the
+    /// command is defined this is its full name, otherwise we only know about the
+    /// words up to the cursor.
+    fn referenced_name(&self, cursor_position: CursorPosition) -> Option<Vec<&str>> {
+        let usage = self.usage_at(cursor_position)?;
+        let name = match self.find_definition_in(
+            cursor_position.file,
+            &usage,
+            None,
+            &mut HashSet::new(),
+        ) {
+            Some(definition) => definition
+                .identifier
+                .iter()
+                .map(|token| token.text)
+                .collect(),
+            None => usage.words[..=usage.cursor_index].to_vec(),
+        };
+
+        Some(name)
+    }
+
+    /// Returns the range of the user defined command name at the given position,
+    /// which is the text that `rename` would replace.
+    ///
+    /// Returns `None` if the cursor is not on the name of a user defined command,
+    /// for example when it is on a built in command or on an argument.
+    pub fn prepare_rename(&self, cursor_position: CursorPosition) -> Option<CursorRange<'_>> {
+        let cursor_position = self.decode_position(cursor_position);
+        let usage = self.usage_at(cursor_position)?;
+        let definition =
+            self.find_definition_in(cursor_position.file, &usage, None, &mut HashSet::new())?;
+        let name = definition
+            .identifier
+            .iter()
+            .map(|token| token.text)
+            .collect::<Vec<&str>>();
+        let cursor = Location::from(cursor_position);
+
+        self.find_reference_ranges(&name)
+            .into_iter()
+            .find(|range| {
+                range.start.file == cursor_position.file
+                    && Location::from(range.start) <= cursor
+                    && cursor < Location::from(range.end)
+            })
+            .map(|range| self.encode_range(range))
+    }
+
+    /// Renames the user defined command at the given position, returning the
+    /// edits to make in each file. Both the definitions and the usages of the
+    /// command are renamed.
+    ///
+    /// Returns `None` if the cursor is not on a user defined command, or if the
+    /// new name is not a valid command name.
+    pub fn rename(
+        &self,
+        cursor_position: CursorPosition,
+        new_name: &str,
+    ) -> Option<WorkspaceEdit<'_>> {
+        let cursor_position = self.decode_position(cursor_position);
+        if !is_valid_command_name(new_name) {
+            return None;
+        }
+
+        let usage = self.usage_at(cursor_position)?;
+        let definition =
+            self.find_definition_in(cursor_position.file, &usage, None, &mut HashSet::new())?;
+        let name = definition
+            .identifier
+            .iter()
+            .map(|token| token.text)
+            .collect::<Vec<&str>>();
 
-        unresolved_paths
+        let mut changes: HashMap<&Path, Vec<TextEdit>> = HashMap::new();
+        for range in self.find_reference_ranges(&name) {
+            changes.entry(range.start.file).or_default().push(TextEdit {
+                range: self.encode_range(range),
+                new_text: new_name.to_owned(),
+            });
+        }
+
+        Some(WorkspaceEdit { changes })
     }
 
-    // TODO
-    // this should return full CommandDefine struct, so we could impl
-    // hover using it
-    pub fn find_definition(&self, cursor_position: CursorPosition) -> Option<CursorPosition> {
-        let script = self.files.get(cursor_position.file)?;
+    /// Returns hover information for the command at the given position.
+    ///
+    /// For user defined commands this is the body of the most recent definition,
+    /// and for built-in commands it is a short description.
+    pub fn hover(&self, cursor_position: CursorPosition) -> Option<Hover> {
+        let cursor_position = self.decode_position(cursor_position);
+        let usage = self.usage_at(cursor_position)?;
+
+        let word = usage.words[usage.cursor_index];
+        if is_argument_reference(word) {
+            let definition = self.enclosing_definition(cursor_position)?;
+            let name = command_name(definition.identifier);
+            let contents = match &word["$arg".len()..] {
+                "c" => format!("The number of arguments passed to `{}`", name),
+                index => format!("Argument {} passed to `{}`", index, name),
+            };
+            return Some(Hover { contents });
+        }
+
+        let definition = match self.find_definition_in(
+            cursor_position.file,
+            &usage,
+            Some(cursor_position.line),
+            &mut HashSet::new(),
+        ) {
+            Some(definition) => definition,
+            None => {
+                return built_ins::description(usage.words[usage.cursor_index]).map(|description| {
+                    Hover {
+                        contents: description.to_owned(),
+                    }
+                })
+            }
+        };
+
+        let body = self.definition_body(&definition);
 
-        // Find the token at the requested position.
-        let line = parse::iters::lines(script)
-            .find(|line| line.start_line_in_file == cursor_position.line)?;
-        let token =
-            parse::iters::tokens(&line).find(|token| token.is_at_location(cursor_position))?;
-        let identifier = token.text;
+        let mut contents = String::new();
+        if let Some(documentation) = self.find_documentation(&definition) {
+            contents.push_str(&documentation);
+            contents.push_str("\n\n");
+        }
+        contents.push_str(&format!("```gdb\n{}\n```", body));
+        if definition.file != cursor_position.file {
+            contents.push_str(&format!("\n\nDefined in `{}`", definition.file.display()));
+        }
 
-        // Find most recent definition of that token before the requested position.
-        self.find_definition_in(cursor_position.file, identifier, Some(cursor_position.line))
+        Some(Hover { contents })
     }
 
     pub fn find_completions(&self, cursor_position: CursorPosition) -> Completions {
-        let script = match self.files.get(cursor_position.file) {
-            Some(script) => script,
+        let cursor_position = self.decode_position(cursor_position);
+        let file = match self.files.get(cursor_position.file) {
+            Some(file) => file,
             None => return Completions::default(),
         };
-        let completion_position = match CompletionPosition::new(script, cursor_position.into()) {
-            Some(completion_position) => completion_position,
-            None => return Completions::default(),
+        let completion_position =
+            match CompletionPosition::new(file.lines(), cursor_position.into()) {
+                Some(completion_position) => completion_position,
+                None => return Completions::default(),
+            };
+
+        // Only candidates which could complete what has been typed so far are
+        // returned, once each, in alphabetical order. Path completions are
+        // filtered by the caller, which lists the files.
+        let typed = completion_position.typed();
+        let is_command = matches!(completion_position, CompletionPosition::Command { .. });
+        let mut completions = self.find_completion_candidates(cursor_position, completion_position);
+        let filter_and_sort = |completions: &mut Vec<Completion>| {
+            completions.retain(|completion| completion.text.starts_with(typed));
+            completions.sort_by(|a, b| a.text.cmp(&b.text));
+            completions.dedup_by(|a, b| a.text == b.text);
         };
+        filter_and_sort(&mut completions.built_in);
+        filter_and_sort(&mut completions.user_provided);
+
+        // Within an open block, closing it is the most likely command.
+        if is_command && open_block_depth(file.commands(), cursor_position.line) > 0 {
+            if let Some(index) = completions
+                .built_in
+                .iter()
+                .position(|completion| completion.text == "end")
+            {
+                completions.built_in[..=index].rotate_right(1);
+            }
+        }
+
+        completions
+    }
+
+    /// Returns every completion which fits the given position, regardless of
+    /// what has been typed there so far.
+    fn find_completion_candidates(
+        &self,
+        cursor_position: CursorPosition,
+        completion_position: CompletionPosition,
+    ) -> Completions {
+        if completion_position.typed().starts_with('$') {
+            return self.find_variable_completions();
+        }
 
         match completion_position {
-            CompletionPosition::Command => {
+            CompletionPosition::Command { .. } => {
                 let built_in = ["define", "if", "else", "end"]
                     .iter()
-                    .map(|&command| Completion {
-                        text: command.to_owned(),
-                    })
+                    .map(|&command| command.to_owned())
+                    .chain(self.extra_commands.iter().cloned())
+                    .map(|text| Completion { text })
                     .collect();
                 let user_provided = self
                     .find_all_user_defined_commands(
                         cursor_position.file,
                         Some(cursor_position.line),
+                        &mut HashSet::new(),
                     )
-                    .iter()
-                    .map(|&command| Completion {
-                        text: command.to_owned(),
-                    })
+                    .into_iter()
+                    .map(|command| Completion { text: command })
                     .collect();
 
                 Completions {
                     built_in,
                     user_provided,
+                    path: None,
+                }
+            }
+            CompletionPosition::Arg(arg) if arg.command == "set" || arg.command == "show" => {
+                Completions {
+                    built_in: completions::settings::complete(
+                        &arg.leading_args,
+                        arg.command == "set",
+                    )
+                    .into_iter()
+                    .map(|text| Completion {
+                        text: text.to_owned(),
+                    })
+                    .collect(),
+                    user_provided: vec![],
+                    path: None,
+                }
+            }
+            CompletionPosition::Arg(arg) if arg.command == "source" => {
+                // Only the path is completed, which follows any flags.
+                if arg
+                    .leading_args
+                    .iter()
+                    .any(|&arg| arg != "-s" && arg != "-v")
+                {
+                    return Completions::default();
+                }
+
+                let typed = arg.typed;
+                let (directory, file_name_prefix) = match typed.rfind('/') {
+                    Some(index) => (
+                        self.canonicalize_path(&typed[..=index]),
+                        &typed[index + 1..],
+                    ),
+                    None => (self.project_root.clone(), typed),
+                };
+
+                Completions {
+                    path: Some(PathCompletion {
+                        directory,
+                        file_name_prefix: file_name_prefix.to_owned(),
+                    }),
+                    ..Completions::default()
+                }
+            }
+            // A condition is an expression, so an operand is suggested first, and
+            // after it an operator joining it to another.
+            CompletionPosition::Arg(arg) if arg.command == "if" || arg.command == "while" => {
+                let operators = completions::variables::CONDITION_OPERATORS;
+                match arg.leading_args.last() {
+                    Some(last) if !operators.contains(last) => Completions {
+                        built_in: operators
+                            .iter()
+                            .map(|&operator| Completion {
+                                text: operator.to_owned(),
+                            })
+                            .collect(),
+                        ..Completions::default()
+                    },
+                    _ => self.find_variable_completions(),
+                }
+            }
+            // Function and file names aren't known, so the only thing to suggest
+            // for a breakpoint is the `if` of a condition, once it has a location.
+            CompletionPosition::Arg(arg)
+                if matches!(
+                    built_ins::resolve_builtin(arg.command),
+                    Some("break" | "tbreak")
+                ) =>
+            {
+                if arg.leading_args.is_empty() || arg.leading_args.contains(&"if") {
+                    return Completions::default();
+                }
+
+                Completions {
+                    built_in: vec![Completion {
+                        text: "if".to_owned(),
+                    }],
+                    ..Completions::default()
+                }
+            }
+            // After a prefix command, the next word of each user defined subcommand
+            // under it is suggested.
+            CompletionPosition::Arg(arg) => {
+                let typed_words = std::iter::once(arg.command)
+                    .chain(arg.leading_args)
+                    .collect::<Vec<&str>>();
+                let mut user_provided = self
+                    .find_all_user_defined_commands(
+                        cursor_position.file,
+                        Some(cursor_position.line),
+                        &mut HashSet::new(),
+                    )
+                    .iter()
+                    .filter_map(|name| {
+                        let words = name.split_whitespace().collect::<Vec<&str>>();
+                        if words.len() > typed_words.len() && words.starts_with(&typed_words) {
+                            Some(words[typed_words.len()].to_owned())
+                        } else {
+                            None
+                        }
+                    })
+                    .collect::<Vec<String>>();
+                user_provided.sort_unstable();
+                user_provided.dedup();
+
+                Completions {
+                    user_provided: user_provided
+                        .into_iter()
+                        .map(|text| Completion { text })
+                        .collect(),
+                    ..Completions::default()
                 }
             }
-            // TODO handle completions in arg position, including user defined variables
-            CompletionPosition::Arg(_) => Completions::default(),
         }
     }
 
-    /// Find the definition of the given identifier in the given script, including
-    /// traversing `source` imports.
+    /// Returns the documentation of the command with the given name, as seen from
+    /// the given file. This is the help text of a user defined command's
+    /// `document` block, or the description of a built-in command.
+    pub fn command_documentation(&self, file: &Path, name: &str) -> Option<String> {
+        let usage = CommandUsage {
+            words: name.split_whitespace().collect(),
+            cursor_index: 0,
+        };
+        match self.find_definition_in(file, &usage, None, &mut HashSet::new()) {
+            Some(definition) => self.find_documentation(&definition),
+            None => built_ins::description(name).map(str::to_owned),
+        }
+    }
+
+    /// Returns completions for convenience variables, including those assigned in
+    /// any known file.
+    fn find_variable_completions(&self) -> Completions {
+        let built_in = completions::variables::BUILT_IN_VARIABLES
+            .iter()
+            .map(|&variable| Completion {
+                text: variable.to_owned(),
+            })
+            .collect();
+
+        let mut user_provided = vec![];
+        for file in self.files.values() {
+            completions::variables::collect_user_defined(file.commands(), &mut user_provided);
+        }
+        user_provided.sort_unstable();
+        user_provided.dedup();
+
+        Completions {
+            built_in,
+            user_provided: user_provided
+                .into_iter()
+                .map(|variable| Completion {
+                    text: variable.to_owned(),
+                })
+                .collect(),
+            path: None,
+        }
+    }
+
+    /// Returns the range of every usage or definition of the given command name,
+    /// across all known files.
+    fn find_reference_ranges(&self, name: &[&str]) -> Vec<CursorRange<'_>> {
+        let mut references = vec![];
+        for (file_path, file) in &self.files {
+            let mut locations = vec![];
+            find_references_in(file.commands(), name, &mut locations);
+            references.extend(
+                locations
+                    .into_iter()
+                    .map(|(start, end, _)| CursorRange::new(file_path, start, end)),
+            );
+        }
+        references.sort_by_key(|reference| {
+            (
+                reference.start.file,
+                reference.start.line,
+                reference.start.column,
+            )
+        });
+
+        references
+    }
+
+    /// Returns help for the arguments of the user defined command being called at
+    /// the given position. The arguments a command takes are inferred from the
+    /// `$argN` variables referenced in its body, and a reference to `$argc` is
+    /// taken to mean it accepts a variable number of arguments.
     ///
-    /// If a line limit is given, the definition must happen above the given line. This
-    /// is useful to ensure the definition isn't below the usage.
-    fn find_definition_in(
-        &self,
-        script_path: &Path,
-        identifier: &str,
-        line_limit: Option<usize>,
-    ) -> Option<CursorPosition> {
-        let (file_path, script) = self.files.get_key_value(script_path)?;
-        parse(script)
+    /// Returns `None` if the cursor is not after the name of a user defined command.
+    pub fn signature_help(&self, cursor_position: CursorPosition) -> Option<SignatureHelp> {
+        let cursor_position = self.decode_position(cursor_position);
+        let file = self.files.get(cursor_position.file)?;
+        let line = parse::iters::line_at(file.lines(), cursor_position.line)?;
+        let tokens = parse::iters::tokens(&line).collect::<Vec<Token>>();
+        let usage = CommandUsage {
+            words: tokens.iter().map(|token| token.text).collect(),
+            cursor_index: 0,
+        };
+        let definition = self.find_definition_in(
+            cursor_position.file,
+            &usage,
+            Some(cursor_position.line),
+            &mut HashSet::new(),
+        )?;
+
+        let tokens_before_cursor = tokens
+            .iter()
+            .take_while(|token| token.end_location() < cursor_position.into())
+            .count();
+        let active_argument = tokens_before_cursor.checked_sub(definition.identifier.len())?;
+
+        let mut highest_argument = None;
+        let mut uses_argc = false;
+        collect_argument_usage(definition.body, &mut highest_argument, &mut uses_argc);
+
+        let parameters = highest_argument.map_or(vec![], |highest_argument| {
+            (0..=highest_argument)
+                .map(|index| format!("arg{}", index))
+                .collect::<Vec<String>>()
+        });
+        let mut label = command_name(definition.identifier);
+        for parameter in &parameters {
+            label.push(' ');
+            label.push_str(parameter);
+        }
+        if uses_argc {
+            label.push_str(" ...");
+        }
+
+        Some(SignatureHelp {
+            label,
+            active_parameter: if active_argument < parameters.len() {
+                Some(active_argument)
+            } else {
+                None
+            },
+            parameters,
+        })
+    }
+
+    /// Returns the symbols defined in the given file. A define nested within
+    /// another define is returned as one of its children, while `if` and `while`
+    /// blocks don't create symbols of their own.
+    pub fn document_symbols<'a>(&'a self, file: &Path) -> Vec<Symbol<'a>> {
+        let (file_path, file) = match self.files.get_key_value(file) {
+            Some(file) => file,
+            None => return vec![],
+        };
+
+        let mut symbols = vec![];
+        collect_symbols(file.commands(), file_path, &mut symbols);
+
+        symbols
             .into_iter()
-            .rev()
-            .find_map(|command| match command {
-                Command::Define {
-                    define: define_command,
-                    identifier: Some(defined_identifier),
-                    ..
-                } => {
-                    if defined_identifier.text == identifier {
-                        if let Some(line_limit) = line_limit {
-                            if define_command.location_in_file.line >= line_limit {
-                                return None;
-                            }
-                        }
-                        Some(CursorPosition {
-                            file: file_path,
-                            line: defined_identifier.location_in_file.line,
-                            column: defined_identifier.location_in_file.column,
-                        })
-                    } else {
-                        None
-                    }
-                }
-                Command::Source {
-                    file_path: Some(file_path),
-                    ..
-                } => {
-                    let path = self.canonicalize_path(PathBuf::from(file_path.text));
-                    self.find_definition_in(&path, identifier, None)
+            .map(|symbol| self.encode_symbol(symbol))
+            .collect()
+    }
+
+    /// Returns the symbols, across all known files, whose name contains the given
+    /// query. Matching ignores case, and an empty query matches every symbol.
+    ///
+    /// The returned symbols are flattened, so each has no children.
+    pub fn workspace_symbols(&self, query: &str) -> Vec<Symbol<'_>> {
+        let query = query.to_lowercase();
+
+        let mut symbols = vec![];
+        for (file_path, file) in &self.files {
+            let mut file_symbols = vec![];
+            collect_symbols(file.commands(), file_path, &mut file_symbols);
+
+            while let Some(mut symbol) = file_symbols.pop() {
+                file_symbols.append(&mut symbol.children);
+                if symbol.name.to_lowercase().contains(&query) {
+                    symbols.push(self.encode_symbol(symbol));
                 }
-                _ => None,
-            })
+            }
+        }
+        symbols.sort_by_key(|symbol| {
+            (
+                symbol.name_range.start.file,
+                symbol.name_range.start.line,
+                symbol.name_range.start.column,
+            )
+        });
+
+        symbols
     }
 
-    fn find_all_user_defined_commands(
-        &self,
-        script_path: &Path,
-        line_limit: Option<usize>,
-    ) -> Vec<&str> {
-        let script = match self.files.get(script_path) {
-            Some(script) => script,
+    /// Returns the commands defined at the top level of the given file, in the
+    /// order they are defined. Unlike `document_symbols`, defines nested within
+    /// other blocks are not included, and sourced files are not followed.
+    pub fn definitions_in<'a>(&'a self, file: &Path) -> Vec<CommandDefinition<'a>> {
+        let (file_path, file) = match self.files.get_key_value(file) {
+            Some(file) => file,
             None => return vec![],
         };
 
-        parse(script)
-            .into_iter()
-            .rev()
+        file.commands()
+            .iter()
             .filter_map(|command| match command {
                 Command::Define {
-                    define: define_command,
-                    identifier: Some(defined_identifier),
-                    ..
-                } => {
-                    if let Some(line_limit) = line_limit {
-                        if define_command.location_in_file.line >= line_limit {
-                            return None;
-                        }
-                    }
-                    Some(defined_identifier.text)
-                }
-                Command::Source {
-                    file_path: Some(file_path),
+                    define,
+                    identifier,
+                    body,
+                    end,
                     ..
-                } => {
-                    let path = self.canonicalize_path(PathBuf::from(file_path.text));
-                    // self.find_definition_in(&path, identifier, None)
-                    None
-                }
+                } if !identifier.is_empty() => Some(self.command_definition(&Definition {
+                    file: file_path,
+                    define,
+                    identifier,
+                    body,
+                    end,
+                })),
                 _ => None,
             })
             .collect()
     }
 
-    fn canonicalize_path(&self, path: PathBuf) -> PathBuf {
-        if path.is_relative() {
-            self.project_root.join(path)
-        } else {
+    /// Returns the commands in the given file as a tree, in the order they are
+    /// written. The commands in the body of a block, including both branches of
+    /// an `if`, are returned as its children, and comments are returned alongside
+    /// the commands they are written between. Comments within the text of a
+    /// `document` or embedded block are part of that command, so are left out.
+    pub fn command_tree<'a>(&'a self, file: &Path) -> Vec<CommandNode<'a>> {
+        let (file_path, file) = match self.files.get_key_value(file) {
+            Some(file) => file,
+            None => return vec![],
+        };
+
+        let mut comments = parse::iters::lines(file.text())
+            .filter_map(|line| parse::iters::comment(&line))
+            .peekable();
+        let mut nodes = vec![];
+        collect_command_nodes(
+            file.commands(),
+            file_path,
+            file.text(),
+            &mut comments,
+            &mut nodes,
+        );
+        collect_comments_before(None, file_path, &mut comments, &mut nodes);
+
+        nodes
+            .into_iter()
+            .map(|node| self.encode_command_node(node))
+            .collect()
+    }
+
+    /// Returns the ranges of lines which can be folded in the given file, one for
+    /// each block of commands.
+    pub fn folding_ranges(&self, file: &Path) -> Vec<FoldingRange> {
+        let file = match self.files.get(file) {
+            Some(file) => file,
+            None => return vec![],
+        };
+
+        let mut folding_ranges = vec![];
+        collect_folding_ranges(file.commands(), &mut folding_ranges);
+
+        folding_ranges
+    }
+
+    /// Returns the tokens in the given file which can be highlighted, ordered by
+    /// position. Tokens which don't have a `SemanticTokenType` are left out.
+    pub fn semantic_tokens<'a>(&'a self, file: &Path) -> Vec<SemanticToken<'a>> {
+        let (file_path, file) = match self.files.get_key_value(file) {
+            Some(file) => file,
+            None => return vec![],
+        };
+
+        let user_defined =
+            self.find_all_user_defined_commands(file_path, None, &mut HashSet::new());
+        let user_defined = user_defined
+            .iter()
+            .map(|name| name.split_whitespace().collect())
+            .collect::<Vec<Vec<&str>>>();
+
+        let mut tokens = vec![];
+        collect_semantic_tokens(file.commands(), &user_defined, &mut tokens);
+        let mut unparsed_lines = vec![];
+        collect_unparsed_lines(file.commands(), &mut unparsed_lines);
+        tokens.extend(
+            parse::iters::lines(file.text())
+                .filter(|line| {
+                    !unparsed_lines
+                        .iter()
+                        .any(|lines| lines.contains(&line.start_line_in_file))
+                })
+                .filter_map(|line| parse::iters::comment(&line))
+                .map(|comment| (comment, SemanticTokenType::Comment)),
+        );
+        tokens
+            .sort_by_key(|(token, _)| (token.location_in_file.line, token.location_in_file.column));
+        // The `end` of an `else if` chain is shared by each `if` in the chain.
+        tokens.dedup_by_key(|(token, _)| token.location_in_file);
+
+        tokens
+            .into_iter()
+            .map(|(token, token_type)| SemanticToken {
+                range: self.encode_range(CursorRange::new(
+                    file_path,
+                    token.location_in_file,
+                    token.end_location(),
+                )),
+                token_type,
+            })
+            .collect()
+    }
+
+    /// Returns the problems found in the given file.
+    pub fn diagnostics<'a>(&'a self, file: &Path) -> Vec<Diagnostic<'a>> {
+        let (file_path, file) = match self.files.get_key_value(file) {
+            Some(file) => file,
+            None => return vec![],
+        };
+
+        let commands = file.commands();
+        let mut diagnostics = vec![];
+        diagnostics::syntax_errors(commands, None, &mut diagnostics);
+        diagnostics::duplicate_definitions(commands, &mut diagnostics);
+        diagnostics::shadowed_built_ins(commands, &mut diagnostics);
+        let user_defined =
+            self.find_all_user_defined_commands(file_path, None, &mut HashSet::new());
+        diagnostics::undefined_commands(
+            commands,
+            &user_defined
+                .iter()
+                .chain(&self.extra_commands)
+                .filter_map(|name| name.split_whitespace().next())
+                .collect(),
+            &mut diagnostics,
+        );
+        self.circular_source_errors(file_path, commands, &mut diagnostics);
+        for (_, source, file_path) in self.unresolved_sources(file_path, commands) {
+            diagnostics.push(diagnostics::RawDiagnostic {
+                severity: Severity::Warning,
+                message: format!("cannot find `{}`", file_path.text),
+                start: source.location_in_file,
+                end: file_path.end_location(),
+                related: vec![],
+            });
+        }
+
+        diagnostics
+            .into_iter()
+            .map(|diagnostic| Diagnostic {
+                severity: diagnostic.severity,
+                message: diagnostic.message,
+                range: self.encode_range(CursorRange::new(
+                    file_path,
+                    diagnostic.start,
+                    diagnostic.end,
+                )),
+                related: diagnostic
+                    .related
+                    .into_iter()
+                    .map(|related| RelatedLocation {
+                        message: related.message,
+                        range: self.encode_range(CursorRange::new(
+                            file_path,
+                            related.start,
+                            related.end,
+                        )),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Returns the fixes which can be made to problems on the lines of the given
+    /// range. For each block missing its `end`, this is an edit which adds one
+    /// after the last line of the block, indented to match its start. For each
+    /// `define` indented with tabs, this is an edit which replaces each tab with
+    /// spaces, as many as the configured tab width.
+    pub fn code_actions<'a>(&'a self, range: CursorRange) -> Vec<CodeAction<'a>> {
+        let start = self.decode_position(range.start);
+        let end = self.decode_position(range.end);
+        let (file_path, file) = match self.files.get_key_value(start.file) {
+            Some(file) => file,
+            None => return vec![],
+        };
+
+        let lines = file.text().lines().collect::<Vec<&str>>();
+        // The lines of a block run from its first token through its `end`, or
+        // through its last nested command if it has no `end`.
+        let block_lines = |block: &Command| block.start_location().line..=block.end_location().line;
+        let in_range = |block: &&Command| {
+            let lines = block_lines(block);
+            *lines.start() <= end.line && start.line <= *lines.end()
+        };
+        let code_action = |title: &str, edits: Vec<TextEdit<'a>>| {
+            let mut changes = HashMap::new();
+            changes.insert(file_path.as_path(), edits);
+            CodeAction {
+                title: title.to_owned(),
+                edit: WorkspaceEdit { changes },
+            }
+        };
+
+        let mut unterminated_blocks = vec![];
+        collect_unterminated_blocks(file.commands(), &mut unterminated_blocks);
+        let mut code_actions = unterminated_blocks
+            .into_iter()
+            .filter(in_range)
+            .map(|block| {
+                let lines_of_block = block_lines(block);
+                let first_line = lines.get(*lines_of_block.start()).copied().unwrap_or("");
+                let end_of_last_line = Location {
+                    line: *lines_of_block.end(),
+                    column: lines
+                        .get(*lines_of_block.end())
+                        .copied()
+                        .map_or(0, parse::utf16_len),
+                };
+                let edit = TextEdit {
+                    range: self.encode_range(CursorRange::new(
+                        file_path,
+                        end_of_last_line,
+                        end_of_last_line,
+                    )),
+                    new_text: format!("\n{}end", indentation(first_line)),
+                };
+                code_action("Insert `end`", vec![edit])
+            })
+            .collect::<Vec<CodeAction>>();
+
+        let spaces = " ".repeat(self.tab_width);
+        code_actions.extend(
+            file.commands()
+                .iter()
+                .filter(|command| matches!(command, Command::Define { .. }))
+                .filter(in_range)
+                .filter_map(|define| {
+                    let edits = block_lines(define)
+                        .filter_map(|line| {
+                            let indentation = indentation(lines.get(line)?);
+                            if !indentation.contains('\t') {
+                                return None;
+                            }
+
+                            Some(TextEdit {
+                                range: self.encode_range(CursorRange::new(
+                                    file_path,
+                                    Location { line, column: 0 },
+                                    Location {
+                                        line,
+                                        column: indentation.len(),
+                                    },
+                                )),
+                                new_text: indentation.replace('\t', &spaces),
+                            })
+                        })
+                        .collect::<Vec<TextEdit>>();
+                    if edits.is_empty() {
+                        return None;
+                    }
+
+                    Some(code_action("Convert tabs to spaces", edits))
+                }),
+        );
+
+        code_actions
+    }
+
+    /// Returns the hints to show on the lines of the given range. After the path
+    /// of each `source` command this is the path it resolves to, since relative
+    /// paths are resolved against the sourcing file rather than the project root.
+    pub fn inlay_hints<'a>(&'a self, range: CursorRange) -> Vec<InlayHint<'a>> {
+        let start = self.decode_position(range.start);
+        let end = self.decode_position(range.end);
+        let (file_path, file) = match self.files.get_key_value(start.file) {
+            Some(file) => file,
+            None => return vec![],
+        };
+
+        file.commands()
+            .iter()
+            .filter_map(|command| match command {
+                Command::Source {
+                    file_path: Some(source_path),
+                    ..
+                } if (start.line..=end.line).contains(&source_path.location_in_file.line) => {
+                    let resolved = self.resolve_source_path(source_path.text, file_path);
+                    Some(InlayHint {
+                        position: self.encode_position(CursorPosition {
+                            file: file_path,
+                            line: source_path.location_in_file.line,
+                            column: source_path.end_location().column,
+                        }),
+                        label: resolved.display().to_string(),
+                    })
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Find the definition of the given identifier in the given script, including
+    /// traversing `source` imports.
+    ///
+    /// The definition found is the one GDB would have most recently run, with each
+    /// sourced file taking effect where its `source` command is. So a `define`
+    /// replaces a definition from a file sourced above it, and is itself replaced
+    /// by a definition from a file sourced below it.
+    ///
+    /// If a line limit is given, the definition must happen above the given line,
+    /// either directly or in a file sourced above it. This is useful to ensure the
+    /// definition isn't below the usage.
+    ///
+    /// Each file is searched at most once, so circular imports do not cause infinite
+    /// recursion. Searching from the end, the first time a file is reached is
+    /// where it was most recently sourced.
+    fn find_definition_in<'a>(
+        &'a self,
+        script_path: &Path,
+        usage: &CommandUsage,
+        line_limit: Option<usize>,
+        visited: &mut HashSet<&'a Path>,
+    ) -> Option<Definition<'a>> {
+        let (file_path, file) = self.files.get_key_value(script_path)?;
+        if !visited.insert(file_path) {
+            return None;
+        }
+        file.commands()
+            .iter()
+            .rev()
+            .find_map(|command| match command {
+                Command::Define {
+                    define,
+                    identifier,
+                    body,
+                    end,
+                    ..
+                } => {
+                    if usage.refers_to(identifier) {
+                        if let Some(line_limit) = line_limit {
+                            if define.location_in_file.line >= line_limit {
+                                return None;
+                            }
+                        }
+                        Some(Definition {
+                            file: file_path,
+                            define,
+                            identifier,
+                            body,
+                            end,
+                        })
+                    } else {
+                        None
+                    }
+                }
+                Command::Source {
+                    source,
+                    file_path: Some(file_path),
+                    ..
+                } if self.follow_sources => {
+                    if let Some(line_limit) = line_limit {
+                        if source.location_in_file.line >= line_limit {
+                            return None;
+                        }
+                    }
+                    let path = self.resolve_source_path(file_path.text, script_path);
+                    self.find_definition_in(&path, usage, None, visited)
+                }
+                _ => None,
+            })
+    }
+
+    fn command_definition<'a>(&'a self, definition: &Definition<'a>) -> CommandDefinition<'a> {
+        let identifier = definition.identifier;
+
+        CommandDefinition {
+            name: command_name(identifier),
+            name_range: self.encode_range(CursorRange::new(
+                definition.file,
+                identifier[0].location_in_file,
+                identifier[identifier.len() - 1].end_location(),
+            )),
+            range: self.encode_range(CursorRange::new(
+                definition.file,
+                definition.define.location_in_file,
+                definition.end_location(),
+            )),
+            body_range: match (definition.body.first(), definition.body.last()) {
+                (Some(first), Some(last)) => Some(self.encode_range(CursorRange::new(
+                    definition.file,
+                    first.start_location(),
+                    last.end_location(),
+                ))),
+                _ => None,
+            },
+            body: self.definition_body(definition),
+        }
+    }
+
+    /// Returns the lines between the `define` and `end` of the given definition,
+    /// as written.
+    fn definition_body(&self, definition: &Definition) -> String {
+        let body_start_line = definition.define.location_in_file.line + 1;
+        let body_end_line = match (definition.end, definition.body.last()) {
+            (Some(end), _) => end.location_in_file.line,
+            (None, Some(last_command)) => last_command.end_location().line + 1,
+            (None, None) => body_start_line,
+        };
+
+        self.files[definition.file]
+            .text()
+            .lines()
+            .skip(body_start_line)
+            .take(body_end_line.saturating_sub(body_start_line))
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+
+    /// Returns the help text from the most recent `document` block for the given
+    /// definition, in the file where it is defined.
+    fn find_documentation(&self, definition: &Definition) -> Option<String> {
+        let file = self.files.get(definition.file)?;
+
+        file.commands()
+            .iter()
+            .rev()
+            .find_map(|command| match command {
+                Command::Document {
+                    identifier, body, ..
+                } if identifier
+                    .iter()
+                    .map(|token| token.text)
+                    .eq(definition.identifier.iter().map(|token| token.text)) =>
+                {
+                    Some(
+                        body.iter()
+                            .map(|line| line.text().trim())
+                            .collect::<Vec<&str>>()
+                            .join("\n"),
+                    )
+                }
+                _ => None,
+            })
+    }
+
+    /// Returns the resolved path, along with the `source` and file path tokens, of
+    /// each top level `source` command in the given commands whose file is not
+    /// known.
+    fn unresolved_sources<'a>(
+        &self,
+        script_path: &Path,
+        commands: &'a [Command<'a>],
+    ) -> Vec<(PathBuf, &'a Token<'a>, &'a Token<'a>)> {
+        if !self.follow_sources {
+            return vec![];
+        }
+
+        commands
+            .iter()
+            .filter_map(|command| match command {
+                Command::Source {
+                    source,
+                    file_path: Some(file_path),
+                    ..
+                } => {
+                    let path = self.resolve_source_path(file_path.text, script_path);
+                    if self.files.contains_key(&path) {
+                        None
+                    } else {
+                        Some((path, source, file_path))
+                    }
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Collects an error for each `source` command which (directly or transitively)
+    /// sources the file it is in.
+    fn circular_source_errors(
+        &self,
+        script_path: &Path,
+        commands: &[Command],
+        diagnostics: &mut Vec<diagnostics::RawDiagnostic>,
+    ) {
+        if !self.follow_sources {
+            return;
+        }
+
+        for command in commands {
+            if let Command::Source {
+                source,
+                file_path: Some(file_path),
+                ..
+            } = command
+            {
+                let path = self.resolve_source_path(file_path.text, script_path);
+                if self.sources_file(&path, script_path, &mut HashSet::new()) {
+                    diagnostics.push(diagnostics::RawDiagnostic {
+                        severity: Severity::Error,
+                        message: format!("circular `source` of `{}`", file_path.text),
+                        start: source.location_in_file,
+                        end: file_path.end_location(),
+                        related: vec![],
+                    });
+                }
+            }
+        }
+    }
+
+    /// Returns true if the given script is the target file, or sources the target
+    /// file through any chain of `source` commands.
+    fn sources_file(
+        &self,
+        script_path: &Path,
+        target: &Path,
+        visited: &mut HashSet<PathBuf>,
+    ) -> bool {
+        if script_path == target {
+            return true;
+        }
+        if !visited.insert(script_path.to_owned()) {
+            return false;
+        }
+        let file = match self.files.get(script_path) {
+            Some(file) => file,
+            None => return false,
+        };
+
+        file.commands().iter().any(|command| match command {
+            Command::Source {
+                file_path: Some(file_path),
+                ..
+            } => {
+                let path = self.resolve_source_path(file_path.text, script_path);
+                self.sources_file(&path, target, visited)
+            }
+            _ => false,
+        })
+    }
+
+    /// Find the most recent assignment to the given convenience variable in the
+    /// given script, including traversing `source` imports. This mirrors
+    /// `find_definition_in`, but for `set $name = ...` rather than `define`.
+    fn find_assignment_in<'a>(
+        &'a self,
+        script_path: &Path,
+        name: &str,
+        line_limit: Option<usize>,
+        visited: &mut HashSet<&'a Path>,
+    ) -> Option<CursorPosition<'a>> {
+        let (file_path, file) = self.files.get_key_value(script_path)?;
+        if !visited.insert(file_path) {
+            return None;
+        }
+        file.commands()
+            .iter()
+            .rev()
+            .find_map(|command| match command {
+                Command::Source {
+                    source,
+                    file_path: Some(file_path),
+                    ..
+                } if self.follow_sources => {
+                    if let Some(line_limit) = line_limit {
+                        if source.location_in_file.line >= line_limit {
+                            return None;
+                        }
+                    }
+                    let path = self.resolve_source_path(file_path.text, script_path);
+                    self.find_assignment_in(&path, name, None, visited)
+                }
+                command => last_assignment_in(std::slice::from_ref(command), name, line_limit).map(
+                    |location| CursorPosition {
+                        file: file_path,
+                        line: location.line,
+                        column: location.column,
+                    },
+                ),
+            })
+    }
+
+    /// Finds the names of all user defined commands in the given script, including
+    /// those defined in `source`d files. Each name is included once, and each file
+    /// is only searched the first time it is reached.
+    ///
+    /// If a line limit is given, only commands defined above the given line are
+    /// included.
+    fn find_all_user_defined_commands<'a>(
+        &'a self,
+        script_path: &Path,
+        line_limit: Option<usize>,
+        visited: &mut HashSet<&'a Path>,
+    ) -> Vec<String> {
+        let (file_path, file) = match self.files.get_key_value(script_path) {
+            Some(file) => file,
+            None => return vec![],
+        };
+        if !visited.insert(file_path) {
+            return vec![];
+        }
+
+        let mut commands = vec![];
+        for command in file.commands().iter().rev() {
+            match command {
+                Command::Define {
+                    define: define_command,
+                    identifier,
+                    ..
+                }
+                | Command::DefinePrefix {
+                    define_prefix: define_command,
+                    identifier,
+                } if !identifier.is_empty() => {
+                    if let Some(line_limit) = line_limit {
+                        if define_command.location_in_file.line >= line_limit {
+                            continue;
+                        }
+                    }
+                    commands.push(command_name(identifier));
+                }
+                Command::Source {
+                    source,
+                    file_path: Some(file_path),
+                    ..
+                } if self.follow_sources => {
+                    if let Some(line_limit) = line_limit {
+                        if source.location_in_file.line >= line_limit {
+                            continue;
+                        }
+                    }
+                    let path = self.resolve_source_path(file_path.text, script_path);
+                    commands.extend(self.find_all_user_defined_commands(&path, None, visited));
+                }
+                _ => {}
+            }
+        }
+        // A command redefined in this file, or in another sourced file, is only
+        // listed once, where it is most recently defined.
+        let mut seen = HashSet::new();
+        commands.retain(|command| seen.insert(command.clone()));
+
+        commands
+    }
+
+    /// Returns the innermost `define` whose body contains the given position.
+    fn enclosing_definition(&self, cursor_position: CursorPosition) -> Option<Definition<'_>> {
+        let (file_path, file) = self.files.get_key_value(cursor_position.file)?;
+
+        enclosing_definition_in(file.commands(), file_path, cursor_position.into())
+    }
+
+    /// Returns the token at the given position, if there is one.
+    pub fn token_at(&self, cursor_position: CursorPosition) -> Option<TokenInfo<'_>> {
+        let cursor_position = self.decode_position(cursor_position);
+        let (file_path, file) = self.files.get_key_value(cursor_position.file)?;
+
+        let (tokens, index) = tokens_at(file, cursor_position.into())?;
+        let token = &tokens[index];
+        Some(TokenInfo {
+            text: token.text,
+            range: self.encode_range(CursorRange::new(
+                file_path,
+                token.location_in_file,
+                token.end_location(),
+            )),
+            kind: if index == 0 {
+                TokenKind::Command
+            } else {
+                TokenKind::Argument
+            },
+        })
+    }
+
+    /// Returns every token in the given file in order. This is lower level than
+    /// `semantic_tokens`, for callers which only need to tell the words of a
+    /// script apart. Lines which aren't GDB commands, such as the help text of a
+    /// `document` block, have no tokens.
+    pub fn tokens<'a>(&'a self, file: &Path) -> Vec<TokenInfo<'a>> {
+        let (file_path, file) = match self.files.get_key_value(file) {
+            Some(file) => file,
+            None => return vec![],
+        };
+
+        let mut unparsed_lines = vec![];
+        collect_unparsed_lines(file.commands(), &mut unparsed_lines);
+        let token_info = |token: Token<'a>, kind| TokenInfo {
+            text: token.text,
+            range: self.encode_range(CursorRange::new(
+                file_path,
+                token.location_in_file,
+                token.end_location(),
+            )),
+            kind,
+        };
+
+        let mut tokens = vec![];
+        for line in parse::iters::lines(file.text()) {
+            if unparsed_lines
+                .iter()
+                .any(|lines| lines.contains(&line.start_line_in_file))
+            {
+                continue;
+            }
+
+            for statement in parse::iters::statements(&line) {
+                for (index, token) in statement.into_iter().enumerate() {
+                    let kind = if index == 0 {
+                        TokenKind::Command
+                    } else {
+                        TokenKind::Argument
+                    };
+                    tokens.push(token_info(token, kind));
+                }
+            }
+            if let Some(comment) = parse::iters::comment(&line) {
+                tokens.push(token_info(comment, TokenKind::Comment));
+            }
+        }
+
+        tokens
+    }
+
+    /// Returns the command line at the given position, if the cursor is on one of
+    /// its tokens.
+    fn usage_at(&self, cursor_position: CursorPosition) -> Option<CommandUsage<'_>> {
+        let file = self.files.get(cursor_position.file)?;
+
+        let (mut tokens, mut cursor_index) = tokens_at(file, cursor_position.into())?;
+        // On a `define` line the command being referred to is the one being
+        // defined.
+        if tokens[0].text == "define" {
+            tokens.remove(0);
+            cursor_index = cursor_index.checked_sub(1)?;
+        }
+
+        Some(CommandUsage {
+            words: tokens.into_iter().map(|token| token.text).collect(),
+            cursor_index,
+        })
+    }
+
+    /// Converts a position from the configured encoding to the UTF-16 columns used
+    /// internally.
+    fn decode_position<'b>(&self, position: CursorPosition<'b>) -> CursorPosition<'b> {
+        match self.position_encoding {
+            PositionEncoding::Utf16 => position,
+            PositionEncoding::Utf8 => {
+                let line = self.line_text(position.file, position.line);
+                let byte_offset = std::cmp::min(position.column, line.len());
+                let column = line
+                    .char_indices()
+                    .take_while(|(index, _)| *index < byte_offset)
+                    .map(|(_, character)| character.len_utf16())
+                    .sum();
+
+                CursorPosition { column, ..position }
+            }
+        }
+    }
+
+    /// Converts a position from the UTF-16 columns used internally to the
+    /// configured encoding.
+    fn encode_position<'b>(&self, position: CursorPosition<'b>) -> CursorPosition<'b> {
+        match self.position_encoding {
+            PositionEncoding::Utf16 => position,
+            PositionEncoding::Utf8 => CursorPosition {
+                column: parse::byte_offset(
+                    self.line_text(position.file, position.line),
+                    position.column,
+                ),
+                ..position
+            },
+        }
+    }
+
+    fn encode_range<'b>(&self, range: CursorRange<'b>) -> CursorRange<'b> {
+        CursorRange {
+            start: self.encode_position(range.start),
+            end: self.encode_position(range.end),
+        }
+    }
+
+    fn encode_symbol<'b>(&self, symbol: Symbol<'b>) -> Symbol<'b> {
+        Symbol {
+            name_range: self.encode_range(symbol.name_range),
+            range: self.encode_range(symbol.range),
+            children: symbol
+                .children
+                .into_iter()
+                .map(|child| self.encode_symbol(child))
+                .collect(),
+            ..symbol
+        }
+    }
+
+    fn encode_command_node<'b>(&self, node: CommandNode<'b>) -> CommandNode<'b> {
+        CommandNode {
+            range: self.encode_range(node.range),
+            children: node
+                .children
+                .into_iter()
+                .map(|child| self.encode_command_node(child))
+                .collect(),
+            ..node
+        }
+    }
+
+    /// Returns the text of the given line, or an empty string if the file or line
+    /// is not known.
+    fn line_text(&self, file: &Path, line: usize) -> &str {
+        self.files
+            .get(file)
+            .and_then(|file| file.line_text(line))
+            .unwrap_or("")
+    }
+
+    /// Converts a path as written in a script to an absolute path. A leading `~`
+    /// is expanded to the home directory, `$VAR` and `${VAR}` are expanded from
+    /// the environment, and relative paths are joined to the project root.
+    ///
+    /// If the path refers to an environment variable which isn't set, it is
+    /// returned unchanged.
+    fn canonicalize_path(&self, path: &str) -> PathBuf {
+        let path = match self.expand_path(path) {
+            Some(path) => PathBuf::from(path),
+            None => return PathBuf::from(path),
+        };
+
+        if path.is_relative() {
+            self.project_root.join(path)
+        } else {
             path
         }
     }
-}
 
-type UnresolvedPaths = Vec<PathBuf>;
+    /// Resolves the path given to a `source` command in the given file. GDB looks
+    /// for a relative path in the directory of the sourcing file, so that is
+    /// preferred, but the path relative to the project root is still used if
+    /// only that file is loaded.
+    fn resolve_source_path(&self, path: &str, sourcing_file: &Path) -> PathBuf {
+        let project_relative = self.canonicalize_path(path);
+        let file_relative = match (self.expand_path(path), sourcing_file.parent()) {
+            (Some(expanded), Some(directory)) if Path::new(&expanded).is_relative() => {
+                directory.join(expanded)
+            }
+            _ => return project_relative,
+        };
+
+        if !self.files.contains_key(&file_relative) && self.files.contains_key(&project_relative) {
+            project_relative
+        } else {
+            file_relative
+        }
+    }
+
+    /// Returns how the given path should be written in a `source` command in the
+    /// given file, so that `resolve_source_path` resolves it to that path.
+    fn source_path_text(path: &Path, sourcing_file: &Path) -> String {
+        sourcing_file
+            .parent()
+            .and_then(|directory| path.strip_prefix(directory).ok())
+            .unwrap_or(path)
+            .display()
+            .to_string()
+    }
+
+    fn expand_path(&self, path: &str) -> Option<String> {
+        let mut expanded = String::new();
+
+        let mut rest = path;
+        if rest == "~" || rest.starts_with("~/") {
+            expanded.push_str(self.environment.get("HOME")?);
+            rest = &rest[1..];
+        }
+
+        while let Some(index) = rest.find('$') {
+            expanded.push_str(&rest[..index]);
+            rest = &rest[index + 1..];
+
+            let (name, remainder) = match rest.strip_prefix('{') {
+                Some(braced) => {
+                    let end = braced.find('}')?;
+                    (&braced[..end], &braced[end + 1..])
+                }
+                None => {
+                    let end = rest
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(rest.len());
+                    (&rest[..end], &rest[end..])
+                }
+            };
+            if name.is_empty() {
+                // A `$` which doesn't start a variable name is kept as is.
+                expanded.push('$');
+                continue;
+            }
+
+            expanded.push_str(self.environment.get(name)?);
+            rest = remainder;
+        }
+        expanded.push_str(rest);
+
+        Some(expanded)
+    }
+}
+
+/// Configures and creates a `Semantics`.
+pub struct SemanticsBuilder {
+    project_root: PathBuf,
+    position_encoding: PositionEncoding,
+    follow_sources: bool,
+    tab_width: usize,
+    extra_commands: Vec<String>,
+}
+
+impl SemanticsBuilder {
+    pub fn new(project_root: PathBuf) -> Self {
+        Self {
+            project_root,
+            position_encoding: PositionEncoding::Utf16,
+            follow_sources: true,
+            tab_width: 4,
+            extra_commands: vec![],
+        }
+    }
+
+    /// Sets the unit in which columns are measured, for positions passed to and
+    /// returned from `Semantics`. Defaults to UTF-16, which matches LSP.
+    pub fn position_encoding(mut self, position_encoding: PositionEncoding) -> Self {
+        self.position_encoding = position_encoding;
+        self
+    }
+
+    /// Sets whether `source` commands are followed into other files, for example
+    /// when looking for definitions. Defaults to true.
+    pub fn follow_sources(mut self, follow_sources: bool) -> Self {
+        self.follow_sources = follow_sources;
+        self
+    }
+
+    /// Sets the number of spaces each tab is replaced with when converting the
+    /// indentation of a block to spaces. Defaults to 4.
+    pub fn tab_width(mut self, tab_width: usize) -> Self {
+        self.tab_width = tab_width;
+        self
+    }
+
+    /// Sets the names of commands which GDB knows in addition to its built-in
+    /// commands, such as those added by a distribution or a Python plugin. These
+    /// are completed alongside the built-in commands, and using them isn't
+    /// reported as an unknown command. Defaults to none.
+    pub fn extra_commands(mut self, extra_commands: Vec<String>) -> Self {
+        self.extra_commands = extra_commands;
+        self
+    }
+
+    pub fn build(self) -> Semantics {
+        Semantics {
+            project_root: self.project_root,
+            files: HashMap::new(),
+            environment: std::env::vars().collect(),
+            position_encoding: self.position_encoding,
+            follow_sources: self.follow_sources,
+            tab_width: self.tab_width,
+            extra_commands: self.extra_commands,
+        }
+    }
+}
+
+/// The unit in which the column of a position is measured.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PositionEncoding {
+    /// Bytes of UTF-8.
+    Utf8,
+    /// UTF-16 code units.
+    Utf16,
+}
+
+impl PositionEncoding {
+    /// The width of the given character in the units of this encoding.
+    pub fn char_width(self, character: char) -> usize {
+        match self {
+            PositionEncoding::Utf8 => character.len_utf8(),
+            PositionEncoding::Utf16 => character.len_utf16(),
+        }
+    }
+}
+
+/// Returns the byte offset into the given text of the given line and column,
+/// with the column measured in the given encoding. This is for applying edits
+/// given as positions to the text of a file. Positions past the end of a line
+/// or of the text map to its end.
+pub fn offset_of(
+    text: &str,
+    line: usize,
+    column: usize,
+    position_encoding: PositionEncoding,
+) -> usize {
+    parse::offset_of(text, Location { line, column }, position_encoding)
+}
+
+/// A user defined command, as resolved by `Semantics::find_definition_in`.
+struct Definition<'a> {
+    file: &'a Path,
+    define: &'a Token<'a>,
+    /// This is never empty.
+    identifier: &'a [Token<'a>],
+    body: &'a [Command<'a>],
+    end: &'a Option<Token<'a>>,
+}
+
+impl Definition<'_> {
+    /// The location just past the `end`, or the last part of the definition if
+    /// it has no `end`.
+    fn end_location(&self) -> Location {
+        self.end
+            .as_ref()
+            .map(Token::end_location)
+            .or_else(|| self.body.last().map(Command::end_location))
+            .unwrap_or_else(|| self.identifier[self.identifier.len() - 1].end_location())
+    }
+}
+
+/// The words of a command line, as seen from a cursor position within it.
+struct CommandUsage<'a> {
+    words: Vec<&'a str>,
+    /// The index into `words` of the token at the cursor.
+    cursor_index: usize,
+}
+
+impl<'a> CommandUsage<'a> {
+    /// Returns true if this command line starts with the given command name, and
+    /// the cursor is on part of that name.
+    fn refers_to(&self, name: &[Token]) -> bool {
+        !name.is_empty()
+            && self.cursor_index < name.len()
+            && self.words.len() >= name.len()
+            && name
+                .iter()
+                .zip(&self.words)
+                .all(|(token, &word)| token.text == word)
+    }
+}
+
+fn enclosing_definition_in<'a>(
+    commands: &'a [Command<'a>],
+    file: &'a Path,
+    location: Location,
+) -> Option<Definition<'a>> {
+    commands.iter().find_map(|command| {
+        let end_location = command.end_location();
+        match command {
+            Command::Define {
+                define,
+                identifier,
+                body,
+                end,
+                ..
+            } if !identifier.is_empty() => {
+                let contains_location =
+                    define.location_in_file <= location && location <= end_location;
+                if !contains_location {
+                    return None;
+                }
+                // A nested define is checked first, since it is the innermost.
+                enclosing_definition_in(body, file, location).or(Some(Definition {
+                    file,
+                    define,
+                    identifier,
+                    body,
+                    end,
+                }))
+            }
+            Command::If {
+                body, else_body, ..
+            } => enclosing_definition_in(body, file, location)
+                .or_else(|| enclosing_definition_in(else_body, file, location)),
+            Command::While { body, .. } | Command::Commands { body, .. } => {
+                enclosing_definition_in(body, file, location)
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Returns the tokens of the statement at the given location, along with the
+/// index of the token the location is on. A command line may hold several
+/// statements separated by `;`, and only the one under the cursor is returned.
+fn tokens_at(file: &ParsedFile, location: Location) -> Option<(Vec<Token<'_>>, usize)> {
+    let line = parse::iters::line_at(file.lines(), location.line)?;
+    parse::iters::statements(&line)
+        .into_iter()
+        .find_map(|statement| {
+            let index = statement
+                .iter()
+                .position(|token| token.is_at_location(location))?;
+            Some((statement, index))
+        })
+}
+
+/// Returns the index of the file path within the words of a `source` command,
+/// which follows any flags. This matches how the command is parsed.
+fn source_path_index(words: &[&str]) -> Option<usize> {
+    if words.first() != Some(&"source") {
+        return None;
+    }
+
+    let flags = words[1..]
+        .iter()
+        .take_while(|word| **word == "-s" || **word == "-v")
+        .count();
+    Some(1 + flags).filter(|index| *index < words.len())
+}
+
+/// Returns true for the `$arg0`..`$argN` and `$argc` variables which GDB provides
+/// within user defined commands.
+fn is_argument_reference(word: &str) -> bool {
+    match word.strip_prefix("$arg") {
+        Some("c") => true,
+        Some(index) => !index.is_empty() && index.chars().all(|c| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+/// Finds the highest `$argN` referenced in the given commands, and whether `$argc`
+/// is referenced. The arguments may appear within larger expressions, such as
+/// `$arg0+1`. Nested defines are skipped, since they have arguments of their own.
+fn collect_argument_usage(
+    commands: &[Command],
+    highest_argument: &mut Option<usize>,
+    uses_argc: &mut bool,
+) {
+    for command in commands {
+        let tokens = match command {
+            Command::If {
+                condition,
+                body,
+                else_body,
+                ..
+            } => {
+                collect_argument_usage(body, highest_argument, uses_argc);
+                collect_argument_usage(else_body, highest_argument, uses_argc);
+                condition.iter().collect::<Vec<&Token>>()
+            }
+            Command::While {
+                condition, body, ..
+            } => {
+                collect_argument_usage(body, highest_argument, uses_argc);
+                condition.iter().collect::<Vec<&Token>>()
+            }
+            Command::Commands {
+                breakpoint_spec,
+                body,
+                ..
+            } => {
+                collect_argument_usage(body, highest_argument, uses_argc);
+                breakpoint_spec.iter().collect::<Vec<&Token>>()
+            }
+            Command::Other { command, args } => std::iter::once(command).chain(args).collect(),
+            Command::SetVar { value, .. } => value.iter().collect(),
+            Command::Define { .. }
+            | Command::DefinePrefix { .. }
+            | Command::Document { .. }
+            | Command::Embedded { .. }
+            | Command::Shell { .. }
+            | Command::Source { .. } => continue,
+        };
+
+        for token in tokens {
+            for (index, _) in token.text.match_indices("$arg") {
+                let rest = &token.text[index + "$arg".len()..];
+                if rest.starts_with('c') {
+                    *uses_argc = true;
+                    continue;
+                }
+                let digits = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .map_or(rest, |end| &rest[..end]);
+                if let Ok(argument) = digits.parse::<usize>() {
+                    *highest_argument = Some(
+                        highest_argument
+                            .map_or(argument, |highest| std::cmp::max(highest, argument)),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Returns the convenience variable at the start of the given word, for example
+/// `$counter` from `$counter+1`.
+fn variable_name(word: &str) -> Option<&str> {
+    let name = word.strip_prefix('$')?;
+    let end = name
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(name.len());
+
+    if end == 0 {
+        None
+    } else {
+        Some(&word[..end + 1])
+    }
+}
+
+/// Finds the location of the last assignment to the given convenience variable in
+/// the given commands, including nested commands. If a line limit is given, the
+/// assignment must be above that line.
+fn last_assignment_in(
+    commands: &[Command],
+    name: &str,
+    line_limit: Option<usize>,
+) -> Option<Location> {
+    commands.iter().rev().find_map(|command| match command {
+        Command::Define { body, .. }
+        | Command::While { body, .. }
+        | Command::Commands { body, .. } => last_assignment_in(body, name, line_limit),
+        Command::If {
+            body, else_body, ..
+        } => last_assignment_in(else_body, name, line_limit)
+            .or_else(|| last_assignment_in(body, name, line_limit)),
+        Command::SetVar { name: variable, .. }
+            if variable.text == name
+                && line_limit
+                    .is_none_or(|line_limit| variable.location_in_file.line < line_limit) =>
+        {
+            Some(variable.location_in_file)
+        }
+        _ => None,
+    })
+}
+
+/// Joins the tokens of a (possibly multi-word) command name.
+fn command_name(identifier: &[Token]) -> String {
+    identifier
+        .iter()
+        .map(|token| token.text)
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Returns true if the given name can be used for a user defined command. GDB
+/// command names are made up of letters, digits, `-`, `_` and `.`, and
+/// subcommand names are several such words separated by whitespace.
+fn is_valid_command_name(name: &str) -> bool {
+    let mut words = name.split_whitespace().peekable();
+
+    words.peek().is_some()
+        && words.all(|word| {
+            word.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        })
+}
+
+/// Collects the range of each reference to the given command name in the given
+/// commands, including nested commands. Definitions are `Write` references, and
+/// usages, including in `document`, are `Read` references.
+fn find_references_in(
+    commands: &[Command],
+    name: &[&str],
+    locations: &mut Vec<(Location, Location, HighlightKind)>,
+) {
+    for command in commands {
+        match command {
+            Command::Define {
+                identifier, body, ..
+            } => {
+                if identifier
+                    .iter()
+                    .map(|token| token.text)
+                    .eq(name.iter().copied())
+                {
+                    locations.push((
+                        identifier[0].location_in_file,
+                        identifier[identifier.len() - 1].end_location(),
+                        HighlightKind::Write,
+                    ));
+                }
+                find_references_in(body, name, locations);
+            }
+            Command::DefinePrefix { identifier, .. } | Command::Document { identifier, .. } => {
+                if identifier
+                    .iter()
+                    .map(|token| token.text)
+                    .eq(name.iter().copied())
+                {
+                    let kind = match command {
+                        Command::DefinePrefix { .. } => HighlightKind::Write,
+                        _ => HighlightKind::Read,
+                    };
+                    locations.push((
+                        identifier[0].location_in_file,
+                        identifier[identifier.len() - 1].end_location(),
+                        kind,
+                    ));
+                }
+            }
+            Command::If {
+                body, else_body, ..
+            } => {
+                find_references_in(body, name, locations);
+                find_references_in(else_body, name, locations);
+            }
+            Command::While { body, .. } | Command::Commands { body, .. } => {
+                find_references_in(body, name, locations)
+            }
+            Command::Other { command, args } => {
+                let words = std::iter::once(command).chain(args);
+                if words
+                    .clone()
+                    .take(name.len())
+                    .map(|token| token.text)
+                    .eq(name.iter().copied())
+                {
+                    // The name is not empty, so this is always the last token of the
+                    // name.
+                    if let Some(last) = words.take(name.len()).last() {
+                        locations.push((
+                            command.location_in_file,
+                            last.end_location(),
+                            HighlightKind::Read,
+                        ));
+                    }
+                }
+            }
+            Command::Embedded { .. }
+            | Command::SetVar { .. }
+            | Command::Shell { .. }
+            | Command::Source { .. } => {}
+        }
+    }
+}
+
+fn collect_symbols<'a>(commands: &[Command<'a>], file: &'a Path, symbols: &mut Vec<Symbol<'a>>) {
+    for command in commands {
+        match command {
+            Command::Define {
+                define,
+                identifier,
+                body,
+                ..
+            } => {
+                let mut children = vec![];
+                collect_symbols(body, file, &mut children);
+
+                if identifier.is_empty() {
+                    symbols.extend(children);
+                } else {
+                    symbols.push(Symbol {
+                        name: command_name(identifier),
+                        kind: SymbolKind::Function,
+                        name_range: CursorRange::new(
+                            file,
+                            identifier[0].location_in_file,
+                            identifier[identifier.len() - 1].end_location(),
+                        ),
+                        range: CursorRange::new(
+                            file,
+                            define.location_in_file,
+                            command.end_location(),
+                        ),
+                        children,
+                    });
+                }
+            }
+            Command::If {
+                body, else_body, ..
+            } => {
+                collect_symbols(body, file, symbols);
+                collect_symbols(else_body, file, symbols);
+            }
+            Command::While { body, .. } | Command::Commands { body, .. } => {
+                collect_symbols(body, file, symbols)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Converts the given commands to `CommandNode`s, including nested commands.
+/// Each comment which comes before the end of a command is taken from `comments`
+/// and placed before it, or within it for a block, so `comments` must be ordered
+/// by position.
+fn collect_command_nodes<'a>(
+    commands: &[Command<'a>],
+    file: &'a Path,
+    source: &str,
+    comments: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    nodes: &mut Vec<CommandNode<'a>>,
+) {
+    for command in commands {
+        collect_comments_before(Some(command.start_location()), file, comments, nodes);
+
+        let mut children = vec![];
+        let (kind, first) = match command {
+            Command::Define { define, body, .. } => {
+                collect_command_nodes(body, file, source, comments, &mut children);
+                (CommandKind::Define, define)
+            }
+            Command::If {
+                r#if,
+                body,
+                else_body,
+                ..
+            } => {
+                collect_command_nodes(body, file, source, comments, &mut children);
+                collect_command_nodes(else_body, file, source, comments, &mut children);
+                (CommandKind::If, r#if)
+            }
+            Command::While { r#while, body, .. } => {
+                collect_command_nodes(body, file, source, comments, &mut children);
+                (CommandKind::While, r#while)
+            }
+            Command::Commands { commands, body, .. } => {
+                collect_command_nodes(body, file, source, comments, &mut children);
+                (CommandKind::Other, commands)
+            }
+            Command::Source { source, .. } => (CommandKind::Source, source),
+            Command::DefinePrefix {
+                define_prefix: first,
+                ..
+            }
+            | Command::Document {
+                document: first, ..
+            }
+            | Command::Embedded {
+                language: first, ..
+            }
+            | Command::SetVar { set: first, .. }
+            | Command::Shell { shell: first, .. }
+            | Command::Other { command: first, .. } => (CommandKind::Other, first),
+        };
+
+        let end = match command {
+            // An embedded block without an `end` runs to the end of the file.
+            Command::Embedded { end: None, .. } => None,
+            _ => Some(command.end_location()),
+        };
+        match command {
+            Command::Define { .. }
+            | Command::If { .. }
+            | Command::While { .. }
+            | Command::Commands { .. } => {
+                collect_comments_before(end, file, comments, &mut children)
+            }
+            // Any other comments before the end are part of the command's text.
+            _ => collect_comments_before(end, file, comments, &mut vec![]),
+        }
+
+        nodes.push(CommandNode {
+            kind,
+            name: first.text.to_owned(),
+            range: CursorRange::new(file, command.start_location(), command.end_location()),
+            text: command.raw_text(source).to_owned(),
+            children,
+        });
+    }
+}
+
+/// Takes each comment which starts before the given location, or every comment
+/// if there is no location, from `comments` and adds it to `nodes`.
+fn collect_comments_before<'a>(
+    location: Option<Location>,
+    file: &'a Path,
+    comments: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    nodes: &mut Vec<CommandNode<'a>>,
+) {
+    while let Some(comment) = comments
+        .next_if(|comment| location.is_none_or(|location| comment.location_in_file < location))
+    {
+        nodes.push(CommandNode {
+            kind: CommandKind::Comment,
+            name: comment.text.to_owned(),
+            range: CursorRange::new(file, comment.location_in_file, comment.end_location()),
+            text: comment.text.to_owned(),
+            children: vec![],
+        });
+    }
+}
+
+/// Returns the whitespace at the start of the given line.
+fn indentation(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// Collects each `define`, `if`, `while` and `commands` block which is missing
+/// its `end`, including nested blocks, outermost first.
+fn collect_unterminated_blocks<'c, 'a>(
+    commands: &'c [Command<'a>],
+    blocks: &mut Vec<&'c Command<'a>>,
+) {
+    for command in commands {
+        let (body, else_body, end) = match command {
+            Command::Define { body, end, .. }
+            | Command::While { body, end, .. }
+            | Command::Commands { body, end, .. } => (&body[..], &[][..], end),
+            Command::If {
+                body,
+                else_body,
+                end,
+                ..
+            } => (&body[..], &else_body[..], end),
+            _ => continue,
+        };
+
+        if end.is_none() {
+            blocks.push(command);
+        }
+        collect_unterminated_blocks(body, blocks);
+        collect_unterminated_blocks(else_body, blocks);
+    }
+}
+
+/// Collects the ranges of lines, such as the help text of a `document` block or
+/// the code of a `python` block, which are not parsed as GDB commands.
+fn collect_unparsed_lines(commands: &[Command], unparsed_lines: &mut Vec<Range<usize>>) {
+    for command in commands {
+        match command {
+            Command::Document { body, .. } => {
+                if let (Some(first), Some(last)) = (body.first(), body.last()) {
+                    unparsed_lines.push(first.start_line_in_file..last.end_line() + 1);
+                }
+            }
+            // An embedded block without an `end` runs to the end of the file.
+            Command::Embedded { language, end, .. } => unparsed_lines.push(
+                language.location_in_file.line + 1
+                    ..end
+                        .as_ref()
+                        .map_or(usize::MAX, |end| end.location_in_file.line),
+            ),
+            Command::Define { body, .. }
+            | Command::While { body, .. }
+            | Command::Commands { body, .. } => collect_unparsed_lines(body, unparsed_lines),
+            Command::If {
+                body, else_body, ..
+            } => {
+                collect_unparsed_lines(body, unparsed_lines);
+                collect_unparsed_lines(else_body, unparsed_lines);
+            }
+            Command::DefinePrefix { .. }
+            | Command::SetVar { .. }
+            | Command::Shell { .. }
+            | Command::Source { .. }
+            | Command::Other { .. } => {}
+        }
+    }
+}
+
+/// Returns the number of blocks closed by an `end` which are open at the given
+/// line. A block is open from the line after it starts through the line of its
+/// `end`, or to the end of the file if it has none.
+fn open_block_depth(commands: &[Command], line: usize) -> usize {
+    for command in commands {
+        let (start, body, else_body, end) = match command {
+            Command::Define {
+                define, body, end, ..
+            } => (define, &body[..], &[][..], end),
+            Command::If {
+                r#if,
+                body,
+                else_body,
+                end,
+                ..
+            } => (r#if, &body[..], &else_body[..], end),
+            Command::While {
+                r#while, body, end, ..
+            } => (r#while, &body[..], &[][..], end),
+            Command::Commands {
+                commands,
+                body,
+                end,
+                ..
+            } => (commands, &body[..], &[][..], end),
+            _ => continue,
+        };
+
+        if start.location_in_file.line < line
+            && end
+                .as_ref()
+                .is_none_or(|end| line <= end.location_in_file.line)
+        {
+            return 1 + open_block_depth(body, line) + open_block_depth(else_body, line);
+        }
+    }
+
+    0
+}
+
+fn collect_folding_ranges(commands: &[Command], folding_ranges: &mut Vec<FoldingRange>) {
+    for command in commands {
+        let (start, body, else_body) = match command {
+            Command::Define { define, body, .. } => (define, &body[..], &[][..]),
+            Command::Embedded { language, .. } => (language, &[][..], &[][..]),
+            Command::If {
+                r#if,
+                body,
+                else_body,
+                ..
+            } => (r#if, &body[..], &else_body[..]),
+            Command::While { r#while, body, .. } => (r#while, &body[..], &[][..]),
+            Command::Commands { commands, body, .. } => (commands, &body[..], &[][..]),
+            Command::DefinePrefix { .. }
+            | Command::Document { .. }
+            | Command::SetVar { .. }
+            | Command::Shell { .. }
+            | Command::Source { .. }
+            | Command::Other { .. } => continue,
+        };
+
+        let start_line = start.location_in_file.line;
+        let end_line = command.end_location().line;
+        if end_line > start_line {
+            folding_ranges.push(FoldingRange {
+                start_line,
+                end_line,
+            });
+        }
+        collect_folding_ranges(body, folding_ranges);
+        collect_folding_ranges(else_body, folding_ranges);
+    }
+}
+
+/// Classifies the tokens of the given commands for highlighting, including nested
+/// commands.
+fn collect_semantic_tokens<'a>(
+    commands: &[Command<'a>],
+    user_defined: &[Vec<&str>],
+    tokens: &mut Vec<(Token<'a>, SemanticTokenType)>,
+) {
+    for command in commands {
+        match command {
+            Command::Define {
+                define,
+                identifier,
+                body,
+                end,
+                ..
+            } => {
+                tokens.push((define.clone(), SemanticTokenType::Keyword));
+                tokens.extend(
+                    identifier
+                        .iter()
+                        .map(|token| (token.clone(), SemanticTokenType::Function)),
+                );
+                collect_semantic_tokens(body, user_defined, tokens);
+                tokens.extend(
+                    end.iter()
+                        .map(|end| (end.clone(), SemanticTokenType::Keyword)),
+                );
+            }
+            Command::DefinePrefix {
+                define_prefix,
+                identifier,
+            } => {
+                tokens.push((define_prefix.clone(), SemanticTokenType::Keyword));
+                tokens.extend(
+                    identifier
+                        .iter()
+                        .map(|token| (token.clone(), SemanticTokenType::Function)),
+                );
+            }
+            Command::Document {
+                document,
+                identifier,
+                end,
+                ..
+            } => {
+                tokens.push((document.clone(), SemanticTokenType::Keyword));
+                tokens.extend(
+                    identifier
+                        .iter()
+                        .map(|token| (token.clone(), SemanticTokenType::Function)),
+                );
+                tokens.extend(
+                    end.iter()
+                        .map(|end| (end.clone(), SemanticTokenType::Keyword)),
+                );
+            }
+            Command::Embedded { language, end, .. } => {
+                tokens.push((language.clone(), SemanticTokenType::Keyword));
+                tokens.extend(
+                    end.iter()
+                        .map(|end| (end.clone(), SemanticTokenType::Keyword)),
+                );
+            }
+            Command::If {
+                r#if,
+                condition,
+                body,
+                r#else,
+                else_body,
+                end,
+            } => {
+                tokens.push((r#if.clone(), SemanticTokenType::Keyword));
+                tokens.extend(condition.iter().filter_map(argument_semantic_token));
+                collect_semantic_tokens(body, user_defined, tokens);
+                tokens.extend(
+                    r#else
+                        .iter()
+                        .map(|r#else| (r#else.clone(), SemanticTokenType::Keyword)),
+                );
+                collect_semantic_tokens(else_body, user_defined, tokens);
+                tokens.extend(
+                    end.iter()
+                        .map(|end| (end.clone(), SemanticTokenType::Keyword)),
+                );
+            }
+            Command::While {
+                r#while,
+                condition,
+                body,
+                end,
+            } => {
+                tokens.push((r#while.clone(), SemanticTokenType::Keyword));
+                tokens.extend(condition.iter().filter_map(argument_semantic_token));
+                collect_semantic_tokens(body, user_defined, tokens);
+                tokens.extend(
+                    end.iter()
+                        .map(|end| (end.clone(), SemanticTokenType::Keyword)),
+                );
+            }
+            Command::Commands {
+                commands,
+                breakpoint_spec,
+                body,
+                end,
+            } => {
+                tokens.push((commands.clone(), SemanticTokenType::Keyword));
+                tokens.extend(breakpoint_spec.iter().filter_map(argument_semantic_token));
+                collect_semantic_tokens(body, user_defined, tokens);
+                tokens.extend(
+                    end.iter()
+                        .map(|end| (end.clone(), SemanticTokenType::Keyword)),
+                );
+            }
+            Command::Source { source, .. } => {
+                tokens.push((source.clone(), SemanticTokenType::Keyword));
+            }
+            Command::SetVar { name, value, .. } => {
+                tokens.push((name.clone(), SemanticTokenType::Variable));
+                tokens.extend(value.iter().filter_map(argument_semantic_token));
+            }
+            // The shell command itself is left unhighlighted, since it isn't GDB
+            // syntax.
+            Command::Shell { shell, .. } => {
+                tokens.push((shell.clone(), SemanticTokenType::Keyword));
+            }
+            Command::Other { command, args } => {
+                if command.text == "else" {
+                    tokens.push((command.clone(), SemanticTokenType::Keyword));
+                    continue;
+                }
+
+                // The longest user defined command name which this command line
+                // starts with, since the name may be several words.
+                let words = std::iter::once(command)
+                    .chain(args)
+                    .collect::<Vec<&Token>>();
+                let name_len = user_defined
+                    .iter()
+                    .filter(|name| {
+                        name.len() <= words.len()
+                            && name
+                                .iter()
+                                .zip(&words)
+                                .all(|(name, word)| *name == word.text)
+                    })
+                    .map(Vec::len)
+                    .max()
+                    .unwrap_or(0);
+
+                tokens.extend(
+                    words[..name_len]
+                        .iter()
+                        .map(|&token| (token.clone(), SemanticTokenType::Function)),
+                );
+                tokens.extend(
+                    words[name_len..]
+                        .iter()
+                        .filter_map(|&token| argument_semantic_token(token)),
+                );
+            }
+        }
+    }
+}
+
+/// Classifies an argument token for highlighting. Strings and convenience
+/// variables are highlighted, while other arguments are not.
+fn argument_semantic_token<'a>(token: &Token<'a>) -> Option<(Token<'a>, SemanticTokenType)> {
+    let token_type = if token.text.starts_with('"') {
+        SemanticTokenType::String
+    } else if token.text.starts_with('$') {
+        SemanticTokenType::Variable
+    } else {
+        return None;
+    };
+
+    Some((token.clone(), token_type))
+}
+
+/// The files `source`d by a script which have not been loaded, as returned by
+/// `Semantics::set_file_text`.
+#[derive(Debug)]
+pub struct UnresolvedPaths<'a> {
+    pub paths: Vec<UnresolvedPath<'a>>,
+}
+
+impl<'a> UnresolvedPaths<'a> {
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    /// Returns just the unresolved file paths, without their source positions.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.paths
+            .iter()
+            .map(|unresolved| unresolved.path.as_path())
+    }
+}
+
+#[derive(Debug)]
+pub struct UnresolvedPath<'a> {
+    /// The absolute path of the file which was `source`d.
+    pub path: PathBuf,
+    /// The position of the `source` command which refers to the file.
+    pub source: CursorPosition<'a>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CursorPosition<'a> {
+    pub file: &'a Path,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A span of text within a single file. The `end` position is exclusive.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CursorRange<'a> {
+    pub start: CursorPosition<'a>,
+    pub end: CursorPosition<'a>,
+}
+
+impl<'a> CursorRange<'a> {
+    fn new(file: &'a Path, start: Location, end: Location) -> Self {
+        Self {
+            start: CursorPosition {
+                file,
+                line: start.line,
+                column: start.column,
+            },
+            end: CursorPosition {
+                file,
+                line: end.line,
+                column: end.column,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Symbol<'a> {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// The range of the identifier naming this symbol.
+    pub name_range: CursorRange<'a>,
+    /// The range of the entire definition, for example from `define` through
+    /// the matching `end`.
+    pub range: CursorRange<'a>,
+    /// The symbols defined within this one.
+    pub children: Vec<Symbol<'a>>,
+}
+
+/// A command defined in a file, as returned by `Semantics::definitions_in` and
+/// `Semantics::find_command_definition`.
+#[derive(Debug)]
+pub struct CommandDefinition<'a> {
+    pub name: String,
+    /// The range of the identifier naming the command.
+    pub name_range: CursorRange<'a>,
+    /// The range from `define` through the matching `end`.
+    pub range: CursorRange<'a>,
+    /// The range from the start of the first command in the body to the end of
+    /// the last, or `None` if the body is empty.
+    pub body_range: Option<CursorRange<'a>>,
+    /// The lines between `define` and `end`, as written.
+    pub body: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    /// A user defined command.
+    Function,
+}
+
+/// An occurrence of a command within a file, as returned by
+/// `Semantics::document_highlights`.
+#[derive(Debug)]
+pub struct DocumentHighlight<'a> {
+    pub range: CursorRange<'a>,
+    pub kind: HighlightKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HighlightKind {
+    /// A usage of the command.
+    Read,
+    /// A definition of the command.
+    Write,
+}
+
+/// A command or comment in a file, as returned by `Semantics::command_tree`.
+#[derive(Debug)]
+pub struct CommandNode<'a> {
+    pub kind: CommandKind,
+    /// The first word of the command as written, such as `define` or `print`.
+    /// For a comment, this is the text of the comment including the `#`.
+    pub name: String,
+    /// The range from the start of the command through its last token, which for
+    /// a block is its `end`.
+    pub range: CursorRange<'a>,
+    /// The text of the range as written, including any escaped newlines and,
+    /// for a block, its body.
+    pub text: String,
+    /// The commands and comments within the body of a block.
+    pub children: Vec<CommandNode<'a>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandKind {
+    Define,
+    Source,
+    If,
+    While,
+    Comment,
+    /// Any other command, including other blocks such as `document`.
+    Other,
+}
+
+/// A fix for a problem, as returned by `Semantics::code_actions`.
+#[derive(Debug)]
+pub struct CodeAction<'a> {
+    /// A short description of the fix, such as "Insert `end`".
+    pub title: String,
+    pub edit: WorkspaceEdit<'a>,
+}
+
+/// Extra information shown inline with a script, as returned by
+/// `Semantics::inlay_hints`.
+#[derive(Debug)]
+pub struct InlayHint<'a> {
+    /// Where the hint is shown, which is just after the text it is about.
+    pub position: CursorPosition<'a>,
+    pub label: String,
+}
+
+/// A set of text edits, grouped by the file they apply to.
+#[derive(Debug)]
+pub struct WorkspaceEdit<'a> {
+    pub changes: HashMap<&'a Path, Vec<TextEdit<'a>>>,
+}
+
+#[derive(Debug)]
+pub struct TextEdit<'a> {
+    /// The text to replace.
+    pub range: CursorRange<'a>,
+    pub new_text: String,
+}
+
+/// A range of lines, from the line which opens a block through the line
+/// which closes it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FoldingRange {
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+#[derive(Debug)]
+pub struct Diagnostic<'a> {
+    pub severity: Severity,
+    pub message: String,
+    pub range: CursorRange<'a>,
+    /// Other locations which help explain this diagnostic.
+    pub related: Vec<RelatedLocation<'a>>,
+}
+
+#[derive(Debug)]
+pub struct RelatedLocation<'a> {
+    pub message: String,
+    pub range: CursorRange<'a>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Hint,
+}
+
+/// A token in a file, as returned by `Semantics::token_at` and
+/// `Semantics::tokens`.
+#[derive(Debug, PartialEq)]
+pub struct TokenInfo<'a> {
+    pub text: &'a str,
+    pub range: CursorRange<'a>,
+    pub kind: TokenKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenKind {
+    /// The first token of a command line, which names the command.
+    Command,
+    /// Any token after the command name.
+    Argument,
+    /// A comment, including the `#`.
+    Comment,
+}
+
+#[derive(Debug)]
+pub struct SemanticToken<'a> {
+    pub range: CursorRange<'a>,
+    pub token_type: SemanticTokenType,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SemanticTokenType {
+    /// Commands which structure a script, such as `define` and `end`.
+    Keyword,
+    /// The name of a user defined command, where it is defined or called.
+    Function,
+    Comment,
+    String,
+    /// A convenience variable, such as `$foo`.
+    Variable,
+}
+
+#[derive(Debug)]
+pub struct SignatureHelp {
+    /// The command name followed by its arguments, for example `greet arg0 arg1`.
+    pub label: String,
+    pub parameters: Vec<String>,
+    /// The index into `parameters` of the argument at the cursor.
+    pub active_parameter: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct Hover {
+    /// Markdown formatted hover text.
+    pub contents: String,
+}
+
+#[derive(Debug)]
+pub struct Completion {
+    pub text: String,
+}
+
+#[derive(Default)]
+pub struct Completions {
+    pub built_in: Vec<Completion>,
+    pub user_provided: Vec<Completion>,
+    /// Set when the cursor is on a file path, such as the argument to `source`.
+    /// Since `Semantics` does no IO, listing the candidate files is left to the
+    /// caller.
+    pub path: Option<PathCompletion>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PathCompletion {
+    /// The absolute path of the directory containing the candidate files.
+    pub directory: PathBuf,
+    /// The part of the file name typed so far, which candidates should start
+    /// with.
+    pub file_name_prefix: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    use expect_test::{expect, Expect};
+
+    use crate::test_support::{parse_cursor_position, parse_cursor_positions};
+
+    use super::{
+        CommandNode, CursorPosition, CursorRange, Diagnostic, InlayHint, PathCompletion,
+        PositionEncoding, Semantics, SemanticsBuilder, Severity, TextEdit, TokenKind,
+    };
+
+    #[test]
+    fn find_definition_simple() {
+        let script = r#"
+define say_hi
+    echo hi
+end
+
+<|>say_hi
+        "#;
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script);
+
+            semantics
+        };
+
+        let item_position = CursorPosition {
+            file: &script_path,
+            line: location.line,
+            column: location.column,
+        };
+
+        let definition = semantics
+            .find_definition(item_position)
+            .expect("should find definition");
+
+        assert_eq!(script_path, definition.file);
+        assert_eq!(1, definition.line);
+        assert_eq!(7, definition.column);
+    }
+
+    #[test]
+    fn find_definition_returns_none_if_def_is_after_identifier() {
+        let script = r#"
+<|>say_hi
+
+define say_hi
+    echo hi
+end
+        "#;
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script);
+
+            semantics
+        };
+
+        let item_position = CursorPosition {
+            file: &script_path,
+            line: location.line,
+            column: location.column,
+        };
+
+        let definition = semantics.find_definition(item_position);
+
+        assert!(definition.is_none());
+    }
+
+    #[test]
+    fn find_command_definition() {
+        let script = r#"
+define say_hi
+    echo hi
+    echo $arg0
+end
+<|>say_hi there
+define empty
+end
+empty
+        "#;
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script);
+
+            semantics
+        };
+
+        let check = |line, column, expect: Expect| {
+            let definition = semantics.find_command_definition(CursorPosition {
+                file: &script_path,
+                line,
+                column,
+            });
+            let format_range = |range: &CursorRange| {
+                format!(
+                    "{}:{}-{}:{}",
+                    range.start.line, range.start.column, range.end.line, range.end.column,
+                )
+            };
+            expect.assert_eq(&definition.map_or("none".to_owned(), |definition| {
+                format!(
+                    "{} {} ({}) body {} {:?}",
+                    definition.name,
+                    format_range(&definition.range),
+                    format_range(&definition.name_range),
+                    definition
+                        .body_range
+                        .as_ref()
+                        .map_or("none".to_owned(), format_range),
+                    definition.body,
+                )
+            }));
+        };
+
+        check(
+            location.line,
+            location.column,
+            expect![[r#"say_hi 1:0-4:3 (1:7-1:13) body 2:4-3:14 "    echo hi\n    echo $arg0""#]],
+        );
+        // An argument refers to the command it is passed to.
+        check(
+            3,
+            10,
+            expect![[r#"say_hi 1:0-4:3 (1:7-1:13) body 2:4-3:14 "    echo hi\n    echo $arg0""#]],
+        );
+        check(8, 0, expect![[r#"empty 6:0-7:3 (6:7-6:12) body none """#]]);
+        // Built in commands have no definition.
+        check(2, 4, expect![[r#"none"#]]);
+    }
+
+    #[test]
+    fn find_definition_follows_sources_in_order() {
+        let script = r#"source one.gdb
+<|>greet
+define greet
+end
+<|>greet
+source two.gdb
+<|>greet
+source three.gdb
+<|>greet
+        "#;
+        let (script, locations) = parse_cursor_positions(script);
+        let script_path = PathBuf::from("/home/user/main.gdb");
+
+        let semantics = {
+            let mut semantics = Semantics::new(PathBuf::from("/home/user"));
+            semantics.set_file_text(script_path.clone(), script);
+            semantics.set_file_text(
+                PathBuf::from("/home/user/one.gdb"),
+                "define greet\nend\n".to_owned(),
+            );
+            semantics.set_file_text(
+                PathBuf::from("/home/user/two.gdb"),
+                "\ndefine greet\nend\n".to_owned(),
+            );
+            // This doesn't define `greet` itself, but sourcing `one.gdb` again
+            // makes its definition the most recent.
+            semantics.set_file_text(
+                PathBuf::from("/home/user/three.gdb"),
+                "source one.gdb\n".to_owned(),
+            );
+
+            semantics
+        };
+
+        let definitions = locations
+            .iter()
+            .map(|location| {
+                let definition = semantics
+                    .find_definition(CursorPosition {
+                        file: &script_path,
+                        line: location.line,
+                        column: location.column,
+                    })
+                    .expect("should find definition");
+                format!(
+                    "{}:{}:{}",
+                    definition.file.display(),
+                    definition.line,
+                    definition.column
+                )
+            })
+            .collect::<Vec<String>>();
+
+        expect![[r#"
+            /home/user/one.gdb:0:7
+            /home/user/main.gdb:2:7
+            /home/user/two.gdb:1:7
+            /home/user/one.gdb:0:7"#]]
+        .assert_eq(&definitions.join("\n"));
+    }
+
+    #[test]
+    fn find_definition_source_path() {
+        let script = r#"source <|>bar.gdb
+source -v <|>baz.gdb
+source <|>missing.gdb
+        "#;
+        let (script, locations) = parse_cursor_positions(script);
+        let script_path = PathBuf::from("/home/user/main.gdb");
+
+        let semantics = {
+            let mut semantics = Semantics::new(PathBuf::from("/home/user"));
+            semantics.set_file_text(script_path.clone(), script);
+            semantics.set_file_text(
+                PathBuf::from("/home/user/bar.gdb"),
+                "define greet\nend\n".to_owned(),
+            );
+            semantics.set_file_text(PathBuf::from("/home/user/baz.gdb"), "\n".to_owned());
+
+            semantics
+        };
+
+        let definitions = locations
+            .iter()
+            .map(|location| {
+                match semantics.find_definition(CursorPosition {
+                    file: &script_path,
+                    line: location.line,
+                    column: location.column,
+                }) {
+                    Some(definition) => format!(
+                        "{}:{}:{}",
+                        definition.file.display(),
+                        definition.line,
+                        definition.column
+                    ),
+                    None => "none".to_owned(),
+                }
+            })
+            .collect::<Vec<String>>();
+
+        expect![[r#"
+            /home/user/bar.gdb:0:0
+            /home/user/baz.gdb:0:0
+            none"#]]
+        .assert_eq(&definitions.join("\n"));
+    }
+
+    #[test]
+    fn find_definition_returns_most_recent_definition() {
+        let script = r#"
+define say_hi
+    echo hi
+end
+
+define say_hi
+    echo hi!!!
+end
+
+<|>say_hi
+        "#;
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script);
+
+            semantics
+        };
+
+        let item_position = CursorPosition {
+            file: &script_path,
+            line: location.line,
+            column: location.column,
+        };
+
+        let definition = semantics
+            .find_definition(item_position)
+            .expect("should find definition");
+
+        assert_eq!(script_path, definition.file);
+        assert_eq!(5, definition.line);
+        assert_eq!(7, definition.column);
+    }
+
+    #[test]
+    fn find_definition_subcommand() {
+        let script = r#"
+define target hookpost-foo
+    echo hi
+end
+
+target <|>hookpost-foo
+        "#;
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script);
+
+            semantics
+        };
+
+        let item_position = CursorPosition {
+            file: &script_path,
+            line: location.line,
+            column: location.column,
+        };
+
+        let definition = semantics
+            .find_definition(item_position)
+            .expect("should find definition");
+
+        assert_eq!(script_path, definition.file);
+        assert_eq!(1, definition.line);
+        assert_eq!(7, definition.column);
+    }
+
+    #[test]
+    fn find_definition_after_command_separator() {
+        let script = r#"
+define foo
+    echo foo
+end
+
+echo a; <|>foo
+<|>foo; echo a
+        "#;
+        let (script, locations) = parse_cursor_positions(script);
+        let script_path = PathBuf::from("foo.gdb");
+
+        let mut semantics = Semantics::new(PathBuf::new());
+        semantics.set_file_text(script_path.clone(), script);
+
+        for location in locations {
+            let definition = semantics
+                .find_definition(CursorPosition {
+                    file: &script_path,
+                    line: location.line,
+                    column: location.column,
+                })
+                .expect("should find definition");
+
+            assert_eq!(script_path, definition.file);
+            assert_eq!(1, definition.line);
+            assert_eq!(7, definition.column);
+        }
+    }
+
+    #[test]
+    fn find_definition_command_with_args() {
+        let script = r#"
+define say_hi
+    echo hi $arg0
+end
+
+say_<|>hi arg1 arg2
+        "#;
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script);
+
+            semantics
+        };
+
+        let item_position = CursorPosition {
+            file: &script_path,
+            line: location.line,
+            column: location.column,
+        };
+
+        let definition = semantics
+            .find_definition(item_position)
+            .expect("should find definition");
+
+        assert_eq!(script_path, definition.file);
+        assert_eq!(1, definition.line);
+        assert_eq!(7, definition.column);
+    }
+
+    #[test]
+    fn find_definition_cursor_on_command_arg() {
+        let script = r#"
+define say_hi
+    echo hi $arg0
+end
+
+define greet
+    say_hi $arg0
+end
+
+say_hi <|>greet
+        "#;
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script);
+
+            semantics
+        };
+
+        let item_position = CursorPosition {
+            file: &script_path,
+            line: location.line,
+            column: location.column,
+        };
+
+        // The argument happens to name a user defined command, but it is passed
+        // to `say_hi` rather than being called.
+        assert!(semantics.find_definition(item_position).is_none());
+    }
+
+    fn check_token_at(script: &str) -> Option<(String, TokenKind, usize, usize, usize)> {
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("foo.gdb");
+
+        let mut semantics = Semantics::new(PathBuf::new());
+        semantics.set_file_text(script_path.clone(), script);
+
+        semantics
+            .token_at(CursorPosition {
+                file: &script_path,
+                line: location.line,
+                column: location.column,
+            })
+            .map(|token| {
+                (
+                    token.text.to_owned(),
+                    token.kind,
+                    token.range.start.line,
+                    token.range.start.column,
+                    token.range.end.column,
+                )
+            })
+    }
+
+    #[test]
+    fn token_at_command() {
+        assert_eq!(
+            Some(("say_hi".to_owned(), TokenKind::Command, 0, 0, 6)),
+            check_token_at("say<|>_hi arg1")
+        );
+    }
+
+    #[test]
+    fn token_at_argument() {
+        assert_eq!(
+            Some(("arg1".to_owned(), TokenKind::Argument, 0, 7, 11)),
+            check_token_at("say_hi <|>arg1")
+        );
+        assert_eq!(
+            Some(("arg2".to_owned(), TokenKind::Argument, 1, 2, 6)),
+            check_token_at("say_hi arg1 \\\n  a<|>rg2")
+        );
+    }
+
+    #[test]
+    fn tokens_in_file() {
+        let script = r#"
+define say_hi # greet
+  echo hi; echo "a b"\n
+end
+document say_hi
+Says hi # not a comment
+end
+        "#;
+        let script_path = PathBuf::from("foo.gdb");
+        let mut semantics = Semantics::new(PathBuf::new());
+        semantics.set_file_text(script_path.clone(), script.to_owned());
+
+        let tokens = semantics
+            .tokens(&script_path)
+            .into_iter()
+            .map(|token| {
+                format!(
+                    "{:?} {}:{}-{}:{} {}",
+                    token.kind,
+                    token.range.start.line,
+                    token.range.start.column,
+                    token.range.end.line,
+                    token.range.end.column,
+                    token.text
+                )
+            })
+            .collect::<Vec<String>>();
+        expect![[r##"
+            Command 1:0-1:6 define
+            Argument 1:7-1:13 say_hi
+            Comment 1:14-1:21 # greet
+            Command 2:2-2:6 echo
+            Argument 2:7-2:9 hi
+            Command 2:11-2:15 echo
+            Argument 2:16-2:23 "a b"\n
+            Command 3:0-3:3 end
+            Command 4:0-4:8 document
+            Argument 4:9-4:15 say_hi
+            Command 6:0-6:3 end"##]]
+        .assert_eq(&tokens.join("\n"));
+    }
+
+    #[test]
+    fn token_at_whitespace() {
+        assert_eq!(None, check_token_at("say_hi <|> arg1"));
+        assert_eq!(None, check_token_at("  <|>  say_hi"));
+    }
+
+    #[test]
+    fn token_at_past_end_of_line() {
+        assert_eq!(None, check_token_at("say_hi arg1<|>"));
+        assert_eq!(None, check_token_at("say_hi arg1   <|>"));
+    }
+
+    #[test]
+    fn find_definition_argument_inside_define() {
+        let script = r#"
+define say
+    echo <|>$arg0
+end
+        "#;
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script);
+
+            semantics
+        };
+
+        let item_position = CursorPosition {
+            file: &script_path,
+            line: location.line,
+            column: location.column,
+        };
+
+        let definition = semantics
+            .find_definition(item_position)
+            .expect("should find definition");
+
+        assert_eq!(script_path, definition.file);
+        assert_eq!(1, definition.line);
+        assert_eq!(0, definition.column);
+
+        let hover = semantics.hover(item_position).expect("should hover");
+        assert_eq!("Argument 0 passed to `say`", hover.contents);
+    }
+
+    #[test]
+    fn find_definition_argument_outside_define() {
+        let script = r#"
+define say
+    echo $arg0
+end
+echo <|>$arg0
+        "#;
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script);
+
+            semantics
+        };
+
+        let item_position = CursorPosition {
+            file: &script_path,
+            line: location.line,
+            column: location.column,
+        };
+
+        assert!(semantics.find_definition(item_position).is_none());
+        assert!(semantics.hover(item_position).is_none());
+    }
+
+    #[test]
+    fn find_definition_from_other_file() {
+        let script_1 = r#"
+source hello.gdb
+
+<|>say_hi
+        "#;
+        let (script_1, location) = parse_cursor_position(script_1);
+        let script_1_path = PathBuf::from("/home/user/foo.gdb");
+        let script_2 = r#"
+define say_hi
+    echo hi
+end
+        "#;
+        let script_2_path = PathBuf::from("/home/user/hello.gdb");
+
+        let semantics = {
+            // We use a non-empty CWD here to show that path canonicalization
+            // works.
+            let fake_cwd: PathBuf = PathBuf::from("/home/user");
+            let mut semantics = Semantics::new(fake_cwd);
+            let unresolved_imports = semantics.set_file_text(script_1_path.clone(), script_1);
+            assert_eq!(1, unresolved_imports.paths.len());
+            assert_eq!(script_2_path, unresolved_imports.paths[0].path);
+
+            semantics.set_file_text(script_2_path.clone(), script_2.to_owned());
+
+            semantics
+        };
+
+        let item_position = CursorPosition {
+            file: &script_1_path,
+            line: location.line,
+            column: location.column,
+        };
+
+        let definition = semantics
+            .find_definition(item_position)
+            .expect("should find definition");
+
+        assert_eq!(script_2_path, definition.file);
+        assert_eq!(1, definition.line);
+        assert_eq!(7, definition.column);
+    }
+
+    #[test]
+    fn find_definition_source_relative_to_sourcing_file() {
+        let script_1 = r#"
+source b.gdb
+
+<|>say_hi
+        "#;
+        let (script_1, location) = parse_cursor_position(script_1);
+        let script_1_path = PathBuf::from("/home/user/sub/a.gdb");
+        let script_2_path = PathBuf::from("/home/user/sub/b.gdb");
+
+        let mut semantics = Semantics::new(PathBuf::from("/home/user"));
+        let unresolved_imports = semantics.set_file_text(script_1_path.clone(), script_1);
+        assert_eq!(
+            vec![
+                Path::new("/home/user/sub/b.gdb"),
+                Path::new("/home/user/b.gdb")
+            ],
+            unresolved_imports.paths().collect::<Vec<&Path>>()
+        );
+        semantics.set_file_text(script_2_path.clone(), "define say_hi\nend\n".to_owned());
+
+        let item_position = CursorPosition {
+            file: &script_1_path,
+            line: location.line,
+            column: location.column,
+        };
+        let definition = semantics
+            .find_definition(item_position)
+            .expect("should find definition");
+
+        assert_eq!(script_2_path, definition.file);
+    }
+
+    #[test]
+    fn find_definition_source_relative_to_project_root() {
+        let script_1 = r#"
+source b.gdb
+
+<|>say_hi
+        "#;
+        let (script_1, location) = parse_cursor_position(script_1);
+        let script_1_path = PathBuf::from("/home/user/sub/a.gdb");
+        let script_2_path = PathBuf::from("/home/user/b.gdb");
+
+        let mut semantics = Semantics::new(PathBuf::from("/home/user"));
+        semantics.set_file_text(script_2_path.clone(), "define say_hi\nend\n".to_owned());
+        let unresolved_imports = semantics.set_file_text(script_1_path.clone(), script_1);
+        assert!(unresolved_imports.is_empty());
+
+        let item_position = CursorPosition {
+            file: &script_1_path,
+            line: location.line,
+            column: location.column,
+        };
+        let definition = semantics
+            .find_definition(item_position)
+            .expect("should find definition");
+
+        assert_eq!(script_2_path, definition.file);
+    }
+
+    #[test]
+    fn set_file_text_requests_unresolved_imports() {
+        let script_1 = r#"source bar.gdb"#;
+        let script_1_path = PathBuf::from("foo.gdb");
+
+        let script_2 = r#"echo hi from bar"#;
+        let script_2_path = PathBuf::from("bar.gdb");
+
+        let script_3 = r#"source bar.gdb"#;
+        let script_3_path = PathBuf::from("baz.gdb");
+
+        let mut semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            Semantics::new(fake_cwd)
+        };
+
+        let unresolved_imports =
+            semantics.set_file_text(script_1_path.clone(), script_1.to_owned());
+        assert_eq!(1, unresolved_imports.paths.len());
+        assert_eq!(script_2_path, unresolved_imports.paths[0].path);
+
+        let unresolved_imports =
+            semantics.set_file_text(script_2_path.clone(), script_2.to_owned());
+        assert!(unresolved_imports.is_empty());
+
+        let unresolved_imports =
+            semantics.set_file_text(script_3_path.clone(), script_3.to_owned());
+        assert!(unresolved_imports.is_empty());
+    }
+
+    fn check_completions_user_provided(script: &str, expect_parse: Expect) {
+        let (script, location) = parse_cursor_position(script);
+
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script.to_owned());
+
+            semantics
+        };
+
+        let cursor_position = CursorPosition {
+            file: &script_path,
+            line: location.line,
+            column: location.column,
+        };
+
+        let completions = semantics.find_completions(cursor_position);
+
+        expect_parse.assert_eq(
+            &completions
+                .user_provided
+                .into_iter()
+                .map(|completion| completion.text)
+                .collect::<Vec<String>>()
+                .join("\n"),
+        );
+    }
+
+    #[test]
+    fn completions_user_provided_empty_script() {
+        check_completions_user_provided("<|>", expect![[r#""#]]);
+    }
+
+    #[test]
+    fn completions_user_provided_cursor_below_single_define() {
+        check_completions_user_provided(
+            r#"
+        define say_hi
+            echo hi
+        end
+
+        <|>
+            "#,
+            expect![[r#"say_hi"#]],
+        );
+    }
+
+    #[test]
+    fn completions_user_provided_subcommands_under_prefix() {
+        check_completions_user_provided(
+            r#"
+define-prefix my-prefix
+define my-prefix say_hi
+end
+define my-prefix say_bye
+end
+define target hookpost-foo
+end
+
+my-prefix say<|>
+            "#,
+            expect![[r#"
+                say_bye
+                say_hi"#]],
+        );
+    }
+
+    #[test]
+    fn completions_user_provided_defined_locally_and_in_sourced_file() {
+        let script_1 = r#"
+source hello.gdb
+
+define say_hi
+    echo hi
+end
+
+<|>
+        "#;
+        let (script_1, location) = parse_cursor_position(script_1);
+        let script_1_path = PathBuf::from("/home/user/foo.gdb");
+        let script_2 = r#"
+define say_hi
+    echo hello
+end
+
+define say_bye
+    echo bye
+end
+        "#;
+        let script_2_path = PathBuf::from("/home/user/hello.gdb");
+
+        let mut semantics = Semantics::new(PathBuf::from("/home/user"));
+        semantics.set_file_text(script_1_path.clone(), script_1);
+        semantics.set_file_text(script_2_path, script_2.to_owned());
+
+        let completions = semantics.find_completions(CursorPosition {
+            file: &script_1_path,
+            line: location.line,
+            column: location.column,
+        });
+
+        expect![[r#"
+            say_bye
+            say_hi"#]]
+        .assert_eq(
+            &completions
+                .user_provided
+                .into_iter()
+                .map(|completion| completion.text)
+                .collect::<Vec<String>>()
+                .join("\n"),
+        );
+    }
+
+    #[test]
+    fn completions_user_provided_includes_sourced_files() {
+        let script_1 = r#"
+source hello.gdb
+
+define say_hi
+    echo hi
+end
+
+define say_bye
+    echo bye
+end
+
+<|>
+        "#;
+        let (script_1, location) = parse_cursor_position(script_1);
+        let script_1_path = PathBuf::from("/home/user/foo.gdb");
+        let script_2 = r#"
+define say_hello
+    echo hello
+end
+        "#;
+        let script_2_path = PathBuf::from("/home/user/hello.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::from("/home/user");
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_1_path.clone(), script_1);
+            semantics.set_file_text(script_2_path, script_2.to_owned());
+
+            semantics
+        };
+
+        let completions = semantics.find_completions(CursorPosition {
+            file: &script_1_path,
+            line: location.line,
+            column: location.column,
+        });
+
+        expect![[r#"
+            say_bye
+            say_hello
+            say_hi"#]]
+        .assert_eq(
+            &completions
+                .user_provided
+                .into_iter()
+                .map(|completion| completion.text)
+                .collect::<Vec<String>>()
+                .join("\n"),
+        );
+    }
+
+    fn check_completions_built_in(script: &str, expect_parse: Expect) {
+        let (script, location) = parse_cursor_position(script);
+
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script);
+
+            semantics
+        };
+
+        let cursor_position = CursorPosition {
+            file: &script_path,
+            line: location.line,
+            column: location.column,
+        };
+
+        let completions = semantics.find_completions(cursor_position);
+
+        expect_parse.assert_eq(
+            &completions
+                .built_in
+                .into_iter()
+                .map(|completion| completion.text)
+                .collect::<Vec<String>>()
+                .join("\n"),
+        );
+    }
+
+    #[test]
+    fn completions_built_in_commands() {
+        check_completions_built_in(
+            r#"
+define say_hi
+  echo hi
+end
+<|>
+            "#,
+            expect![[r#"
+                define
+                else
+                end
+                if"#]],
+        );
+    }
+
+    #[test]
+    fn completions_built_in_commands_in_open_block() {
+        check_completions_built_in(
+            r#"
+define say_hi
+  if $argc
+    echo hi
+  end
+  <|>
+            "#,
+            expect![[r#"
+                end
+                define
+                else
+                if"#]],
+        );
+    }
+
+    #[test]
+    fn completions_built_in_command_prefix() {
+        check_completions_built_in("defi<|>", expect![[r#"define"#]]);
+    }
+
+    #[test]
+    fn completions_user_provided_command_prefix() {
+        check_completions_user_provided(
+            r#"
+define say_hi
+end
+define say_bye
+end
+define greet
+end
+say_b<|>
+            "#,
+            expect![[r#"say_bye"#]],
+        );
+    }
+
+    #[test]
+    fn completions_set_setting_prefix() {
+        check_completions_built_in("set pa<|>", expect![[r#"pagination"#]]);
+    }
+
+    #[test]
+    fn completions_set_setting() {
+        check_completions_built_in(
+            "set <|>",
+            expect![[r#"
+            confirm
+            height
+            listsize
+            logging
+            max-completions
+            pagination
+            print
+            verbose
+            width"#]],
+        );
+    }
+
+    #[test]
+    fn completions_set_setting_value() {
+        check_completions_built_in(
+            "set pagination <|>",
+            expect![[r#"
+                off
+                on"#]],
+        );
+    }
+
+    #[test]
+    fn completions_set_multi_word_setting() {
+        check_completions_built_in(
+            "set print <|>",
+            expect![[r#"
+                elements
+                pretty"#]],
+        );
+    }
+
+    #[test]
+    fn completions_show_setting_has_no_values() {
+        check_completions_built_in("show pagination <|>", expect![[r#""#]]);
+    }
+
+    #[test]
+    fn completions_break_condition() {
+        check_completions_built_in("break main <|>", expect![[r#"if"#]]);
+        check_completions_built_in("tbreak foo.c:12 <|>", expect![[r#"if"#]]);
+        check_completions_built_in("b main thread 1 <|>", expect![[r#"if"#]]);
+        check_completions_built_in("break <|>", expect![[r#""#]]);
+        check_completions_built_in("break main if <|>", expect![[r#""#]]);
+        check_completions_user_provided("define say_hi\nend\nbreak main <|>", expect![[r#""#]]);
+    }
+
+    #[test]
+    fn completions_break_condition_abbreviated() {
+        check_completions_built_in("br main <|>", expect![[r#"if"#]]);
+        check_completions_built_in("brea main <|>", expect![[r#"if"#]]);
+        check_completions_built_in("tb main <|>", expect![[r#"if"#]]);
+        check_completions_built_in("tbr main <|>", expect![[r#"if"#]]);
+    }
+
+    fn check_path_completion(script: &str) -> Option<PathCompletion> {
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("/home/user/foo.gdb");
+
+        let mut semantics = Semantics::new(PathBuf::from("/home/user"));
+        semantics.set_file_text(script_path.clone(), script);
+
+        semantics
+            .find_completions(CursorPosition {
+                file: &script_path,
+                line: location.line,
+                column: location.column,
+            })
+            .path
+    }
+
+    #[test]
+    fn completions_source_path() {
+        assert_eq!(
+            Some(PathCompletion {
+                directory: PathBuf::from("/home/user"),
+                file_name_prefix: "he".to_owned(),
+            }),
+            check_path_completion("source he<|>")
+        );
+        assert_eq!(
+            Some(PathCompletion {
+                directory: PathBuf::from("/home/user/scripts/"),
+                file_name_prefix: String::new(),
+            }),
+            check_path_completion("source -v scripts/<|>")
+        );
+        assert_eq!(
+            Some(PathCompletion {
+                directory: PathBuf::from("/etc/gdb/"),
+                file_name_prefix: "gdb".to_owned(),
+            }),
+            check_path_completion("source /etc/gdb/gdb<|>")
+        );
+    }
+
+    #[test]
+    fn completions_source_only_first_path() {
+        assert_eq!(None, check_path_completion("source hello.gdb <|>"));
+        assert_eq!(None, check_path_completion("echo <|>"));
+    }
+
+    #[test]
+    fn completions_underscore_variables() {
+        check_completions_built_in(
+            "$_<|>",
+            expect![[r#"
+            $_
+            $__
+            $_exitcode
+            $_siginfo
+            $_thread"#]],
+        );
+    }
+
+    #[test]
+    fn completions_built_in_variables() {
+        check_completions_built_in(
+            "$<|>",
+            expect![[r#"
+                $_
+                $__
+                $_exitcode
+                $_siginfo
+                $_thread
+                $argc
+                $bpnum
+                $fp
+                $pc
+                $ps
+                $sp"#]],
+        );
+    }
+
+    #[test]
+    fn completions_condition() {
+        check_completions_built_in(
+            "if $<|>",
+            expect![[r#"
+                $_
+                $__
+                $_exitcode
+                $_siginfo
+                $_thread
+                $argc
+                $bpnum
+                $fp
+                $pc
+                $ps
+                $sp"#]],
+        );
+        check_completions_user_provided("set $count = 0\nwhile <|>", expect![[r#"$count"#]]);
+        check_completions_built_in(
+            "while $count <|>",
+            expect![[r#"
+                !=
+                &&
+                <
+                <=
+                ==
+                >
+                >=
+                ||"#]],
+        );
+        check_completions_built_in(
+            "if $count == <|>",
+            expect![[r#"
+                $_
+                $__
+                $_exitcode
+                $_siginfo
+                $_thread
+                $argc
+                $bpnum
+                $fp
+                $pc
+                $ps
+                $sp"#]],
+        );
+    }
+
+    #[test]
+    fn completions_user_provided_variables_exclude_registers() {
+        check_completions_user_provided(
+            "set $myvar = 1\nset $pc = 0x1000\necho $<|>",
+            expect![[r#"$myvar"#]],
+        );
+    }
+
+    #[test]
+    fn completions_user_provided_variables() {
+        check_completions_user_provided(
+            r#"
+        set $counter = 0
+        define bump
+            set $total=$counter
+        end
+
+        print $<|>
+            "#,
+            expect![[r#"
+                $counter
+                $total"#]],
+        );
+    }
+
+    #[test]
+    fn document_symbols() {
+        let script = r#"
+define say_hi
+    echo hi
+end
+
+say_hi
+
+define say_bye
+        "#;
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script.to_owned());
+
+            semantics
+        };
+
+        expect![[r#"
+            [
+                Symbol {
+                    name: "say_hi",
+                    kind: Function,
+                    name_range: CursorRange {
+                        start: CursorPosition {
+                            file: "foo.gdb",
+                            line: 1,
+                            column: 7,
+                        },
+                        end: CursorPosition {
+                            file: "foo.gdb",
+                            line: 1,
+                            column: 13,
+                        },
+                    },
+                    range: CursorRange {
+                        start: CursorPosition {
+                            file: "foo.gdb",
+                            line: 1,
+                            column: 0,
+                        },
+                        end: CursorPosition {
+                            file: "foo.gdb",
+                            line: 3,
+                            column: 3,
+                        },
+                    },
+                    children: [],
+                },
+                Symbol {
+                    name: "say_bye",
+                    kind: Function,
+                    name_range: CursorRange {
+                        start: CursorPosition {
+                            file: "foo.gdb",
+                            line: 7,
+                            column: 7,
+                        },
+                        end: CursorPosition {
+                            file: "foo.gdb",
+                            line: 7,
+                            column: 14,
+                        },
+                    },
+                    range: CursorRange {
+                        start: CursorPosition {
+                            file: "foo.gdb",
+                            line: 7,
+                            column: 0,
+                        },
+                        end: CursorPosition {
+                            file: "foo.gdb",
+                            line: 7,
+                            column: 14,
+                        },
+                    },
+                    children: [],
+                },
+            ]
+        "#]]
+        .assert_debug_eq(&semantics.document_symbols(&script_path));
+    }
+
+    #[test]
+    fn find_references_across_files() {
+        let script_1 = r#"
+source hello.gdb
+
+say_hi
+define say_hi_twice
+    <|>say_hi
+    say_hi
+end
+        "#;
+        let (script_1, location) = parse_cursor_position(script_1);
+        let script_1_path = PathBuf::from("/home/user/foo.gdb");
+        let script_2 = r#"
+define say_hi
+    echo hi
+end
+        "#;
+        let script_2_path = PathBuf::from("/home/user/hello.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::from("/home/user");
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_1_path.clone(), script_1);
+            semantics.set_file_text(script_2_path.clone(), script_2.to_owned());
+
+            semantics
+        };
+
+        let item_position = CursorPosition {
+            file: &script_1_path,
+            line: location.line,
+            column: location.column,
+        };
+
+        let references = semantics
+            .find_references(item_position)
+            .into_iter()
+            .map(|reference| {
+                format!(
+                    "{}:{}:{}-{}:{}",
+                    reference.start.file.display(),
+                    reference.start.line,
+                    reference.start.column,
+                    reference.end.line,
+                    reference.end.column
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        expect![[r#"
+            /home/user/foo.gdb:3:0-3:6
+            /home/user/foo.gdb:5:4-5:10
+            /home/user/foo.gdb:6:4-6:10
+            /home/user/hello.gdb:1:7-1:13"#]]
+        .assert_eq(&references);
+    }
+
+    #[test]
+    fn diamond_sources_are_visited_once() {
+        // `a.gdb` sources `common.gdb` both directly and through `b.gdb`.
+        let script_a = r#"
+source common.gdb
+source b.gdb
+<|>
+shared
+        "#;
+        let (script_a, location) = parse_cursor_position(script_a);
+        let script_a_path = PathBuf::from("/home/user/a.gdb");
+        let script_b_path = PathBuf::from("/home/user/b.gdb");
+        let common_path = PathBuf::from("/home/user/common.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::from("/home/user");
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_a_path.clone(), script_a);
+            semantics.set_file_text(
+                script_b_path,
+                "source common.gdb\ndefine shared\n    echo b\nend\ndefine from_b\nend\n"
+                    .to_owned(),
+            );
+            semantics.set_file_text(
+                common_path,
+                "define shared\n    echo common\nend\n".to_owned(),
+            );
+
+            semantics
+        };
+
+        let completions = semantics.find_completions(CursorPosition {
+            file: &script_a_path,
+            line: location.line,
+            column: location.column,
+        });
+        expect![[r#"
+            from_b
+            shared"#]]
+        .assert_eq(
+            &completions
+                .user_provided
+                .into_iter()
+                .map(|completion| completion.text)
+                .collect::<Vec<String>>()
+                .join("\n"),
+        );
+
+        let usage = CursorPosition {
+            file: &script_a_path,
+            line: location.line + 1,
+            column: 0,
+        };
+        let definition = semantics
+            .find_definition(usage)
+            .expect("should find definition");
+        expect![[r#"/home/user/b.gdb:1:7"#]].assert_eq(&format!(
+            "{}:{}:{}",
+            definition.file.display(),
+            definition.line,
+            definition.column
+        ));
+
+        expect![[r#"
+            /home/user/a.gdb:4:0-4:6
+            /home/user/b.gdb:1:7-1:13
+            /home/user/common.gdb:0:7-0:13"#]]
+        .assert_eq(
+            &semantics
+                .find_references(usage)
+                .into_iter()
+                .map(|reference| {
+                    format!(
+                        "{}:{}:{}-{}:{}",
+                        reference.start.file.display(),
+                        reference.start.line,
+                        reference.start.column,
+                        reference.end.line,
+                        reference.end.column
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+        );
+
+        assert!(semantics.diagnostics(&script_a_path).is_empty());
+    }
+
+    #[test]
+    fn linked_editing_ranges_recursive_define() {
+        let script = r#"
+define <|>countdown
+    if $arg0 > 0
+        echo tick\n
+        <|>countdown $arg0 - 1
+    end
+end
+
+define say_hi
+    echo <|>hi
+end
+
+define <|>say_hi
+    echo hi
+end
+
+define-prefix say
+define say hi
+    say   hi
+    <|>say hi
+end
+
+<|>countdown 3
+"#;
+        let (script, locations) = parse_cursor_positions(script);
+        let script_path = PathBuf::from("foo.gdb");
+        let mut semantics = Semantics::new(PathBuf::new());
+        semantics.set_file_text(script_path.clone(), script);
+
+        let linked_ranges = locations
+            .iter()
+            .map(|location| {
+                let ranges = semantics.linked_editing_ranges(CursorPosition {
+                    file: &script_path,
+                    line: location.line,
+                    column: location.column,
+                });
+                match ranges {
+                    Some(ranges) => ranges
+                        .iter()
+                        .map(|range| {
+                            format!(
+                                "{}:{}-{}:{}",
+                                range.start.line,
+                                range.start.column,
+                                range.end.line,
+                                range.end.column
+                            )
+                        })
+                        .collect::<Vec<String>>()
+                        .join(" "),
+                    None => "none".to_owned(),
+                }
+            })
+            .collect::<Vec<String>>();
+
+        expect![[r#"
+            1:7-1:16 4:8-4:17
+            1:7-1:16 4:8-4:17
+            none
+            none
+            17:7-17:13 19:4-19:10
+            none"#]]
+        .assert_eq(&linked_ranges.join("\n"));
+    }
+
+    #[test]
+    fn document_highlights_in_cursor_file() {
+        let script_1 = r#"
+define say_hi
+    echo hi
+end
+
+<|>say_hi
+if 1
+    say_hi
+end
+        "#;
+        let (script_1, location) = parse_cursor_position(script_1);
+        let script_1_path = PathBuf::from("/home/user/foo.gdb");
+        let script_2_path = PathBuf::from("/home/user/bar.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::from("/home/user");
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_1_path.clone(), script_1);
+            semantics.set_file_text(script_2_path.clone(), "source foo.gdb\nsay_hi\n".to_owned());
+
+            semantics
+        };
+
+        let highlights = semantics
+            .document_highlights(CursorPosition {
+                file: &script_1_path,
+                line: location.line,
+                column: location.column,
+            })
+            .into_iter()
+            .map(|highlight| {
+                format!(
+                    "{:?} {}:{}-{}:{}",
+                    highlight.kind,
+                    highlight.range.start.line,
+                    highlight.range.start.column,
+                    highlight.range.end.line,
+                    highlight.range.end.column,
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        expect![[r#"
+            Write 1:7-1:13
+            Read 5:0-5:6
+            Read 7:4-7:10"#]]
+        .assert_eq(&highlights);
+    }
+
+    /// The cursor is expected in the first script, and any other scripts
+    /// are loaded alongside it.
+    fn check_hover(scripts: &[(&str, &str)], expect: Expect) {
+        let (path, script) = scripts[0];
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from(path);
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::from("/home/user");
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script);
+            for (path, script) in scripts.iter().skip(1) {
+                semantics.set_file_text(PathBuf::from(path), (*script).to_owned());
+            }
+
+            semantics
+        };
+
+        let hover = semantics.hover(CursorPosition {
+            file: &script_path,
+            line: location.line,
+            column: location.column,
+        });
+
+        expect.assert_eq(&hover.map(|hover| hover.contents).unwrap_or_default());
+    }
+
+    #[test]
+    fn hover_user_defined_command() {
+        check_hover(
+            &[(
+                "/home/user/foo.gdb",
+                r#"
+define say_hi
+    echo hi
+    echo there
+end
+
+<|>say_hi
+            "#,
+            )],
+            expect![[r#"
+                ```gdb
+                    echo hi
+                    echo there
+                ```"#]],
+        );
+    }
+
+    #[test]
+    fn hover_user_defined_command_from_other_file() {
+        check_hover(
+            &[
+                (
+                    "/home/user/foo.gdb",
+                    r#"
+source hello.gdb
+
+<|>say_hi
+            "#,
+                ),
+                (
+                    "/home/user/hello.gdb",
+                    r#"
+define say_hi
+    echo hi
+end
+            "#,
+                ),
+            ],
+            expect![[r#"
+                ```gdb
+                    echo hi
+                ```
+
+                Defined in `/home/user/hello.gdb`"#]],
+        );
+    }
+
+    #[test]
+    fn hover_documented_command() {
+        check_hover(
+            &[(
+                "/home/user/foo.gdb",
+                r#"
+define say_hi
+    echo hi
+end
+document say_hi
+Says hi.
+Usage: say_hi
+end
+
+<|>say_hi
+            "#,
+            )],
+            expect![[r#"
+                Says hi.
+                Usage: say_hi
+
+                ```gdb
+                    echo hi
+                ```"#]],
+        );
+    }
+
+    #[test]
+    fn hover_built_in_command() {
+        check_hover(
+            &[("/home/user/foo.gdb", "<|>echo hi")],
+            expect![[r#"Print a constant string."#]],
+        );
+    }
+
+    #[test]
+    fn semantic_tokens() {
+        let script = r#"
+# Greets someone.
+define greet
+    if $argc == 1
+        echo "hi " $arg0 # inline
+    else
+        echo hi
+    end
+end
+document greet
+# not a comment
+end
+set $name = "you"
+greet $name
+source other.gdb
+        "#;
+        let script_path = PathBuf::from("foo.gdb");
+
+        let mut semantics = Semantics::new(PathBuf::new());
+        semantics.set_file_text(script_path.clone(), script.to_owned());
+
+        expect![[r#"
+            Comment 1:0-1:17
+            Keyword 2:0-2:6
+            Function 2:7-2:12
+            Keyword 3:4-3:6
+            Variable 3:7-3:12
+            String 4:13-4:18
+            Variable 4:19-4:24
+            Comment 4:25-4:33
+            Keyword 5:4-5:8
+            Keyword 7:4-7:7
+            Keyword 8:0-8:3
+            Keyword 9:0-9:8
+            Function 9:9-9:14
+            Keyword 11:0-11:3
+            Variable 12:4-12:9
+            String 12:12-12:17
+            Function 13:0-13:5
+            Variable 13:6-13:11
+            Keyword 14:0-14:6"#]]
+        .assert_eq(
+            &semantics
+                .semantic_tokens(&script_path)
+                .into_iter()
+                .map(|token| {
+                    format!(
+                        "{:?} {}:{}-{}:{}",
+                        token.token_type,
+                        token.range.start.line,
+                        token.range.start.column,
+                        token.range.end.line,
+                        token.range.end.column,
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+        );
+    }
+
+    fn check_diagnostics(script: &str, expect: Expect) {
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script.to_owned());
+
+            semantics
+        };
+
+        expect.assert_eq(
+            &semantics
+                .diagnostics(&script_path)
+                .into_iter()
+                .map(|diagnostic| {
+                    let mut output = format!(
+                        "{:?} {}:{}-{}:{} {}",
+                        diagnostic.severity,
+                        diagnostic.range.start.line,
+                        diagnostic.range.start.column,
+                        diagnostic.range.end.line,
+                        diagnostic.range.end.column,
+                        diagnostic.message
+                    );
+                    for related in diagnostic.related {
+                        output.push_str(&format!(
+                            "\n    related {}:{}-{}:{} {}",
+                            related.range.start.line,
+                            related.range.start.column,
+                            related.range.end.line,
+                            related.range.end.column,
+                            related.message
+                        ));
+                    }
+
+                    output
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+        );
+    }
+
+    #[test]
+    fn diagnostics_well_formed_define() {
+        check_diagnostics(
+            r#"
+define say_hi
+    echo hi
+end
+            "#,
+            expect![[r#""#]],
+        );
+    }
+
+    #[test]
+    fn diagnostics_define_unexpected_tokens() {
+        check_diagnostics(
+            r#"
+define foo bar baz
+end
+            "#,
+            expect![[r#"Error 1:11-1:18 `define` takes a single command name"#]],
+        );
+    }
+
+    #[test]
+    fn diagnostics_nested_define() {
+        check_diagnostics(
+            r#"
+define outer
+    if $argc == 1
+        define inner
+        end
+    end
+end
+define after
+end
+            "#,
+            expect![[r#"
+                Error 3:8-3:14 `define` cannot be nested within another `define`
+                    related 1:0-1:6 enclosing `define`"#]],
+        );
+    }
+
+    #[test]
+    fn diagnostics_breakpoint_commands() {
+        check_diagnostics(
+            r#"
+commands 2
+    silent
+    bt
+    silent
+end
+commands
+            "#,
+            expect![[r#"
+                Error 6:0-6:8 `commands` is missing a matching `end`
+                Hint 4:4-4:10 `silent` is not a known command"#]],
+        );
+    }
+
+    #[test]
+    fn diagnostics_stray_else() {
+        check_diagnostics(
+            r#"
+else
+define foo
+    else
+    if $argc == 1
+        echo one
+    else
+        echo other
+    end
+end
+            "#,
+            expect![[r#"
+                Error 1:0-1:4 `else` without a matching `if`
+                Error 3:4-3:8 `else` without a matching `if`"#]],
+        );
+    }
+
+    #[test]
+    fn diagnostics_abbreviated_commands() {
+        check_diagnostics(
+            r#"
+b main
+brea main
+de
+            "#,
+            expect![[r#"Hint 3:0-3:2 `de` is not a known command"#]],
+        );
+    }
+
+    #[test]
+    fn diagnostics_duplicate_define() {
+        check_diagnostics(
+            r#"
+define say_hi
+    echo hi
+end
+
+define say_hi
+    echo hello
+end
+            "#,
+            expect![[r#"
+                Warning 5:7-5:13 `say_hi` is already defined
+                    related 1:7-1:13 previous definition"#]],
+        );
+    }
+
+    #[test]
+    fn diagnostics_shebang_line() {
+        check_diagnostics(
+            "#!/usr/bin/gdb -x\ndefine say_hi\n    echo hi\nend\nsay_hi\n",
+            expect![[]],
+        );
+    }
+
+    #[test]
+    fn diagnostics_define_shadows_built_in() {
+        check_diagnostics(
+            r#"
+define break
+    echo breaking
+end
+
+define my_break
+    echo breaking
+end
+
+if $argc
+    define b
+    end
+end
+            "#,
+            expect![[r#"
+                Warning 1:7-1:12 `break` redefines a built-in command
+                Warning 10:11-10:12 `b` redefines a built-in command"#]],
+        );
+    }
+
+    #[test]
+    fn diagnostics_define_keyword() {
+        check_diagnostics(
+            r#"
+define if
+    echo hi
+end
+
+define end
+    echo bye
+end
+
+define if_set
+    echo hi
+end
+            "#,
+            expect![[r#"
+                Error 1:7-1:9 `if` is a keyword and cannot be defined
+                Error 5:7-5:10 `end` is a keyword and cannot be defined"#]],
+        );
+    }
+
+    #[test]
+    fn diagnostics_undefined_command() {
+        check_diagnostics(
+            r#"
+define say_hi
+    echo hi
+end
+
+say_hi
+echo hello
+disas
+say_bye
+            "#,
+            expect![[r#"Hint 8:0-8:7 `say_bye` is not a known command"#]],
+        );
+    }
+
+    #[test]
+    fn diagnostics_define_missing_end_at_eof() {
+        check_diagnostics(
+            r#"
+define say_hi
+    echo hi
+            "#,
+            expect![[r#"Error 1:0-1:6 `define` is missing a matching `end`"#]],
+        );
+    }
+
+    #[test]
+    fn diagnostics_if_missing_end_at_eof() {
+        check_diagnostics(
+            r#"
+if $argc == 1
+    echo one
+            "#,
+            expect![[r#"Error 1:0-1:2 `if` is missing a matching `end`"#]],
+        );
+    }
+
+    #[test]
+    fn diagnostics_while_missing_end_inside_define() {
+        check_diagnostics(
+            r#"
+define count
+    while $i < $arg0
+        set $i = $i + 1
+end
+            "#,
+            expect![[r#"Error 1:0-1:6 `define` is missing a matching `end`"#]],
+        );
+    }
+
+    #[test]
+    fn diagnostics_nested_define_missing_end() {
+        check_diagnostics(
+            r#"
+define outer
+    define inner
+        echo hi
+end
+            "#,
+            expect![[r#"
+                Error 1:0-1:6 `define` is missing a matching `end`
+                Error 2:4-2:10 `define` cannot be nested within another `define`
+                    related 1:0-1:6 enclosing `define`"#]],
+        );
+    }
+
+    #[test]
+    fn circular_source() {
+        let script_a = r#"
+source b.gdb
+
+<|>say_hi
+        "#;
+        let (script_a, location) = parse_cursor_position(script_a);
+        let script_a_path = PathBuf::from("/home/user/a.gdb");
+        let script_b = r#"source a.gdb"#;
+        let script_b_path = PathBuf::from("/home/user/b.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::from("/home/user");
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_a_path.clone(), script_a);
+            semantics.set_file_text(script_b_path.clone(), script_b.to_owned());
+
+            semantics
+        };
+
+        let item_position = CursorPosition {
+            file: &script_a_path,
+            line: location.line,
+            column: location.column,
+        };
+
+        assert!(semantics.find_definition(item_position).is_none());
+
+        // `say_hi` is never defined, which is reported separately as a hint.
+        let diagnostics = semantics
+            .diagnostics(&script_a_path)
+            .into_iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Error)
+            .collect::<Vec<Diagnostic>>();
+        assert_eq!(1, diagnostics.len());
+        assert_eq!("circular `source` of `b.gdb`", diagnostics[0].message);
+        assert_eq!(1, diagnostics[0].range.start.line);
+    }
+
+    #[test]
+    fn folding_ranges_nested_blocks() {
+        let script = r#"
+define count
+    while $i < $arg0
+        if $i == 1
+            echo one
+        end
+    end
+end
+echo done
+        "#;
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script.to_owned());
+
+            semantics
+        };
+
+        expect![[r#"
+            [
+                FoldingRange {
+                    start_line: 1,
+                    end_line: 7,
+                },
+                FoldingRange {
+                    start_line: 2,
+                    end_line: 6,
+                },
+                FoldingRange {
+                    start_line: 3,
+                    end_line: 5,
+                },
+            ]
+        "#]]
+        .assert_debug_eq(&semantics.folding_ranges(&script_path));
+    }
+
+    #[test]
+    fn folding_ranges_unterminated_if() {
+        let script = r#"
+if $argc == 1
+    echo one
+    echo two
+        "#;
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script.to_owned());
+
+            semantics
+        };
+
+        // Without an `end` the block runs to its last command.
+        expect![[r#"
+            [
+                FoldingRange {
+                    start_line: 1,
+                    end_line: 3,
+                },
+            ]
+        "#]]
+        .assert_debug_eq(&semantics.folding_ranges(&script_path));
+    }
+
+    #[test]
+    fn rename_across_files() {
+        let script_1 = r#"
+source hello.gdb
+
+<|>say_hi
+define say_hi_twice
+    say_hi
+    say_hi
+end
+        "#;
+        let (script_1, location) = parse_cursor_position(script_1);
+        let script_1_path = PathBuf::from("/home/user/foo.gdb");
+        let script_2 = r#"
+define say_hi
+    echo hi
+end
+document say_hi
+Says hi.
+end
+        "#;
+        let script_2_path = PathBuf::from("/home/user/hello.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::from("/home/user");
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_1_path.clone(), script_1);
+            semantics.set_file_text(script_2_path.clone(), script_2.to_owned());
+
+            semantics
+        };
+
+        let item_position = CursorPosition {
+            file: &script_1_path,
+            line: location.line,
+            column: location.column,
+        };
+
+        assert!(semantics.rename(item_position, "say hi!").is_none());
+
+        let workspace_edit = semantics
+            .rename(item_position, "greet")
+            .expect("should rename");
+        let mut edits = workspace_edit
+            .changes
+            .values()
+            .flatten()
+            .map(|edit| {
+                format!(
+                    "{}:{}:{}-{}:{} {}",
+                    edit.range.start.file.display(),
+                    edit.range.start.line,
+                    edit.range.start.column,
+                    edit.range.end.line,
+                    edit.range.end.column,
+                    edit.new_text
+                )
+            })
+            .collect::<Vec<String>>();
+        edits.sort();
+
+        expect![[r#"
+            /home/user/foo.gdb:3:0-3:6 greet
+            /home/user/foo.gdb:5:4-5:10 greet
+            /home/user/foo.gdb:6:4-6:10 greet
+            /home/user/hello.gdb:1:7-1:13 greet
+            /home/user/hello.gdb:4:9-4:15 greet"#]]
+        .assert_eq(&edits.join("\n"));
+    }
+
+    #[test]
+    fn prepare_rename() {
+        let script = r#"
+define say_hi
+    echo hi
+end
+
+say_hi
+        "#;
+        let script_path = PathBuf::from("/home/user/foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::from("/home/user");
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script.to_owned());
+
+            semantics
+        };
+
+        let range = semantics
+            .prepare_rename(CursorPosition {
+                file: &script_path,
+                line: 5,
+                column: 3,
+            })
+            .expect("should be renameable");
+        assert_eq!((5, 0), (range.start.line, range.start.column));
+        assert_eq!((5, 6), (range.end.line, range.end.column));
+
+        // The built in `echo` command can't be renamed.
+        assert!(semantics
+            .prepare_rename(CursorPosition {
+                file: &script_path,
+                line: 2,
+                column: 5,
+            })
+            .is_none());
+    }
+
+    #[test]
+    fn document_symbols_nested() {
+        let script = r#"
+define outer
+    if $argc == 1
+        define inner
+            echo inner
+        end
+    end
+end
+        "#;
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script.to_owned());
+
+            semantics
+        };
+
+        fn format_symbols(symbols: &[super::Symbol], depth: usize, output: &mut String) {
+            for symbol in symbols {
+                output.push_str(&format!(
+                    "{}{} {}:{}-{}:{} ({}:{}-{}:{})\n",
+                    "  ".repeat(depth),
+                    symbol.name,
+                    symbol.range.start.line,
+                    symbol.range.start.column,
+                    symbol.range.end.line,
+                    symbol.range.end.column,
+                    symbol.name_range.start.line,
+                    symbol.name_range.start.column,
+                    symbol.name_range.end.line,
+                    symbol.name_range.end.column,
+                ));
+                format_symbols(&symbol.children, depth + 1, output);
+            }
+        }
+
+        let mut output = String::new();
+        format_symbols(&semantics.document_symbols(&script_path), 0, &mut output);
+        expect![[r#"
+            outer 1:0-7:3 (1:7-1:12)
+              inner 3:8-5:11 (3:15-3:20)
+        "#]]
+        .assert_eq(&output);
+    }
+
+    #[test]
+    fn definitions_in() {
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::from("/home/user");
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(
+                PathBuf::from("/home/user/none.gdb"),
+                "echo hi\nsource one.gdb\n".to_owned(),
+            );
+            semantics.set_file_text(
+                PathBuf::from("/home/user/one.gdb"),
+                "define say_hi\n    echo hi\n    echo there\nend\n".to_owned(),
+            );
+            semantics.set_file_text(
+                PathBuf::from("/home/user/many.gdb"),
+                r#"
+define empty
+end
+if 1
+    define conditional
+    end
+end
+define outer
+    define inner
+    end
+end
+define target hookpost-run
+    echo ran
+end
+                "#
+                .to_owned(),
+            );
+
+            semantics
+        };
 
-#[derive(Copy, Clone)]
-pub struct CursorPosition<'a> {
-    pub file: &'a Path,
-    pub line: usize,
-    pub column: usize,
-}
+        let check = |file: &str, expect: Expect| {
+            expect.assert_eq(
+                &semantics
+                    .definitions_in(Path::new(file))
+                    .iter()
+                    .map(|definition| {
+                        let format_range = |range: &CursorRange| {
+                            format!(
+                                "{}:{}-{}:{}",
+                                range.start.line,
+                                range.start.column,
+                                range.end.line,
+                                range.end.column,
+                            )
+                        };
+                        format!(
+                            "{} {} ({}) body {}\n",
+                            definition.name,
+                            format_range(&definition.range),
+                            format_range(&definition.name_range),
+                            definition
+                                .body_range
+                                .as_ref()
+                                .map_or("none".to_owned(), format_range),
+                        )
+                    })
+                    .collect::<String>(),
+            )
+        };
 
-#[derive(Debug)]
-pub struct Completion {
-    pub text: String,
-}
+        check("/home/user/none.gdb", expect![[r#""#]]);
+        check(
+            "/home/user/one.gdb",
+            expect![[r#"
+            say_hi 0:0-3:3 (0:7-0:13) body 1:4-2:14
+        "#]],
+        );
+        check(
+            "/home/user/many.gdb",
+            expect![[r#"
+            empty 1:0-2:3 (1:7-1:12) body none
+            outer 7:0-10:3 (7:7-7:12) body 8:4-9:7
+            target hookpost-run 11:0-13:3 (11:7-11:26) body 12:4-12:12
+        "#]],
+        );
+        check("/home/user/unknown.gdb", expect![[r#""#]]);
+    }
 
-#[derive(Default)]
-pub struct Completions {
-    pub built_in: Vec<Completion>,
-    pub user_provided: Vec<Completion>,
-}
+    #[test]
+    fn definition_body_ranges() {
+        let script = r#"
+define one_line
+    echo hi
+end
 
-#[cfg(test)]
-mod tests {
-    use std::path::PathBuf;
+define empty
+end
+
+define missing_end
+    echo hi
+    echo bye
+"#;
+        let script_path = PathBuf::from("/home/user/foo.gdb");
+        let mut semantics = Semantics::new(PathBuf::from("/home/user"));
+        semantics.set_file_text(script_path.clone(), script.to_owned());
+
+        let body_ranges = semantics
+            .definitions_in(&script_path)
+            .iter()
+            .map(|definition| match &definition.body_range {
+                Some(range) => format!(
+                    "{} {}:{}-{}:{}",
+                    definition.name,
+                    range.start.line,
+                    range.start.column,
+                    range.end.line,
+                    range.end.column
+                ),
+                None => format!("{} none", definition.name),
+            })
+            .collect::<Vec<String>>();
+
+        expect![[r#"
+            one_line 2:4-2:11
+            empty none
+            missing_end 9:4-10:12"#]]
+        .assert_eq(&body_ranges.join("\n"));
+    }
+
+    #[test]
+    fn command_tree() {
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::from("/home/user");
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(
+                PathBuf::from("/home/user/script.gdb"),
+                r#"# setup
+source other.gdb
+define greet # says hi
+    if $argc == 1
+        echo hi
+    else
+        # nobody to greet
+        echo bye
+    end
+end
+while $i < 3
+    set $i = $i + 1
+end
+python
+# not a gdb comment
+end
+print 1 # trailing
+"#
+                .to_owned(),
+            );
+
+            semantics
+        };
+
+        fn format_nodes(nodes: &[CommandNode], depth: usize, out: &mut String) {
+            for node in nodes {
+                out.push_str(&format!(
+                    "{}{:?} {} {}:{}-{}:{}\n",
+                    "    ".repeat(depth),
+                    node.kind,
+                    node.name,
+                    node.range.start.line,
+                    node.range.start.column,
+                    node.range.end.line,
+                    node.range.end.column,
+                ));
+                format_nodes(&node.children, depth + 1, out);
+            }
+        }
+
+        let mut actual = String::new();
+        format_nodes(
+            &semantics.command_tree(Path::new("/home/user/script.gdb")),
+            0,
+            &mut actual,
+        );
+        expect![[r##"
+            Comment # setup 0:0-0:7
+            Source source 1:0-1:16
+            Define define 2:0-9:3
+                Comment # says hi 2:13-2:22
+                If if 3:4-8:7
+                    Other echo 4:8-4:15
+                    Comment # nobody to greet 6:8-6:25
+                    Other echo 7:8-7:16
+            While while 10:0-12:3
+                Other set 11:4-11:19
+            Other python 13:0-15:3
+            Other print 16:0-16:7
+            Comment # trailing 16:8-16:18
+        "##]]
+        .assert_eq(&actual);
+        expect![[r#"
+            "while $i < 3\n    set $i = $i + 1\nend"
+        "#]]
+        .assert_debug_eq(&semantics.command_tree(Path::new("/home/user/script.gdb"))[3].text);
+        assert!(semantics
+            .command_tree(Path::new("/home/user/unknown.gdb"))
+            .is_empty());
+    }
+
+    #[test]
+    fn workspace_symbols() {
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::from("/home/user");
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(
+                PathBuf::from("/home/user/a.gdb"),
+                "define say_hi\n    echo hi\nend\n".to_owned(),
+            );
+            semantics.set_file_text(
+                PathBuf::from("/home/user/b.gdb"),
+                "define Say_Bye\nend\ndefine print_regs\nend\n".to_owned(),
+            );
+            semantics.set_file_text(
+                PathBuf::from("/home/user/c.gdb"),
+                "define outer\n    define say_more\n    end\nend\n".to_owned(),
+            );
+
+            semantics
+        };
+
+        let check = |query: &str, expect: Expect| {
+            expect.assert_eq(
+                &semantics
+                    .workspace_symbols(query)
+                    .iter()
+                    .map(|symbol| {
+                        format!(
+                            "{} {}:{}:{}",
+                            symbol.name,
+                            symbol.name_range.start.file.display(),
+                            symbol.name_range.start.line,
+                            symbol.name_range.start.column,
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+            )
+        };
+
+        check(
+            "SAY",
+            expect![[r#"
+            say_hi /home/user/a.gdb:0:7
+            Say_Bye /home/user/b.gdb:0:7
+            say_more /home/user/c.gdb:1:11"#]],
+        );
+        check(
+            "",
+            expect![[r#"
+            say_hi /home/user/a.gdb:0:7
+            Say_Bye /home/user/b.gdb:0:7
+            print_regs /home/user/b.gdb:2:7
+            outer /home/user/c.gdb:0:7
+            say_more /home/user/c.gdb:1:11"#]],
+        );
+    }
+
+    #[test]
+    fn find_definition_argument_after_non_ascii() {
+        let script = r#"
+define greet
+    echo "👋 héllo" <|>$arg0
+end
+        "#;
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script);
+
+            semantics
+        };
+
+        let definition = semantics
+            .find_definition(CursorPosition {
+                file: &script_path,
+                line: location.line,
+                column: location.column,
+            })
+            .expect("should find definition");
+
+        assert_eq!(1, definition.line);
+        assert_eq!(0, definition.column);
+    }
+
+    #[test]
+    fn completions_variables_after_non_ascii() {
+        check_completions_user_provided(
+            r#"
+set $é_count = 0
+echo "😀" $<|>
+            "#,
+            expect![[r#"$é_count"#]],
+        );
+    }
+
+    #[test]
+    fn signature_help() {
+        let script = r#"
+define greet
+    if $argc == 2
+        printf "%s, %s\n", $arg0, $arg1
+    end
+end
+
+greet hello <|>
+        "#;
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script);
+
+            semantics
+        };
+
+        let signature_help = semantics
+            .signature_help(CursorPosition {
+                file: &script_path,
+                line: location.line,
+                column: location.column,
+            })
+            .expect("should find signature help");
+
+        assert_eq!("greet arg0 arg1 ...", signature_help.label);
+        assert_eq!(vec!["arg0", "arg1"], signature_help.parameters);
+        assert_eq!(Some(1), signature_help.active_parameter);
+
+        // There is no signature help while still typing the command name.
+        assert!(semantics
+            .signature_help(CursorPosition {
+                file: &script_path,
+                line: location.line,
+                column: 2,
+            })
+            .is_none());
+    }
+
+    #[test]
+    fn source_path_expansion() {
+        let script = r#"
+source ~/scripts/foo.gdb
+source ${SCRIPTS}/bar.gdb
+source $SCRIPTS/baz.gdb
+source $UNSET/qux.gdb
+
+<|>say_hi
+        "#;
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("/home/user/.gdbinit");
+        let foo_path = PathBuf::from("/home/user/scripts/foo.gdb");
+
+        let mut semantics = Semantics::new(PathBuf::from("/home/user/project"));
+        semantics.environment = vec![
+            ("HOME".to_owned(), "/home/user".to_owned()),
+            ("SCRIPTS".to_owned(), "/opt/scripts".to_owned()),
+        ]
+        .into_iter()
+        .collect();
+
+        semantics.set_file_text(foo_path.clone(), "define say_hi\nend\n".to_owned());
+        let unresolved_paths = semantics.set_file_text(script_path.clone(), script);
+
+        assert_eq!(
+            vec![
+                Path::new("/opt/scripts/bar.gdb"),
+                Path::new("/opt/scripts/baz.gdb"),
+                Path::new("$UNSET/qux.gdb"),
+            ],
+            unresolved_paths.paths().collect::<Vec<&Path>>()
+        );
+
+        let definition = semantics
+            .find_definition(CursorPosition {
+                file: &script_path,
+                line: location.line,
+                column: location.column,
+            })
+            .expect("should find definition");
+        assert_eq!(foo_path, definition.file);
+    }
+
+    #[test]
+    fn find_definition_variable() {
+        let script = r#"
+set $counter = 0
+while $counter < 3
+    set var $counter=$counter + 1
+end
+echo <|>$counter
+        "#;
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let fake_cwd: PathBuf = PathBuf::new();
+            let mut semantics = Semantics::new(fake_cwd);
+            semantics.set_file_text(script_path.clone(), script);
+
+            semantics
+        };
+
+        let definition = semantics
+            .find_definition(CursorPosition {
+                file: &script_path,
+                line: location.line,
+                column: location.column,
+            })
+            .expect("should find definition");
+        assert_eq!(script_path, definition.file);
+        assert_eq!(3, definition.line);
+        assert_eq!(12, definition.column);
+
+        // The usage within the loop condition comes before the assignment in the
+        // loop body.
+        let definition = semantics
+            .find_definition(CursorPosition {
+                file: &script_path,
+                line: 2,
+                column: 7,
+            })
+            .expect("should find definition");
+        assert_eq!(1, definition.line);
+        assert_eq!(4, definition.column);
+    }
+
+    #[test]
+    fn builder_defaults_match_new() {
+        let semantics = Semantics::new(PathBuf::from("/home/user"));
+        let built = SemanticsBuilder::new(PathBuf::from("/home/user")).build();
+
+        assert_eq!(semantics.project_root, built.project_root);
+        assert_eq!(PositionEncoding::Utf16, built.position_encoding);
+        assert_eq!(semantics.position_encoding, built.position_encoding);
+        assert!(built.follow_sources);
+        assert_eq!(semantics.follow_sources, built.follow_sources);
+    }
+
+    #[test]
+    fn builder_utf8_position_encoding() {
+        let script = r#"
+define greet
+    echo "😀" $arg0
+end
+        "#;
+        let script_path = PathBuf::from("foo.gdb");
+
+        let semantics = {
+            let mut semantics = SemanticsBuilder::new(PathBuf::new())
+                .position_encoding(PositionEncoding::Utf8)
+                .build();
+            semantics.set_file_text(script_path.clone(), script.to_owned());
+
+            semantics
+        };
+
+        // The emoji is four bytes of UTF-8, but two UTF-16 code units, so `$arg0`
+        // starts at byte 16 rather than code unit 14.
+        let position = CursorPosition {
+            file: &script_path,
+            line: 2,
+            column: 16,
+        };
+        let hover = semantics.hover(position).expect("should hover");
+        assert_eq!("Argument 0 passed to `greet`", hover.contents);
+    }
+
+    #[test]
+    fn builder_without_following_sources() {
+        let script_1 = r#"
+source hello.gdb
 
-    use expect_test::{expect, Expect};
+<|>say_hi
+        "#;
+        let (script_1, location) = parse_cursor_position(script_1);
+        let script_1_path = PathBuf::from("/home/user/foo.gdb");
+        let script_2_path = PathBuf::from("/home/user/hello.gdb");
 
-    use crate::test_support::parse_cursor_position;
+        let mut semantics = SemanticsBuilder::new(PathBuf::from("/home/user"))
+            .follow_sources(false)
+            .build();
+        semantics.set_file_text(script_2_path, "define say_hi\nend\n".to_owned());
+        let unresolved_paths = semantics.set_file_text(script_1_path.clone(), script_1);
 
-    use super::{CursorPosition, Semantics};
+        assert!(unresolved_paths.is_empty());
+        assert!(semantics
+            .find_definition(CursorPosition {
+                file: &script_1_path,
+                line: location.line,
+                column: location.column,
+            })
+            .is_none());
+    }
 
     #[test]
-    fn find_definition_simple() {
+    fn extra_commands_are_known() {
         let script = r#"
-define say_hi
-    echo hi
-end
-
-<|>say_hi
+telescope $sp 10
+pwndbg
+say_bye
+te<|>
         "#;
         let (script, location) = parse_cursor_position(script);
-        let script_path = PathBuf::from("foo.gdb");
+        let script_path = PathBuf::from("/home/user/foo.gdb");
 
-        let semantics = {
-            let fake_cwd: PathBuf = PathBuf::new();
-            let mut semantics = Semantics::new(fake_cwd);
-            semantics.set_file_text(script_path.clone(), script);
+        let mut semantics = SemanticsBuilder::new(PathBuf::from("/home/user"))
+            .extra_commands(vec!["telescope".to_owned(), "pwndbg".to_owned()])
+            .build();
+        semantics.set_file_text(script_path.clone(), script);
 
-            semantics
-        };
+        let diagnostics = semantics
+            .diagnostics(&script_path)
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect::<Vec<String>>();
+        expect![[r#"`say_bye` is not a known command"#]].assert_eq(&diagnostics.join("\n"));
 
-        let item_position = CursorPosition {
+        let completions = semantics.find_completions(CursorPosition {
             file: &script_path,
             line: location.line,
             column: location.column,
-        };
-
-        let definition = semantics
-            .find_definition(item_position)
-            .expect("should find definition");
-
-        assert_eq!(script_path, definition.file);
-        assert_eq!(1, definition.line);
-        assert_eq!(7, definition.column);
+        });
+        let built_in = completions
+            .built_in
+            .into_iter()
+            .map(|completion| completion.text)
+            .collect::<Vec<String>>();
+        expect![[r#"telescope"#]].assert_eq(&built_in.join("\n"));
     }
 
     #[test]
-    fn find_definition_returns_none_if_def_is_after_identifier() {
-        let script = r#"
-<|>say_hi
+    fn remove_sourced_file() {
+        let script_1 = r#"
+source hello.gdb
 
-define say_hi
-    echo hi
-end
+<|>say_hi
         "#;
-        let (script, location) = parse_cursor_position(script);
-        let script_path = PathBuf::from("foo.gdb");
-
-        let semantics = {
-            let fake_cwd: PathBuf = PathBuf::new();
-            let mut semantics = Semantics::new(fake_cwd);
-            semantics.set_file_text(script_path.clone(), script);
+        let (script_1, location) = parse_cursor_position(script_1);
+        let script_1_path = PathBuf::from("/home/user/foo.gdb");
+        let script_2_path = PathBuf::from("/home/user/hello.gdb");
 
-            semantics
-        };
+        let mut semantics = Semantics::new(PathBuf::from("/home/user"));
+        semantics.set_file_text(script_2_path.clone(), "define say_hi\nend\n".to_owned());
+        semantics.set_file_text(script_1_path.clone(), script_1);
 
         let item_position = CursorPosition {
-            file: &script_path,
+            file: &script_1_path,
             line: location.line,
             column: location.column,
         };
+        assert!(semantics.find_definition(item_position).is_some());
 
-        let definition = semantics.find_definition(item_position);
+        semantics.remove_file(&script_2_path);
 
-        assert!(definition.is_none());
+        assert!(!semantics.has_file(&script_2_path));
+        assert!(semantics.find_definition(item_position).is_none());
+        assert_eq!(1, semantics.find_references(item_position).len());
+        assert!(semantics.diagnostics(&script_2_path).is_empty());
     }
 
     #[test]
-    fn find_definition_returns_most_recent_definition() {
-        let script = r#"
-define say_hi
-    echo hi
-end
+    fn inlay_hints_resolved_source_paths() {
+        let script =
+            "source ../common.gdb\nsource -v /opt/gdb/init.gdb\n\nsource lib/é.gdb # utf-8\n";
+        let script_path = PathBuf::from("/home/user/scripts/main.gdb");
+        let mut semantics = Semantics::new(PathBuf::from("/home/user"));
+        semantics.set_file_text(script_path.clone(), script.to_owned());
 
-define say_hi
-    echo hi!!!
-end
+        let hints = |start_line, end_line| {
+            semantics.inlay_hints(CursorRange {
+                start: CursorPosition {
+                    file: &script_path,
+                    line: start_line,
+                    column: 0,
+                },
+                end: CursorPosition {
+                    file: &script_path,
+                    line: end_line,
+                    column: 0,
+                },
+            })
+        };
+        let format_hints = |hints: Vec<InlayHint>| {
+            hints
+                .into_iter()
+                .map(|hint| {
+                    format!(
+                        "{}:{} {}",
+                        hint.position.line, hint.position.column, hint.label
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n")
+        };
 
-<|>say_hi
-        "#;
+        expect![[r#"
+            0:20 /home/user/scripts/../common.gdb
+            1:27 /opt/gdb/init.gdb
+            3:16 /home/user/scripts/lib/é.gdb"#]]
+        .assert_eq(&format_hints(hints(0, 3)));
+        expect![[r#"1:27 /opt/gdb/init.gdb"#]].assert_eq(&format_hints(hints(1, 2)));
+    }
+
+    #[test]
+    fn code_action_inserts_missing_end() {
+        let script = "<|>define say_hi\n  if $argc\n    echo hi # greet\n  end\n";
         let (script, location) = parse_cursor_position(script);
         let script_path = PathBuf::from("foo.gdb");
+        let mut semantics = Semantics::new(PathBuf::new());
+        semantics.set_file_text(script_path.clone(), script);
 
-        let semantics = {
-            let fake_cwd: PathBuf = PathBuf::new();
-            let mut semantics = Semantics::new(fake_cwd);
-            semantics.set_file_text(script_path.clone(), script);
+        let cursor_position = CursorPosition {
+            file: &script_path,
+            line: location.line,
+            column: location.column,
+        };
+        let code_actions = semantics.code_actions(CursorRange {
+            start: cursor_position,
+            end: cursor_position,
+        });
 
-            semantics
+        let edits = code_actions
+            .iter()
+            .flat_map(|code_action| {
+                code_action.edit.changes[script_path.as_path()]
+                    .iter()
+                    .map(move |edit| {
+                        format!(
+                            "{} {}:{}-{}:{} {:?}",
+                            code_action.title,
+                            edit.range.start.line,
+                            edit.range.start.column,
+                            edit.range.end.line,
+                            edit.range.end.column,
+                            edit.new_text
+                        )
+                    })
+            })
+            .collect::<Vec<String>>();
+        expect![[r#"Insert `end` 3:5-3:5 "\nend""#]].assert_eq(&edits.join("\n"));
+    }
+
+    #[test]
+    fn code_action_for_block_around_terminated_block() {
+        let script = "define say_hi\n  while 1\n    <|>echo hi\nend\necho done\n";
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("foo.gdb");
+        let mut semantics = Semantics::new(PathBuf::new());
+        semantics.set_file_text(script_path.clone(), script);
+
+        let cursor_position = CursorPosition {
+            file: &script_path,
+            line: location.line,
+            column: location.column,
         };
+        let code_actions = semantics.code_actions(CursorRange {
+            start: cursor_position,
+            end: cursor_position,
+        });
 
-        let item_position = CursorPosition {
+        let edits = code_actions
+            .iter()
+            .flat_map(|code_action| code_action.edit.changes[script_path.as_path()].iter())
+            .map(|edit| {
+                format!(
+                    "{}:{} {:?}",
+                    edit.range.start.line, edit.range.start.column, edit.new_text
+                )
+            })
+            .collect::<Vec<String>>();
+        expect![[r#"4:9 "\nend""#]].assert_eq(&edits.join("\n"));
+    }
+
+    #[test]
+    fn code_action_converts_tabs_to_spaces() {
+        let script = "echo\tstart\ndefine say_hi\n\tif $argc\n\t\techo\thi\n  \tend\n<|>end\n";
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("foo.gdb");
+        let mut semantics = Semantics::new(PathBuf::new());
+        semantics.set_file_text(script_path.clone(), script.clone());
+
+        let cursor_position = CursorPosition {
             file: &script_path,
             line: location.line,
             column: location.column,
         };
+        let code_actions = semantics.code_actions(CursorRange {
+            start: cursor_position,
+            end: cursor_position,
+        });
+        assert_eq!(1, code_actions.len());
+        assert_eq!("Convert tabs to spaces", code_actions[0].title);
 
-        let definition = semantics
-            .find_definition(item_position)
-            .expect("should find definition");
+        // The edits don't overlap, so applying them from the end keeps the
+        // earlier ranges valid.
+        let mut edits = code_actions[0].edit.changes[script_path.as_path()]
+            .iter()
+            .collect::<Vec<&TextEdit>>();
+        edits.sort_by_key(|edit| (edit.range.start.line, edit.range.start.column));
+        let mut lines = script.lines().map(str::to_owned).collect::<Vec<String>>();
+        for edit in edits.into_iter().rev() {
+            assert_eq!(edit.range.start.line, edit.range.end.line);
+            lines[edit.range.start.line].replace_range(
+                edit.range.start.column..edit.range.end.column,
+                &edit.new_text,
+            );
+        }
+        let converted = lines.join("\n") + "\n";
 
-        assert_eq!(script_path, definition.file);
-        assert_eq!(5, definition.line);
-        assert_eq!(7, definition.column);
+        expect![[r#"
+            echo	start
+            define say_hi
+                if $argc
+                    echo	hi
+                  end
+            end
+        "#]]
+        .assert_eq(&converted);
+        // Only indentation changes, so each command keeps its tokens and line.
+        fn words(text: &str) -> Vec<Vec<&str>> {
+            text.lines()
+                .map(|line| line.split_whitespace().collect())
+                .collect()
+        }
+        assert_eq!(words(&script), words(&converted));
     }
 
     #[test]
-    fn find_definition_from_other_file() {
+    fn code_action_tab_width_is_configurable() {
+        let script_path = PathBuf::from("foo.gdb");
+        let mut semantics = SemanticsBuilder::new(PathBuf::new()).tab_width(2).build();
+        semantics.set_file_text(
+            script_path.clone(),
+            "define say_hi\n\t\techo hi\nend\n".to_owned(),
+        );
+
+        let cursor_position = CursorPosition {
+            file: &script_path,
+            line: 1,
+            column: 0,
+        };
+        let code_actions = semantics.code_actions(CursorRange {
+            start: cursor_position,
+            end: cursor_position,
+        });
+        let edits = &code_actions[0].edit.changes[script_path.as_path()];
+        assert_eq!(1, edits.len());
+        assert_eq!("    ", edits[0].new_text);
+    }
+
+    #[test]
+    fn rename_sourced_file() {
         let script_1 = r#"
 source hello.gdb
 
@@ -357,77 +6025,140 @@ source hello.gdb
         "#;
         let (script_1, location) = parse_cursor_position(script_1);
         let script_1_path = PathBuf::from("/home/user/foo.gdb");
-        let script_2 = r#"
-define say_hi
-    echo hi
-end
-        "#;
         let script_2_path = PathBuf::from("/home/user/hello.gdb");
+        let script_3_path = PathBuf::from("/other/bar.gdb");
+        let renamed_path = PathBuf::from("/home/user/lib/hello.gdb");
 
-        let semantics = {
-            // We use a non-empty CWD here to show that path canonicalization
-            // works.
-            let fake_cwd: PathBuf = PathBuf::from("/home/user");
-            let mut semantics = Semantics::new(fake_cwd);
-            let unresolved_imports = semantics.set_file_text(script_1_path.clone(), script_1);
-            assert_eq!(1, unresolved_imports.len());
-            assert_eq!(&script_2_path, unresolved_imports.get(0).unwrap());
-
-            semantics.set_file_text(script_2_path.clone(), script_2.to_owned());
+        let mut semantics = Semantics::new(PathBuf::from("/home/user"));
+        semantics.set_file_text(script_2_path.clone(), "define say_hi\nend\n".to_owned());
+        semantics.set_file_text(script_1_path.clone(), script_1.clone());
+        semantics.set_file_text(
+            script_3_path,
+            "source /home/user/hello.gdb\nsource foo.gdb\n".to_owned(),
+        );
 
-            semantics
-        };
+        let workspace_edit = semantics.rename_file(&script_2_path, &renamed_path);
+        let mut edits = workspace_edit
+            .changes
+            .values()
+            .flatten()
+            .map(|edit| {
+                format!(
+                    "{}:{}:{}-{}:{} {}",
+                    edit.range.start.file.display(),
+                    edit.range.start.line,
+                    edit.range.start.column,
+                    edit.range.end.line,
+                    edit.range.end.column,
+                    edit.new_text
+                )
+            })
+            .collect::<Vec<String>>();
+        edits.sort();
+        expect![[r#"
+            /home/user/foo.gdb:1:7-1:16 lib/hello.gdb
+            /other/bar.gdb:0:7-0:27 /home/user/lib/hello.gdb"#]]
+        .assert_eq(&edits.join("\n"));
 
-        let item_position = CursorPosition {
-            file: &script_1_path,
-            line: location.line,
-            column: location.column,
-        };
+        assert!(!semantics.has_file(&script_2_path));
+        assert!(semantics.has_file(&renamed_path));
 
+        // Once the edit is applied, definitions resolve to the renamed file.
+        semantics.set_file_text(
+            script_1_path.clone(),
+            script_1.replace("source hello.gdb", "source lib/hello.gdb"),
+        );
         let definition = semantics
-            .find_definition(item_position)
+            .find_definition(CursorPosition {
+                file: &script_1_path,
+                line: location.line,
+                column: location.column,
+            })
             .expect("should find definition");
-
-        assert_eq!(script_2_path, definition.file);
-        assert_eq!(1, definition.line);
-        assert_eq!(7, definition.column);
+        assert_eq!(renamed_path, definition.file);
+        // The edit to `bar.gdb` hasn't been applied, so it still refers to the old path.
+        let unresolved = semantics.unresolved_imports();
+        assert_eq!(1, unresolved.len());
+        assert_eq!(script_2_path, unresolved[0].path);
     }
 
     #[test]
-    fn set_file_text_requests_unresolved_imports() {
-        let script_1 = r#"source bar.gdb"#;
-        let script_1_path = PathBuf::from("foo.gdb");
-
-        let script_2 = r#"echo hi from bar"#;
-        let script_2_path = PathBuf::from("bar.gdb");
+    fn unresolved_paths_include_source_position() {
+        let script = r#"
+source foo.gdb
+    source bar.gdb
+        "#;
+        let script_path = PathBuf::from("/home/user/main.gdb");
 
-        let script_3 = r#"source bar.gdb"#;
-        let script_3_path = PathBuf::from("baz.gdb");
+        let mut semantics = Semantics::new(PathBuf::from("/home/user"));
+        semantics.set_file_text(PathBuf::from("/home/user/foo.gdb"), String::new());
+        let unresolved_paths = semantics.set_file_text(script_path.clone(), script.to_owned());
 
-        let mut semantics = {
-            let fake_cwd: PathBuf = PathBuf::new();
-            let semantics = Semantics::new(fake_cwd);
+        assert_eq!(1, unresolved_paths.paths.len());
+        let unresolved = &unresolved_paths.paths[0];
+        assert_eq!(Path::new("/home/user/bar.gdb"), unresolved.path);
+        assert_eq!(
+            CursorPosition {
+                file: &script_path,
+                line: 2,
+                column: 4,
+            },
+            unresolved.source
+        );
+    }
 
-            semantics
-        };
+    #[test]
+    fn unresolved_imports_across_files() {
+        let main_path = PathBuf::from("/home/user/main.gdb");
+        let nested_path = PathBuf::from("/home/user/scripts/nested.gdb");
 
-        let unresolved_imports =
-            semantics.set_file_text(script_1_path.clone(), script_1.to_owned());
-        assert_eq!(1, unresolved_imports.len());
-        assert_eq!(&script_2_path, unresolved_imports.get(0).unwrap());
+        let mut semantics = Semantics::new(PathBuf::from("/home/user"));
+        semantics.set_file_text(
+            main_path.clone(),
+            "source scripts/nested.gdb\nsource missing.gdb\n".to_owned(),
+        );
+        semantics.set_file_text(nested_path.clone(), "source helpers.gdb\n".to_owned());
 
-        let unresolved_imports =
-            semantics.set_file_text(script_2_path.clone(), script_2.to_owned());
-        assert!(unresolved_imports.is_empty());
+        let unresolved = semantics.unresolved_imports();
+        assert_eq!(
+            vec![
+                (
+                    Path::new("/home/user/main.gdb"),
+                    Path::new("/home/user/missing.gdb"),
+                    1
+                ),
+                (
+                    Path::new("/home/user/scripts/nested.gdb"),
+                    Path::new("/home/user/scripts/helpers.gdb"),
+                    0
+                ),
+            ],
+            unresolved
+                .iter()
+                .map(|unresolved| (
+                    unresolved.source.file,
+                    unresolved.path.as_path(),
+                    unresolved.source.line
+                ))
+                .collect::<Vec<(&Path, &Path, usize)>>()
+        );
 
-        let unresolved_imports =
-            semantics.set_file_text(script_3_path.clone(), script_3.to_owned());
-        assert!(unresolved_imports.is_empty());
+        semantics.set_file_text(PathBuf::from("/home/user/missing.gdb"), String::new());
+        assert_eq!(1, semantics.unresolved_imports().len());
     }
 
-    fn check_completions_user_provided(script: &str, expect_parse: Expect) {
-        let (script, location) = parse_cursor_position(script);
-
+    #[test]
+    fn command_documentation() {
+        let script = r#"
+define greet
+    echo hello
+end
+document greet
+Greets the user.
+end
+define undocumented
+end
+        "#;
         let script_path = PathBuf::from("foo.gdb");
 
         let semantics = {
@@ -438,40 +6169,131 @@ end
             semantics
         };
 
-        let cursor_position = CursorPosition {
+        assert_eq!(
+            Some("Greets the user.".to_owned()),
+            semantics.command_documentation(&script_path, "greet")
+        );
+        assert_eq!(
+            None,
+            semantics.command_documentation(&script_path, "undocumented")
+        );
+        assert!(semantics
+            .command_documentation(&script_path, "echo")
+            .is_some());
+        assert_eq!(
+            None,
+            semantics.command_documentation(&script_path, "not_a_command")
+        );
+    }
+
+    #[test]
+    fn diagnostics_unresolved_source() {
+        check_diagnostics(
+            r#"
+source -v missing.gdb
+            "#,
+            expect![[r#"Warning 1:0-1:21 cannot find `missing.gdb`"#]],
+        );
+    }
+
+    #[test]
+    fn queries_on_backslash_at_end_of_file() {
+        let script_path = PathBuf::from("/home/user/foo.gdb");
+        for script in &["echo hi \\", "echo hi \\\\", "define say_hi\n  echo hi\\"] {
+            let mut semantics = Semantics::new(PathBuf::from("/home/user"));
+            semantics.set_file_text(script_path.clone(), (*script).to_owned());
+            semantics.diagnostics(&script_path);
+            semantics.semantic_tokens(&script_path);
+            semantics.tokens(&script_path);
+            semantics.folding_ranges(&script_path);
+
+            let last_line = script.lines().last().unwrap();
+            for column in 0..=last_line.len() + 1 {
+                let cursor_position = CursorPosition {
+                    file: &script_path,
+                    line: script.lines().count() - 1,
+                    column,
+                };
+                semantics.find_completions(cursor_position);
+                semantics.find_definition(cursor_position);
+                semantics.hover(cursor_position);
+                semantics.signature_help(cursor_position);
+                semantics.token_at(cursor_position);
+            }
+        }
+    }
+
+    #[test]
+    fn queries_reuse_parsed_commands() {
+        let script = r#"
+define say_hi
+  echo hi
+end
+
+<|>say_hi
+        "#;
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("/home/user/foo.gdb");
+
+        let mut semantics = Semantics::new(PathBuf::from("/home/user"));
+        semantics.set_file_text(script_path.clone(), script);
+        let parsed_file = Arc::clone(&semantics.files[&script_path]);
+
+        let item_position = CursorPosition {
             file: &script_path,
             line: location.line,
             column: location.column,
         };
+        assert!(semantics.find_definition(item_position).is_some());
+        assert!(semantics.hover(item_position).is_some());
+        semantics.find_completions(item_position);
+        semantics.find_references(item_position);
+        semantics.diagnostics(&script_path);
+        semantics.document_symbols(&script_path);
+        semantics.folding_ranges(&script_path);
 
-        let completions = semantics.find_completions(cursor_position);
-
-        expect_parse.assert_eq(
-            &completions
-                .user_provided
-                .into_iter()
-                .map(|completion| completion.text)
-                .collect::<Vec<String>>()
-                .join("\n"),
-        );
+        // The file parsed when its text was set is still the one queries use.
+        assert!(Arc::ptr_eq(&parsed_file, &semantics.files[&script_path]));
     }
 
     #[test]
-    fn completions_user_provided_empty_script() {
-        check_completions_user_provided("<|>", expect![[r#""#]]);
-    }
+    fn cloned_semantics_is_an_independent_snapshot() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Semantics>();
 
-    #[test]
-    fn completions_user_provided_cursor_below_single_define() {
-        check_completions_user_provided(
-            r#"
-        define say_hi
-            echo hi
-        end
+        let script = r#"
+define say_hi
+  echo hi
+end
 
-        <|>
-            "#,
-            expect![[r#"say_hi"#]],
-        );
+<|>say_hi
+        "#;
+        let (script, location) = parse_cursor_position(script);
+        let script_path = PathBuf::from("/home/user/foo.gdb");
+
+        let mut semantics = Semantics::new(PathBuf::from("/home/user"));
+        semantics.set_file_text(script_path.clone(), script);
+        let snapshot = semantics.clone();
+
+        // Changing the original file doesn't affect the snapshot.
+        semantics.set_file_text(script_path.clone(), "say_hi\n".to_owned());
+        assert_eq!(1, semantics.diagnostics(&script_path).len());
+
+        let (definition, diagnostics) = std::thread::spawn(move || {
+            let item_position = CursorPosition {
+                file: &script_path,
+                line: location.line,
+                column: location.column,
+            };
+            let definition = snapshot
+                .find_definition(item_position)
+                .map(|definition| (definition.line, definition.column));
+            (definition, snapshot.diagnostics(&script_path).len())
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(Some((1, 7)), definition);
+        assert_eq!(0, diagnostics);
     }
 }
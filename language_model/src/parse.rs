@@ -1,12 +1,14 @@
-use crate::CursorPosition;
+use std::collections::VecDeque;
+
+use crate::{built_ins, CursorPosition, PositionEncoding};
 
 pub mod iters;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct Token<'a> {
     pub text: &'a str,
     /// Location of the start of this token in the file. Tokens cannot contain
-    /// newlines, so to find the end you can add the text length to the column.
+    /// newlines, so to find the end you can add the text width to the column.
     pub location_in_file: Location,
 }
 
@@ -16,11 +18,95 @@ impl<'a> Token<'a> {
 
         location_to_check.line == self.location_in_file.line
             && location_to_check.column >= self.location_in_file.column
-            && location_to_check.column < self.location_in_file.column + self.text.len()
+            && location_to_check.column < self.location_in_file.column + utf16_len(self.text)
+    }
+
+    /// The location just past the last character of this token. Most tokens
+    /// are on a single line, but the command of a shell escape may span escaped
+    /// newlines.
+    pub(crate) fn end_location(&self) -> Location {
+        match self.text.rfind('\n') {
+            Some(index) => Location {
+                line: self.location_in_file.line + self.text.matches('\n').count(),
+                column: utf16_len(&self.text[index + 1..]),
+            },
+            None => Location {
+                line: self.location_in_file.line,
+                column: self.location_in_file.column + utf16_len(self.text),
+            },
+        }
+    }
+}
+
+/// Returns the width of the given text in UTF-16 code units, which is the unit
+/// columns are measured in. This matches the position encoding used by LSP.
+pub(crate) fn utf16_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Returns the byte offset into the given text of the given column, measured in
+/// UTF-16 code units. Columns past the end of the text map to its length.
+pub(crate) fn byte_offset(text: &str, column: usize) -> usize {
+    let mut width = 0;
+    for (index, character) in text.char_indices() {
+        if width >= column {
+            return index;
+        }
+        width += character.len_utf16();
+    }
+
+    text.len()
+}
+
+/// Returns the byte offset into the given source of the given location, with
+/// the column measured in the given encoding. Locations past the end of a line
+/// map to the end of that line, before its line ending, and locations past the
+/// end of the source map to its end. A column within a character maps to the
+/// start of the next character.
+pub(crate) fn offset_of(
+    source: &str,
+    location: Location,
+    position_encoding: PositionEncoding,
+) -> usize {
+    let mut line_start = 0;
+    for _ in 0..location.line {
+        match source[line_start..].find('\n') {
+            Some(newline) => line_start += newline + 1,
+            None => return source.len(),
+        }
+    }
+    let line = source[line_start..].split('\n').next().unwrap_or("");
+    let line = line.strip_suffix('\r').unwrap_or(line);
+
+    let mut width = 0;
+    for (index, character) in line.char_indices() {
+        if width >= location.column {
+            return line_start + index;
+        }
+        width += position_encoding.char_width(character);
+    }
+
+    line_start + line.len()
+}
+
+/// Returns the location of the given byte offset into the given source, or
+/// `None` if it is past the end of the source, within a character, or between
+/// the `\r` and `\n` of a line ending. For locations within the source, this is
+/// the inverse of `offset_of` with UTF-16 columns.
+pub(crate) fn location_of(source: &str, offset: usize) -> Option<Location> {
+    let before = source.get(..offset)?;
+    if before.ends_with('\r') && source[offset..].starts_with('\n') {
+        return None;
     }
+    let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+
+    Some(Location {
+        line: before.matches('\n').count(),
+        column: utf16_len(&before[line_start..]),
+    })
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub(crate) struct Location {
     pub line: usize,
     pub column: usize,
@@ -37,29 +123,125 @@ impl<'a> From<CursorPosition<'a>> for Location {
 
 /// Represents a single GDB command line, which is one or more
 /// lines in the script file.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct CommandLine<'a> {
     text: &'a str,
     /// The line in the file where this command line starts.
     pub start_line_in_file: usize,
-    // TODO maybe add num_lines?
+    /// The number of lines in the file this command line spans, which is more
+    /// than one if it uses escaped newlines.
+    pub num_lines: usize,
+}
+
+impl<'a> CommandLine<'a> {
+    pub(crate) fn text(&self) -> &'a str {
+        self.text
+    }
+
+    /// The last line in the file which is part of this command line.
+    pub(crate) fn end_line(&self) -> usize {
+        self.start_line_in_file + self.num_lines - 1
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) enum Command<'a> {
     Define {
         define: Token<'a>,
         // TODO how to note something which is optional in the grammar vs something
         // which is optional because the user hasn't entered it yet (or made a mistake)
-        identifier: Option<Token<'a>>,
+        /// The name of the command being defined. This is usually a single
+        /// token, but may be several for subcommands such as
+        /// `define target hookpost-foo`.
+        identifier: Vec<Token<'a>>,
+        /// `define` takes a single command name, so any tokens after it are
+        /// unexpected.
+        unexpected: Vec<Token<'a>>,
+        body: Vec<Command<'a>>,
+        end: Option<Token<'a>>,
+    },
+    /// A `define-prefix`, which makes a command that user defined subcommands
+    /// can be defined under, as in `define-prefix my-prefix` followed by
+    /// `define my-prefix sub`.
+    DefinePrefix {
+        define_prefix: Token<'a>,
+        /// The name of the prefix command, which may itself be a subcommand.
+        identifier: Vec<Token<'a>>,
+    },
+    Document {
+        document: Token<'a>,
+        /// The name of the command being documented, which like the `define`
+        /// identifier may be several tokens.
+        identifier: Vec<Token<'a>>,
+        /// The help text, which is not parsed as commands.
+        body: Vec<CommandLine<'a>>,
+        end: Option<Token<'a>>,
+    },
+    /// A block of code in another language, such as `python ... end`.
+    Embedded {
+        language: Token<'a>,
+        /// The raw text of the block, which is not parsed.
+        // This is only read by the parser tests, through the `Debug` impl, since
+        // nothing looks into embedded code yet.
+        #[allow(dead_code)]
+        body_text: String,
+        end: Option<Token<'a>>,
+    },
+    /// An `if` block, with an optional `else` branch.
+    ///
+    /// An `else if` chain is parsed as an `else` whose body is a single nested
+    /// `If`, which shares the `end` of the outer `if`. So `if a`, `else if b`,
+    /// `else`, `end` is an `If` with an `else_body` containing an `If` for `b`,
+    /// which has the last `else` branch.
+    If {
+        r#if: Token<'a>,
+        condition: Vec<Token<'a>>,
+        body: Vec<Command<'a>>,
+        r#else: Option<Token<'a>>,
+        else_body: Vec<Command<'a>>,
+        end: Option<Token<'a>>,
+    },
+    While {
+        r#while: Token<'a>,
+        condition: Vec<Token<'a>>,
+        body: Vec<Command<'a>>,
+        end: Option<Token<'a>>,
+    },
+    /// A list of commands to run when a breakpoint is hit, as in
+    /// `commands 1 ... end`.
+    Commands {
+        commands: Token<'a>,
+        /// The breakpoints the commands are attached to, which is empty for the
+        /// most recently set breakpoint.
+        breakpoint_spec: Vec<Token<'a>>,
         body: Vec<Command<'a>>,
         end: Option<Token<'a>>,
-        // TODO
-        // add ability to track unexpected tokens and add tests for this
     },
     Source {
         source: Token<'a>,
+        /// The `-s` and `-v` flags which may precede the file path.
+        flags: Vec<Token<'a>>,
         file_path: Option<Token<'a>>,
+        /// `source` takes a single file path, so any tokens after it are
+        /// unexpected.
+        extra: Vec<Token<'a>>,
+    },
+    /// An assignment to a convenience variable, as in `set $name = value` or
+    /// `set var $name = value`.
+    SetVar {
+        set: Token<'a>,
+        /// The variable being assigned, including the leading `$`.
+        name: Token<'a>,
+        /// The expression being assigned, not including the `=`.
+        value: Vec<Token<'a>>,
+    },
+    /// A shell command, as in `shell ls -la` or `!ls -la`. The command is
+    /// passed to the shell as written, so it is kept as a single token rather
+    /// than being split into arguments.
+    Shell {
+        /// The `shell` or `!` token.
+        shell: Token<'a>,
+        command: Option<Token<'a>>,
     },
     Other {
         command: Token<'a>,
@@ -67,70 +249,533 @@ pub(crate) enum Command<'a> {
     },
 }
 
-pub(crate) fn parse(input: &str) -> Vec<Command> {
-    parse_until(&mut iters::lines(input).into_iter(), false).0
+impl<'a> Command<'a> {
+    /// The location of the first token of this command.
+    pub(crate) fn start_location(&self) -> Location {
+        match self {
+            Command::Define { define: first, .. }
+            | Command::DefinePrefix {
+                define_prefix: first,
+                ..
+            }
+            | Command::Document {
+                document: first, ..
+            }
+            | Command::Embedded {
+                language: first, ..
+            }
+            | Command::If { r#if: first, .. }
+            | Command::While { r#while: first, .. }
+            | Command::Commands {
+                commands: first, ..
+            }
+            | Command::Source { source: first, .. }
+            | Command::SetVar { set: first, .. }
+            | Command::Shell { shell: first, .. }
+            | Command::Other { command: first, .. } => first.location_in_file,
+        }
+    }
+
+    /// Returns the text of this command exactly as written in the given source,
+    /// which must be the text it was parsed from. This runs from the first token
+    /// through the last, including any escaped newlines and nested commands, but
+    /// not a trailing comment.
+    pub(crate) fn raw_text<'s>(&self, source: &'s str) -> &'s str {
+        let start = offset_of(source, self.start_location(), PositionEncoding::Utf16);
+        let end = offset_of(source, self.end_location(), PositionEncoding::Utf16);
+
+        &source[start..end]
+    }
+
+    /// The location just past the last token which is part of this command.
+    pub(crate) fn end_location(&self) -> Location {
+        match self {
+            Command::Define {
+                define,
+                identifier,
+                unexpected,
+                body,
+                end,
+            } => end
+                .as_ref()
+                .map(Token::end_location)
+                .or_else(|| body.last().map(Command::end_location))
+                .or_else(|| unexpected.last().map(Token::end_location))
+                .or_else(|| identifier.last().map(Token::end_location))
+                .unwrap_or_else(|| define.end_location()),
+            Command::DefinePrefix {
+                define_prefix,
+                identifier,
+            } => identifier.last().unwrap_or(define_prefix).end_location(),
+            Command::Document {
+                document,
+                identifier,
+                body,
+                end,
+            } => end
+                .as_ref()
+                .map(Token::end_location)
+                .or_else(|| {
+                    body.last().map(|line| Location {
+                        line: line.end_line(),
+                        column: line
+                            .text
+                            .trim_end_matches('\n')
+                            .rsplit('\n')
+                            .next()
+                            .map_or(0, utf16_len),
+                    })
+                })
+                .or_else(|| identifier.last().map(Token::end_location))
+                .unwrap_or_else(|| document.end_location()),
+            Command::Embedded { language, end, .. } => {
+                end.as_ref().unwrap_or(language).end_location()
+            }
+            Command::If {
+                r#if,
+                condition,
+                body,
+                r#else,
+                else_body,
+                end,
+            } => end
+                .as_ref()
+                .map(Token::end_location)
+                .or_else(|| else_body.last().map(Command::end_location))
+                .or_else(|| r#else.as_ref().map(Token::end_location))
+                .or_else(|| body.last().map(Command::end_location))
+                .or_else(|| condition.last().map(Token::end_location))
+                .unwrap_or_else(|| r#if.end_location()),
+            Command::While {
+                r#while,
+                condition,
+                body,
+                end,
+            } => end
+                .as_ref()
+                .map(Token::end_location)
+                .or_else(|| body.last().map(Command::end_location))
+                .or_else(|| condition.last().map(Token::end_location))
+                .unwrap_or_else(|| r#while.end_location()),
+            Command::Commands {
+                commands,
+                breakpoint_spec,
+                body,
+                end,
+            } => end
+                .as_ref()
+                .map(Token::end_location)
+                .or_else(|| body.last().map(Command::end_location))
+                .or_else(|| breakpoint_spec.last().map(Token::end_location))
+                .unwrap_or_else(|| commands.end_location()),
+            Command::SetVar { name, value, .. } => value.last().unwrap_or(name).end_location(),
+            Command::Source {
+                source,
+                flags,
+                file_path,
+                extra,
+            } => extra
+                .last()
+                .or(file_path.as_ref())
+                .or_else(|| flags.last())
+                .unwrap_or(source)
+                .end_location(),
+            Command::Shell { shell, command } => command.as_ref().unwrap_or(shell).end_location(),
+            Command::Other { command, args } => args.last().unwrap_or(command).end_location(),
+        }
+    }
+}
+
+pub(crate) fn parse(input: &str) -> Vec<Command<'_>> {
+    parse_iter(input).collect()
+}
+
+/// Parses the given input one top level command at a time, for callers which
+/// may not need every command.
+pub(crate) fn parse_iter(input: &str) -> impl Iterator<Item = Command<'_>> {
+    Commands {
+        input: Statements::new(iters::lines(input)),
+    }
+}
+
+/// An iterator over the top level commands of a script, returned by
+/// `parse_iter`. Each command, including any nested commands, is parsed when it
+/// is reached.
+struct Commands<'a, I> {
+    input: Statements<'a, I>,
 }
 
-// TODO clean up this function signature
-//
-// it is really two functions, the Option<CommandLine> is always None
-// if until_end is false
-//
-// if until_end is true, it is Some assuming the script is well
-// formed (not missing an end)
+impl<'a, I: Iterator<Item = CommandLine<'a>>> Iterator for Commands<'a, I> {
+    type Item = Command<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let statement = self.input.next_statement()?;
+            if let Some(command) = parse_statement(statement, &mut self.input) {
+                return Some(command);
+            }
+        }
+    }
+}
+
+/// The input to the parser, which yields the statements of each command line in
+/// turn. Bodies which aren't parsed as commands, such as the help text of a
+/// `document`, are read from `lines` directly.
+struct Statements<'a, I> {
+    lines: I,
+    /// The statements remaining on the last command line read.
+    pending: VecDeque<Vec<Token<'a>>>,
+    /// The names of the commands made into prefixes by `define-prefix` so far,
+    /// which like built-in prefix commands may start the name given to `define`.
+    user_prefixes: Vec<&'a str>,
+}
+
+impl<'a, I: Iterator<Item = CommandLine<'a>>> Statements<'a, I> {
+    fn new(lines: I) -> Self {
+        Self {
+            lines,
+            pending: VecDeque::new(),
+            user_prefixes: vec![],
+        }
+    }
+
+    /// Returns the tokens of the next statement, which are never empty.
+    fn next_statement(&mut self) -> Option<Vec<Token<'a>>> {
+        loop {
+            if let Some(statement) = self.pending.pop_front() {
+                return Some(statement);
+            }
+            let line = self.lines.next()?;
+            self.pending.extend(iters::statements(&line));
+        }
+    }
+}
+
+/// Parses an `if` block, whose first line has already been read, through its
+/// `else` branch if it has one and the matching `end`.
+fn parse_if<'a>(
+    if_token: Token<'a>,
+    condition: Vec<Token<'a>>,
+    input: &mut Statements<'a, impl Iterator<Item = CommandLine<'a>>>,
+) -> Command<'a> {
+    let (body, terminator) = parse_until(input, &["else", "end"]);
+    let mut terminator_tokens = terminator.unwrap_or_default().into_iter();
+
+    let (r#else, else_body, end) = match terminator_tokens.next() {
+        Some(else_token @ Token { text: "else", .. }) => match terminator_tokens.next() {
+            Some(nested_if_token @ Token { text: "if", .. }) => {
+                let nested_if = parse_if(nested_if_token, terminator_tokens.collect(), input);
+                let end = match &nested_if {
+                    Command::If { end, .. } => end.clone(),
+                    _ => None,
+                };
+                (Some(else_token), vec![nested_if], end)
+            }
+            _ => {
+                let (else_body, end) = parse_until(input, &["end"]);
+                (
+                    Some(else_token),
+                    else_body,
+                    end.and_then(|end| end.into_iter().next()),
+                )
+            }
+        },
+        end => (None, vec![], end),
+    };
+
+    Command::If {
+        r#if: if_token,
+        condition,
+        body,
+        r#else,
+        else_body,
+        end,
+    }
+}
+
+/// Splits the tokens following `define` into the name of the command being
+/// defined and any unexpected tokens after it. The name is a single token,
+/// optionally preceded by prefix commands, as in `define target hookpost-foo`.
+/// These may be built-in or one of the given user defined prefixes.
+fn split_define_identifier<'a>(
+    mut tokens: Vec<Token<'a>>,
+    user_prefixes: &[&str],
+) -> (Vec<Token<'a>>, Vec<Token<'a>>) {
+    let name_len = tokens
+        .iter()
+        .position(|token| {
+            !built_ins::is_prefix_command(token.text) && !user_prefixes.contains(&token.text)
+        })
+        .map_or(tokens.len(), |index| index + 1);
+    let unexpected = tokens.split_off(name_len);
+
+    (tokens, unexpected)
+}
+
+/// Parses commands up to the first statement starting with one of the given
+/// terminators, returning the commands and that statement. The statement is
+/// `None` if the input ran out first, which for a block means it is missing its
+/// `end`.
 fn parse_until<'a>(
-    input: &mut impl Iterator<Item = CommandLine<'a>>,
-    until_end: bool,
-) -> (Vec<Command<'a>>, Option<CommandLine<'a>>) {
+    input: &mut Statements<'a, impl Iterator<Item = CommandLine<'a>>>,
+    terminators: &[&str],
+) -> (Vec<Command<'a>>, Option<Vec<Token<'a>>>) {
     let mut commands = vec![];
-    while let Some(line) = input.next() {
-        let mut tokens = iters::tokens(&line);
-        match tokens.next() {
-            Some(define_token @ Token { text: "define", .. }) => {
-                let (body, end_line) = parse_until(input, true);
-                commands.push(Command::Define {
-                    define: define_token,
-                    identifier: tokens.next(),
-                    body,
-                    // This unwrap is safe because parse_until until_end only returns a
-                    // command line if that command line has at least one token and
-                    // that token is `end`.
-                    //
-                    // TODO this should be removed when parse_until is reworked as
-                    // described in the todo above.
-                    end: end_line.map(|command_line| iters::tokens(&command_line).next().unwrap()),
-                });
-            }
-            Some(Token { text: "end", .. }) => {
-                if until_end {
-                    return (commands, Some(line));
-                }
+    while let Some(statement) = input.next_statement() {
+        if terminators.contains(&statement[0].text) {
+            return (commands, Some(statement));
+        }
+        commands.extend(parse_statement(statement, input));
+    }
+
+    (commands, None)
+}
+
+/// Parses the command starting with the given statement, reading the rest of
+/// its block from the input if it opens one. Returns `None` for statements which
+/// aren't commands, such as an `end` with no open block.
+fn parse_statement<'a>(
+    statement: Vec<Token<'a>>,
+    input: &mut Statements<'a, impl Iterator<Item = CommandLine<'a>>>,
+) -> Option<Command<'a>> {
+    let mut tokens = statement.into_iter();
+    let command = match tokens.next()? {
+        define_token @ Token { text: "define", .. } => {
+            let (identifier, unexpected) =
+                split_define_identifier(tokens.collect(), &input.user_prefixes);
+            let (body, end) = parse_until(input, &["end"]);
+            Command::Define {
+                define: define_token,
+                identifier,
+                unexpected,
+                body,
+                end: end.and_then(|end| end.into_iter().next()),
+            }
+        }
+        define_prefix_token @ Token {
+            text: "define-prefix",
+            ..
+        } => {
+            let identifier = tokens.collect::<Vec<Token>>();
+            if let Some(name) = identifier.last() {
+                input.user_prefixes.push(name.text);
             }
-            Some(source_token @ Token { text: "source", .. }) => {
-                commands.push(Command::Source {
-                    source: source_token,
-                    file_path: tokens.next(),
-                });
+            Command::DefinePrefix {
+                define_prefix: define_prefix_token,
+                identifier,
             }
-            Some(command) => {
-                commands.push(Command::Other {
-                    command,
-                    args: tokens.collect(),
-                });
+        }
+        document_token @ Token {
+            text: "document", ..
+        } => {
+            let (body, end) = parse_document_body(&mut input.lines);
+            Command::Document {
+                document: document_token,
+                identifier: tokens.collect(),
+                body,
+                end,
+            }
+        }
+        if_token @ Token { text: "if", .. } => parse_if(if_token, tokens.collect(), input),
+        while_token @ Token { text: "while", .. } => {
+            let condition = tokens.collect();
+            let (body, end) = parse_until(input, &["end"]);
+            Command::While {
+                r#while: while_token,
+                condition,
+                body,
+                end: end.and_then(|end| end.into_iter().next()),
+            }
+        }
+        commands_token @ Token {
+            text: "commands", ..
+        } => {
+            let breakpoint_spec = tokens.collect();
+            let (body, end) = parse_until(input, &["end"]);
+            Command::Commands {
+                commands: commands_token,
+                breakpoint_spec,
+                body,
+                end: end.and_then(|end| end.into_iter().next()),
+            }
+        }
+        // An `end` with no open block is ignored.
+        Token { text: "end", .. } => return None,
+        source_token @ Token { text: "source", .. } => {
+            let mut tokens = tokens.peekable();
+            let mut flags = vec![];
+            while let Some(flag) = tokens.next_if(|token| token.text == "-s" || token.text == "-v")
+            {
+                flags.push(flag);
+            }
+            Command::Source {
+                source: source_token,
+                flags,
+                file_path: tokens.next(),
+                extra: tokens.collect(),
+            }
+        }
+        set_token @ Token { text: "set", .. } => parse_set(set_token, tokens.collect()),
+        shell_token @ Token {
+            text: "shell" | "!",
+            ..
+        } => Command::Shell {
+            shell: shell_token,
+            command: tokens.next(),
+        },
+        language if EMBEDDED_LANGUAGES.contains(&language.text) => {
+            let args = tokens.collect::<Vec<Token>>();
+            if args.is_empty() {
+                let (body_text, end) = parse_embedded_body(&mut input.lines);
+                Command::Embedded {
+                    language,
+                    body_text,
+                    end,
+                }
+            } else {
+                // Code given on the same line, as in `python print(1)`, is a
+                // single command rather than the start of a block.
+                Command::Other {
+                    command: language,
+                    args,
+                }
             }
-            // Ignore empty lines
-            None => {}
         }
+        command => Command::Other {
+            command,
+            args: tokens.collect(),
+        },
+    };
+
+    Some(command)
+}
+
+/// Commands which begin a block of code in another language when given without
+/// arguments.
+const EMBEDDED_LANGUAGES: &[&str] = &["python", "py", "guile", "gu"];
+
+/// Collects the raw text of an embedded code block up to the first line which
+/// is exactly `end`.
+fn parse_embedded_body<'a>(
+    input: &mut impl Iterator<Item = CommandLine<'a>>,
+) -> (String, Option<Token<'a>>) {
+    let mut body_text = String::new();
+    for line in input {
+        if line.text.trim() == "end" {
+            return (body_text, iters::tokens(&line).next());
+        }
+        body_text.push_str(line.text);
     }
 
-    (commands, None)
+    (body_text, None)
+}
+
+/// Collects the lines of a `document` block up to its `end`. Unlike other
+/// blocks the body is help text, so keywords within it (for example a line
+/// starting with `define`) do not open nested blocks.
+fn parse_document_body<'a>(
+    input: &mut impl Iterator<Item = CommandLine<'a>>,
+) -> (Vec<CommandLine<'a>>, Option<Token<'a>>) {
+    let mut body = vec![];
+    for line in input {
+        if let Some(end_token @ Token { text: "end", .. }) = iters::tokens(&line).next() {
+            return (body, Some(end_token));
+        }
+        body.push(line);
+    }
+
+    (body, None)
+}
+
+/// Registers which may be assigned with `set`, as in `set $pc = 0x1000`. These
+/// are GDB's standard register names and the named registers of common
+/// architectures. Numbered registers such as `$r0` are left out, since they
+/// are easily mistaken for convenience variables.
+const REGISTERS: &[&str] = &[
+    "$pc", "$sp", "$fp", "$ps", "$rip", "$rsp", "$rbp", "$rax", "$rbx", "$rcx", "$rdx", "$rsi",
+    "$rdi", "$eflags", "$eip", "$esp", "$ebp", "$eax", "$ebx", "$ecx", "$edx", "$esi", "$edi",
+    "$lr", "$cpsr",
+];
+
+/// Parses a `set` command, which is a `SetVar` if it assigns a convenience
+/// variable and otherwise, for example when changing a setting or writing to a
+/// register, is `Other`.
+fn parse_set<'a>(set: Token<'a>, args: Vec<Token<'a>>) -> Command<'a> {
+    let mut rest = &args[..];
+    if let Some(Token {
+        text: "var" | "variable",
+        ..
+    }) = rest.first()
+    {
+        rest = &rest[1..];
+    }
+
+    let set_var = rest.split_first().and_then(|(first, rest)| {
+        if !first.text.starts_with('$') {
+            return None;
+        }
+
+        // The `=` may be written without surrounding whitespace, as in
+        // `set $counter=0`, so it is not always a separate token.
+        let (name, first_value) = match first.text.find('=') {
+            Some(index) => (
+                split_token(first, 0, index),
+                Some(split_token(first, index + 1, first.text.len())),
+            ),
+            None => {
+                let (equals, _) = rest.split_first()?;
+                if !equals.text.starts_with('=') || equals.text.starts_with("==") {
+                    return None;
+                }
+                (
+                    first.clone(),
+                    Some(split_token(equals, 1, equals.text.len())),
+                )
+            }
+        };
+        if name.text.len() < 2 || REGISTERS.contains(&name.text) {
+            return None;
+        }
+        let rest = if first.text.contains('=') {
+            rest
+        } else {
+            &rest[1..]
+        };
+
+        Some(Command::SetVar {
+            set: set.clone(),
+            name,
+            value: first_value
+                .into_iter()
+                .filter(|token| !token.text.is_empty())
+                .chain(rest.iter().cloned())
+                .collect(),
+        })
+    });
+
+    set_var.unwrap_or(Command::Other { command: set, args })
+}
+
+/// Returns the part of the given token between the given byte offsets.
+fn split_token<'a>(token: &Token<'a>, start: usize, end: usize) -> Token<'a> {
+    Token {
+        text: &token.text[start..end],
+        location_in_file: Location {
+            line: token.location_in_file.line,
+            column: token.location_in_file.column + utf16_len(&token.text[..start]),
+        },
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use expect_test::{expect, Expect};
 
-    use super::parse;
+    use super::{
+        iters, location_of, offset_of, parse, parse_iter, parse_until, Command, Location,
+        Statements,
+    };
+    use crate::PositionEncoding;
 
     fn check_lex_and_parse(input: &str, expect_parse: Expect) {
         expect_parse.assert_eq(
@@ -142,6 +787,16 @@ mod tests {
         );
     }
 
+    fn check_raw_text(input: &str, expect_raw_text: Expect) {
+        expect_raw_text.assert_eq(
+            &parse(input)
+                .into_iter()
+                .map(|command| format!("{:?}\n", command.raw_text(input)))
+                .collect::<Vec<String>>()
+                .join(""),
+        );
+    }
+
     #[test]
     fn commands() {
         let script = r#"
@@ -229,7 +884,7 @@ end
                             column: 0,
                         },
                     },
-                    identifier: Some(
+                    identifier: [
                         Token {
                             text: "say_hi",
                             location_in_file: Location {
@@ -237,7 +892,8 @@ end
                                 column: 7,
                             },
                         },
-                    ),
+                    ],
+                    unexpected: [],
                     body: [
                         Other {
                             command: Token {
@@ -271,4 +927,2048 @@ end
             "#]],
         );
     }
+
+    #[test]
+    fn while_loop() {
+        let script = r#"
+while $i < 3
+    loop_continue
+end
+        "#;
+
+        check_lex_and_parse(
+            script,
+            expect![[r#"
+            While {
+                while: Token {
+                    text: "while",
+                    location_in_file: Location {
+                        line: 1,
+                        column: 0,
+                    },
+                },
+                condition: [
+                    Token {
+                        text: "$i",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 6,
+                        },
+                    },
+                    Token {
+                        text: "<",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 9,
+                        },
+                    },
+                    Token {
+                        text: "3",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 11,
+                        },
+                    },
+                ],
+                body: [
+                    Other {
+                        command: Token {
+                            text: "loop_continue",
+                            location_in_file: Location {
+                                line: 2,
+                                column: 4,
+                            },
+                        },
+                        args: [],
+                    },
+                ],
+                end: Some(
+                    Token {
+                        text: "end",
+                        location_in_file: Location {
+                            line: 3,
+                            column: 0,
+                        },
+                    },
+                ),
+            }
+        "#]],
+        );
+    }
+
+    #[test]
+    fn while_loop_nested_in_define() {
+        let script = r#"
+define count
+    while $i < $arg0
+        loop_break
+    end
+end
+        "#;
+
+        check_lex_and_parse(
+            script,
+            expect![[r#"
+                Define {
+                    define: Token {
+                        text: "define",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 0,
+                        },
+                    },
+                    identifier: [
+                        Token {
+                            text: "count",
+                            location_in_file: Location {
+                                line: 1,
+                                column: 7,
+                            },
+                        },
+                    ],
+                    unexpected: [],
+                    body: [
+                        While {
+                            while: Token {
+                                text: "while",
+                                location_in_file: Location {
+                                    line: 2,
+                                    column: 4,
+                                },
+                            },
+                            condition: [
+                                Token {
+                                    text: "$i",
+                                    location_in_file: Location {
+                                        line: 2,
+                                        column: 10,
+                                    },
+                                },
+                                Token {
+                                    text: "<",
+                                    location_in_file: Location {
+                                        line: 2,
+                                        column: 13,
+                                    },
+                                },
+                                Token {
+                                    text: "$arg0",
+                                    location_in_file: Location {
+                                        line: 2,
+                                        column: 15,
+                                    },
+                                },
+                            ],
+                            body: [
+                                Other {
+                                    command: Token {
+                                        text: "loop_break",
+                                        location_in_file: Location {
+                                            line: 3,
+                                            column: 8,
+                                        },
+                                    },
+                                    args: [],
+                                },
+                            ],
+                            end: Some(
+                                Token {
+                                    text: "end",
+                                    location_in_file: Location {
+                                        line: 4,
+                                        column: 4,
+                                    },
+                                },
+                            ),
+                        },
+                    ],
+                    end: Some(
+                        Token {
+                            text: "end",
+                            location_in_file: Location {
+                                line: 5,
+                                column: 0,
+                            },
+                        },
+                    ),
+                }
+            "#]],
+        );
+    }
+
+    #[test]
+    fn source() {
+        check_lex_and_parse(
+            "source foo.gdb",
+            expect![[r#"
+            Source {
+                source: Token {
+                    text: "source",
+                    location_in_file: Location {
+                        line: 0,
+                        column: 0,
+                    },
+                },
+                flags: [],
+                file_path: Some(
+                    Token {
+                        text: "foo.gdb",
+                        location_in_file: Location {
+                            line: 0,
+                            column: 7,
+                        },
+                    },
+                ),
+                extra: [],
+            }
+        "#]],
+        );
+    }
+
+    #[test]
+    fn source_verbose() {
+        check_lex_and_parse(
+            "source -v foo.gdb",
+            expect![[r#"
+            Source {
+                source: Token {
+                    text: "source",
+                    location_in_file: Location {
+                        line: 0,
+                        column: 0,
+                    },
+                },
+                flags: [
+                    Token {
+                        text: "-v",
+                        location_in_file: Location {
+                            line: 0,
+                            column: 7,
+                        },
+                    },
+                ],
+                file_path: Some(
+                    Token {
+                        text: "foo.gdb",
+                        location_in_file: Location {
+                            line: 0,
+                            column: 10,
+                        },
+                    },
+                ),
+                extra: [],
+            }
+        "#]],
+        );
+    }
+
+    #[test]
+    fn source_extra_args() {
+        check_lex_and_parse(
+            "source a.gdb b.gdb",
+            expect![[r#"
+            Source {
+                source: Token {
+                    text: "source",
+                    location_in_file: Location {
+                        line: 0,
+                        column: 0,
+                    },
+                },
+                flags: [],
+                file_path: Some(
+                    Token {
+                        text: "a.gdb",
+                        location_in_file: Location {
+                            line: 0,
+                            column: 7,
+                        },
+                    },
+                ),
+                extra: [
+                    Token {
+                        text: "b.gdb",
+                        location_in_file: Location {
+                            line: 0,
+                            column: 13,
+                        },
+                    },
+                ],
+            }
+        "#]],
+        );
+    }
+
+    #[test]
+    fn subcommand_definition() {
+        let script = r#"
+define target hookpost-foo
+end
+        "#;
+
+        check_lex_and_parse(
+            script,
+            expect![[r#"
+                Define {
+                    define: Token {
+                        text: "define",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 0,
+                        },
+                    },
+                    identifier: [
+                        Token {
+                            text: "target",
+                            location_in_file: Location {
+                                line: 1,
+                                column: 7,
+                            },
+                        },
+                        Token {
+                            text: "hookpost-foo",
+                            location_in_file: Location {
+                                line: 1,
+                                column: 14,
+                            },
+                        },
+                    ],
+                    unexpected: [],
+                    body: [],
+                    end: Some(
+                        Token {
+                            text: "end",
+                            location_in_file: Location {
+                                line: 2,
+                                column: 0,
+                            },
+                        },
+                    ),
+                }
+            "#]],
+        );
+    }
+
+    #[test]
+    fn define_unexpected_tokens() {
+        let script = r#"
+define foo bar baz
+end
+        "#;
+
+        check_lex_and_parse(
+            script,
+            expect![[r#"
+            Define {
+                define: Token {
+                    text: "define",
+                    location_in_file: Location {
+                        line: 1,
+                        column: 0,
+                    },
+                },
+                identifier: [
+                    Token {
+                        text: "foo",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 7,
+                        },
+                    },
+                ],
+                unexpected: [
+                    Token {
+                        text: "bar",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 11,
+                        },
+                    },
+                    Token {
+                        text: "baz",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 15,
+                        },
+                    },
+                ],
+                body: [],
+                end: Some(
+                    Token {
+                        text: "end",
+                        location_in_file: Location {
+                            line: 2,
+                            column: 0,
+                        },
+                    },
+                ),
+            }
+        "#]],
+        );
+    }
+
+    #[test]
+    fn document_after_define() {
+        let script = r#"
+define say_hi
+end
+document say_hi
+Says hi.
+end
+        "#;
+
+        check_lex_and_parse(
+            script,
+            expect![[r#"
+                Define {
+                    define: Token {
+                        text: "define",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 0,
+                        },
+                    },
+                    identifier: [
+                        Token {
+                            text: "say_hi",
+                            location_in_file: Location {
+                                line: 1,
+                                column: 7,
+                            },
+                        },
+                    ],
+                    unexpected: [],
+                    body: [],
+                    end: Some(
+                        Token {
+                            text: "end",
+                            location_in_file: Location {
+                                line: 2,
+                                column: 0,
+                            },
+                        },
+                    ),
+                }
+                Document {
+                    document: Token {
+                        text: "document",
+                        location_in_file: Location {
+                            line: 3,
+                            column: 0,
+                        },
+                    },
+                    identifier: [
+                        Token {
+                            text: "say_hi",
+                            location_in_file: Location {
+                                line: 3,
+                                column: 9,
+                            },
+                        },
+                    ],
+                    body: [
+                        CommandLine {
+                            text: "Says hi.\n",
+                            start_line_in_file: 4,
+                            num_lines: 1,
+                        },
+                    ],
+                    end: Some(
+                        Token {
+                            text: "end",
+                            location_in_file: Location {
+                                line: 5,
+                                column: 0,
+                            },
+                        },
+                    ),
+                }
+            "#]],
+        );
+    }
+
+    #[test]
+    fn if_else_block() {
+        let script = r#"
+if $argc == 1
+    echo one
+else
+    echo other
+end
+        "#;
+
+        check_lex_and_parse(
+            script,
+            expect![[r#"
+            If {
+                if: Token {
+                    text: "if",
+                    location_in_file: Location {
+                        line: 1,
+                        column: 0,
+                    },
+                },
+                condition: [
+                    Token {
+                        text: "$argc",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 3,
+                        },
+                    },
+                    Token {
+                        text: "==",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 9,
+                        },
+                    },
+                    Token {
+                        text: "1",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 12,
+                        },
+                    },
+                ],
+                body: [
+                    Other {
+                        command: Token {
+                            text: "echo",
+                            location_in_file: Location {
+                                line: 2,
+                                column: 4,
+                            },
+                        },
+                        args: [
+                            Token {
+                                text: "one",
+                                location_in_file: Location {
+                                    line: 2,
+                                    column: 9,
+                                },
+                            },
+                        ],
+                    },
+                ],
+                else: Some(
+                    Token {
+                        text: "else",
+                        location_in_file: Location {
+                            line: 3,
+                            column: 0,
+                        },
+                    },
+                ),
+                else_body: [
+                    Other {
+                        command: Token {
+                            text: "echo",
+                            location_in_file: Location {
+                                line: 4,
+                                column: 4,
+                            },
+                        },
+                        args: [
+                            Token {
+                                text: "other",
+                                location_in_file: Location {
+                                    line: 4,
+                                    column: 9,
+                                },
+                            },
+                        ],
+                    },
+                ],
+                end: Some(
+                    Token {
+                        text: "end",
+                        location_in_file: Location {
+                            line: 5,
+                            column: 0,
+                        },
+                    },
+                ),
+            }
+        "#]],
+        );
+    }
+
+    #[test]
+    fn if_else_if_chain() {
+        let script = r#"
+if $argc == 1
+    echo one
+else if $argc == 2
+    echo two
+else
+    echo other
+end
+        "#;
+
+        check_lex_and_parse(
+            script,
+            expect![[r#"
+            If {
+                if: Token {
+                    text: "if",
+                    location_in_file: Location {
+                        line: 1,
+                        column: 0,
+                    },
+                },
+                condition: [
+                    Token {
+                        text: "$argc",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 3,
+                        },
+                    },
+                    Token {
+                        text: "==",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 9,
+                        },
+                    },
+                    Token {
+                        text: "1",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 12,
+                        },
+                    },
+                ],
+                body: [
+                    Other {
+                        command: Token {
+                            text: "echo",
+                            location_in_file: Location {
+                                line: 2,
+                                column: 4,
+                            },
+                        },
+                        args: [
+                            Token {
+                                text: "one",
+                                location_in_file: Location {
+                                    line: 2,
+                                    column: 9,
+                                },
+                            },
+                        ],
+                    },
+                ],
+                else: Some(
+                    Token {
+                        text: "else",
+                        location_in_file: Location {
+                            line: 3,
+                            column: 0,
+                        },
+                    },
+                ),
+                else_body: [
+                    If {
+                        if: Token {
+                            text: "if",
+                            location_in_file: Location {
+                                line: 3,
+                                column: 5,
+                            },
+                        },
+                        condition: [
+                            Token {
+                                text: "$argc",
+                                location_in_file: Location {
+                                    line: 3,
+                                    column: 8,
+                                },
+                            },
+                            Token {
+                                text: "==",
+                                location_in_file: Location {
+                                    line: 3,
+                                    column: 14,
+                                },
+                            },
+                            Token {
+                                text: "2",
+                                location_in_file: Location {
+                                    line: 3,
+                                    column: 17,
+                                },
+                            },
+                        ],
+                        body: [
+                            Other {
+                                command: Token {
+                                    text: "echo",
+                                    location_in_file: Location {
+                                        line: 4,
+                                        column: 4,
+                                    },
+                                },
+                                args: [
+                                    Token {
+                                        text: "two",
+                                        location_in_file: Location {
+                                            line: 4,
+                                            column: 9,
+                                        },
+                                    },
+                                ],
+                            },
+                        ],
+                        else: Some(
+                            Token {
+                                text: "else",
+                                location_in_file: Location {
+                                    line: 5,
+                                    column: 0,
+                                },
+                            },
+                        ),
+                        else_body: [
+                            Other {
+                                command: Token {
+                                    text: "echo",
+                                    location_in_file: Location {
+                                        line: 6,
+                                        column: 4,
+                                    },
+                                },
+                                args: [
+                                    Token {
+                                        text: "other",
+                                        location_in_file: Location {
+                                            line: 6,
+                                            column: 9,
+                                        },
+                                    },
+                                ],
+                            },
+                        ],
+                        end: Some(
+                            Token {
+                                text: "end",
+                                location_in_file: Location {
+                                    line: 7,
+                                    column: 0,
+                                },
+                            },
+                        ),
+                    },
+                ],
+                end: Some(
+                    Token {
+                        text: "end",
+                        location_in_file: Location {
+                            line: 7,
+                            column: 0,
+                        },
+                    },
+                ),
+            }
+        "#]],
+        );
+    }
+
+    #[test]
+    fn if_block() {
+        let script = r#"
+if $argc == 1
+    echo one
+end
+        "#;
+
+        check_lex_and_parse(
+            script,
+            expect![[r#"
+                If {
+                    if: Token {
+                        text: "if",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 0,
+                        },
+                    },
+                    condition: [
+                        Token {
+                            text: "$argc",
+                            location_in_file: Location {
+                                line: 1,
+                                column: 3,
+                            },
+                        },
+                        Token {
+                            text: "==",
+                            location_in_file: Location {
+                                line: 1,
+                                column: 9,
+                            },
+                        },
+                        Token {
+                            text: "1",
+                            location_in_file: Location {
+                                line: 1,
+                                column: 12,
+                            },
+                        },
+                    ],
+                    body: [
+                        Other {
+                            command: Token {
+                                text: "echo",
+                                location_in_file: Location {
+                                    line: 2,
+                                    column: 4,
+                                },
+                            },
+                            args: [
+                                Token {
+                                    text: "one",
+                                    location_in_file: Location {
+                                        line: 2,
+                                        column: 9,
+                                    },
+                                },
+                            ],
+                        },
+                    ],
+                    else: None,
+                    else_body: [],
+                    end: Some(
+                        Token {
+                            text: "end",
+                            location_in_file: Location {
+                                line: 3,
+                                column: 0,
+                            },
+                        },
+                    ),
+                }
+            "#]],
+        );
+    }
+
+    #[test]
+    fn while_loop_missing_end() {
+        let script = r#"
+while 1
+    echo spin
+        "#;
+
+        check_lex_and_parse(
+            script,
+            expect![[r#"
+            While {
+                while: Token {
+                    text: "while",
+                    location_in_file: Location {
+                        line: 1,
+                        column: 0,
+                    },
+                },
+                condition: [
+                    Token {
+                        text: "1",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 6,
+                        },
+                    },
+                ],
+                body: [
+                    Other {
+                        command: Token {
+                            text: "echo",
+                            location_in_file: Location {
+                                line: 2,
+                                column: 4,
+                            },
+                        },
+                        args: [
+                            Token {
+                                text: "spin",
+                                location_in_file: Location {
+                                    line: 2,
+                                    column: 9,
+                                },
+                            },
+                        ],
+                    },
+                ],
+                end: None,
+            }
+        "#]],
+        );
+    }
+
+    #[test]
+    fn shebang_line() {
+        check_lex_and_parse(
+            "#!/usr/bin/gdb -x\necho hi\n",
+            expect![[r#"
+            Other {
+                command: Token {
+                    text: "echo",
+                    location_in_file: Location {
+                        line: 1,
+                        column: 0,
+                    },
+                },
+                args: [
+                    Token {
+                        text: "hi",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 5,
+                        },
+                    },
+                ],
+            }
+        "#]],
+        );
+    }
+
+    #[test]
+    fn set_register() {
+        let script = r#"
+set $myvar = 1
+set $pc = 0x1000
+set var $sp=0
+set logging on
+        "#;
+
+        check_lex_and_parse(
+            script,
+            expect![[r#"
+            SetVar {
+                set: Token {
+                    text: "set",
+                    location_in_file: Location {
+                        line: 1,
+                        column: 0,
+                    },
+                },
+                name: Token {
+                    text: "$myvar",
+                    location_in_file: Location {
+                        line: 1,
+                        column: 4,
+                    },
+                },
+                value: [
+                    Token {
+                        text: "1",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 13,
+                        },
+                    },
+                ],
+            }
+            Other {
+                command: Token {
+                    text: "set",
+                    location_in_file: Location {
+                        line: 2,
+                        column: 0,
+                    },
+                },
+                args: [
+                    Token {
+                        text: "$pc",
+                        location_in_file: Location {
+                            line: 2,
+                            column: 4,
+                        },
+                    },
+                    Token {
+                        text: "=",
+                        location_in_file: Location {
+                            line: 2,
+                            column: 8,
+                        },
+                    },
+                    Token {
+                        text: "0x1000",
+                        location_in_file: Location {
+                            line: 2,
+                            column: 10,
+                        },
+                    },
+                ],
+            }
+            Other {
+                command: Token {
+                    text: "set",
+                    location_in_file: Location {
+                        line: 3,
+                        column: 0,
+                    },
+                },
+                args: [
+                    Token {
+                        text: "var",
+                        location_in_file: Location {
+                            line: 3,
+                            column: 4,
+                        },
+                    },
+                    Token {
+                        text: "$sp=0",
+                        location_in_file: Location {
+                            line: 3,
+                            column: 8,
+                        },
+                    },
+                ],
+            }
+            Other {
+                command: Token {
+                    text: "set",
+                    location_in_file: Location {
+                        line: 4,
+                        column: 0,
+                    },
+                },
+                args: [
+                    Token {
+                        text: "logging",
+                        location_in_file: Location {
+                            line: 4,
+                            column: 4,
+                        },
+                    },
+                    Token {
+                        text: "on",
+                        location_in_file: Location {
+                            line: 4,
+                            column: 12,
+                        },
+                    },
+                ],
+            }
+        "#]],
+        );
+    }
+
+    #[test]
+    fn set_var() {
+        let script = r#"
+set $counter = 0
+set var $total=$counter + 1
+set confirm off
+        "#;
+
+        check_lex_and_parse(
+            script,
+            expect![[r#"
+            SetVar {
+                set: Token {
+                    text: "set",
+                    location_in_file: Location {
+                        line: 1,
+                        column: 0,
+                    },
+                },
+                name: Token {
+                    text: "$counter",
+                    location_in_file: Location {
+                        line: 1,
+                        column: 4,
+                    },
+                },
+                value: [
+                    Token {
+                        text: "0",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 15,
+                        },
+                    },
+                ],
+            }
+            SetVar {
+                set: Token {
+                    text: "set",
+                    location_in_file: Location {
+                        line: 2,
+                        column: 0,
+                    },
+                },
+                name: Token {
+                    text: "$total",
+                    location_in_file: Location {
+                        line: 2,
+                        column: 8,
+                    },
+                },
+                value: [
+                    Token {
+                        text: "$counter",
+                        location_in_file: Location {
+                            line: 2,
+                            column: 15,
+                        },
+                    },
+                    Token {
+                        text: "+",
+                        location_in_file: Location {
+                            line: 2,
+                            column: 24,
+                        },
+                    },
+                    Token {
+                        text: "1",
+                        location_in_file: Location {
+                            line: 2,
+                            column: 26,
+                        },
+                    },
+                ],
+            }
+            Other {
+                command: Token {
+                    text: "set",
+                    location_in_file: Location {
+                        line: 3,
+                        column: 0,
+                    },
+                },
+                args: [
+                    Token {
+                        text: "confirm",
+                        location_in_file: Location {
+                            line: 3,
+                            column: 4,
+                        },
+                    },
+                    Token {
+                        text: "off",
+                        location_in_file: Location {
+                            line: 3,
+                            column: 12,
+                        },
+                    },
+                ],
+            }
+        "#]],
+        );
+    }
+
+    #[test]
+    fn python_block() {
+        let script = r#"
+python
+def hello():
+    if True:
+        print("end")
+end
+define say_hi
+    python print("hi")
+end
+        "#;
+
+        check_lex_and_parse(
+            script,
+            expect![[r#"
+                Embedded {
+                    language: Token {
+                        text: "python",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 0,
+                        },
+                    },
+                    body_text: "def hello():\n    if True:\n        print(\"end\")\n",
+                    end: Some(
+                        Token {
+                            text: "end",
+                            location_in_file: Location {
+                                line: 5,
+                                column: 0,
+                            },
+                        },
+                    ),
+                }
+                Define {
+                    define: Token {
+                        text: "define",
+                        location_in_file: Location {
+                            line: 6,
+                            column: 0,
+                        },
+                    },
+                    identifier: [
+                        Token {
+                            text: "say_hi",
+                            location_in_file: Location {
+                                line: 6,
+                                column: 7,
+                            },
+                        },
+                    ],
+                    unexpected: [],
+                    body: [
+                        Other {
+                            command: Token {
+                                text: "python",
+                                location_in_file: Location {
+                                    line: 7,
+                                    column: 4,
+                                },
+                            },
+                            args: [
+                                Token {
+                                    text: "print(\"hi\")",
+                                    location_in_file: Location {
+                                        line: 7,
+                                        column: 11,
+                                    },
+                                },
+                            ],
+                        },
+                    ],
+                    end: Some(
+                        Token {
+                            text: "end",
+                            location_in_file: Location {
+                                line: 8,
+                                column: 0,
+                            },
+                        },
+                    ),
+                }
+            "#]],
+        );
+    }
+
+    #[test]
+    fn raw_text_of_continued_command() {
+        let script = "echo foo \\\n    bar # comment\nprint 1\n";
+
+        check_raw_text(
+            script,
+            expect![[r#"
+            "echo foo \\\n    bar"
+            "print 1"
+        "#]],
+        );
+    }
+
+    #[test]
+    fn raw_text_of_block() {
+        let script = r#"
+  define say_hi
+    echo hi
+  end
+        "#;
+
+        check_raw_text(
+            script,
+            expect![[r#"
+            "define say_hi\n    echo hi\n  end"
+        "#]],
+        );
+    }
+
+    #[test]
+    fn command_separators() {
+        check_lex_and_parse(
+            "print 1; print 2",
+            expect![[r#"
+                Other {
+                    command: Token {
+                        text: "print",
+                        location_in_file: Location {
+                            line: 0,
+                            column: 0,
+                        },
+                    },
+                    args: [
+                        Token {
+                            text: "1",
+                            location_in_file: Location {
+                                line: 0,
+                                column: 6,
+                            },
+                        },
+                    ],
+                }
+                Other {
+                    command: Token {
+                        text: "print",
+                        location_in_file: Location {
+                            line: 0,
+                            column: 9,
+                        },
+                    },
+                    args: [
+                        Token {
+                            text: "2",
+                            location_in_file: Location {
+                                line: 0,
+                                column: 15,
+                            },
+                        },
+                    ],
+                }
+            "#]],
+        );
+    }
+
+    #[test]
+    fn parse_iter_matches_parse() {
+        let script = r#"
+define foo
+    if $argc == 1; echo one; else; echo other; end
+end
+end
+document foo
+    define inside help text
+end
+python
+print(1)
+end
+source -s other.gdb
+set $x = 1; echo $x
+while 1
+        "#;
+
+        // Nested blocks are collected by `parse_until`, so the top level commands
+        // should match what it collects for the whole script.
+        let format_commands = |commands: Vec<_>| format!("{:#?}", commands);
+        assert_eq!(
+            format_commands(parse_iter(script).collect()),
+            format_commands(parse_until(&mut Statements::new(iters::lines(script)), &[]).0),
+        );
+    }
+
+    #[test]
+    fn parse_iter_yields_commands_as_they_are_parsed() {
+        let script = r#"
+echo first
+define second
+end
+        "#;
+
+        let mut commands = parse_iter(script);
+        expect![[r#"
+            Some(
+                Other {
+                    command: Token {
+                        text: "echo",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 0,
+                        },
+                    },
+                    args: [
+                        Token {
+                            text: "first",
+                            location_in_file: Location {
+                                line: 1,
+                                column: 5,
+                            },
+                        },
+                    ],
+                },
+            )
+        "#]]
+        .assert_debug_eq(&commands.next());
+        assert!(commands.next().is_some());
+        assert!(commands.next().is_none());
+    }
+
+    #[test]
+    fn breakpoint_commands() {
+        let script = r#"
+commands 1
+    silent
+    print $pc
+end
+        "#;
+
+        check_lex_and_parse(
+            script,
+            expect![[r#"
+            Commands {
+                commands: Token {
+                    text: "commands",
+                    location_in_file: Location {
+                        line: 1,
+                        column: 0,
+                    },
+                },
+                breakpoint_spec: [
+                    Token {
+                        text: "1",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 9,
+                        },
+                    },
+                ],
+                body: [
+                    Other {
+                        command: Token {
+                            text: "silent",
+                            location_in_file: Location {
+                                line: 2,
+                                column: 4,
+                            },
+                        },
+                        args: [],
+                    },
+                    Other {
+                        command: Token {
+                            text: "print",
+                            location_in_file: Location {
+                                line: 3,
+                                column: 4,
+                            },
+                        },
+                        args: [
+                            Token {
+                                text: "$pc",
+                                location_in_file: Location {
+                                    line: 3,
+                                    column: 10,
+                                },
+                            },
+                        ],
+                    },
+                ],
+                end: Some(
+                    Token {
+                        text: "end",
+                        location_in_file: Location {
+                            line: 4,
+                            column: 0,
+                        },
+                    },
+                ),
+            }
+        "#]],
+        );
+    }
+
+    #[test]
+    fn breakpoint_commands_without_breakpoint_spec() {
+        let script = r#"
+define break_and_continue
+    break $arg0
+    commands
+        continue
+    end
+end
+        "#;
+
+        check_lex_and_parse(
+            script,
+            expect![[r#"
+            Define {
+                define: Token {
+                    text: "define",
+                    location_in_file: Location {
+                        line: 1,
+                        column: 0,
+                    },
+                },
+                identifier: [
+                    Token {
+                        text: "break_and_continue",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 7,
+                        },
+                    },
+                ],
+                unexpected: [],
+                body: [
+                    Other {
+                        command: Token {
+                            text: "break",
+                            location_in_file: Location {
+                                line: 2,
+                                column: 4,
+                            },
+                        },
+                        args: [
+                            Token {
+                                text: "$arg0",
+                                location_in_file: Location {
+                                    line: 2,
+                                    column: 10,
+                                },
+                            },
+                        ],
+                    },
+                    Commands {
+                        commands: Token {
+                            text: "commands",
+                            location_in_file: Location {
+                                line: 3,
+                                column: 4,
+                            },
+                        },
+                        breakpoint_spec: [],
+                        body: [
+                            Other {
+                                command: Token {
+                                    text: "continue",
+                                    location_in_file: Location {
+                                        line: 4,
+                                        column: 8,
+                                    },
+                                },
+                                args: [],
+                            },
+                        ],
+                        end: Some(
+                            Token {
+                                text: "end",
+                                location_in_file: Location {
+                                    line: 5,
+                                    column: 4,
+                                },
+                            },
+                        ),
+                    },
+                ],
+                end: Some(
+                    Token {
+                        text: "end",
+                        location_in_file: Location {
+                            line: 6,
+                            column: 0,
+                        },
+                    },
+                ),
+            }
+        "#]],
+        );
+    }
+
+    #[test]
+    fn else_token_locations() {
+        let script = r#"
+while 1
+    if $a; echo a; else; echo b; end
+    if $b
+        echo b
+      else
+        if $c
+        else
+            echo c
+        end
+    end
+end
+        "#;
+
+        // Collects the location of the `else` of each `if`, including nested ones.
+        fn collect_else_locations(commands: &[Command], locations: &mut Vec<String>) {
+            for command in commands {
+                match command {
+                    Command::If {
+                        r#if,
+                        body,
+                        r#else,
+                        else_body,
+                        ..
+                    } => {
+                        locations.push(format!(
+                            "if@{}:{} else@{}",
+                            r#if.location_in_file.line,
+                            r#if.location_in_file.column,
+                            r#else.as_ref().map_or("none".to_owned(), |r#else| format!(
+                                "{}:{}",
+                                r#else.location_in_file.line, r#else.location_in_file.column
+                            )),
+                        ));
+                        collect_else_locations(body, locations);
+                        collect_else_locations(else_body, locations);
+                    }
+                    Command::While { body, .. } => collect_else_locations(body, locations),
+                    _ => {}
+                }
+            }
+        }
+
+        let mut locations = vec![];
+        collect_else_locations(&parse(script), &mut locations);
+        expect![[r#"
+            if@2:4 else@2:19
+            if@3:4 else@5:6
+            if@6:8 else@7:8"#]]
+        .assert_eq(&locations.join("\n"));
+    }
+
+    #[test]
+    fn stray_else() {
+        let script = r#"
+else
+define foo
+    else
+    echo foo
+end
+        "#;
+
+        check_lex_and_parse(
+            script,
+            expect![[r#"
+            Other {
+                command: Token {
+                    text: "else",
+                    location_in_file: Location {
+                        line: 1,
+                        column: 0,
+                    },
+                },
+                args: [],
+            }
+            Define {
+                define: Token {
+                    text: "define",
+                    location_in_file: Location {
+                        line: 2,
+                        column: 0,
+                    },
+                },
+                identifier: [
+                    Token {
+                        text: "foo",
+                        location_in_file: Location {
+                            line: 2,
+                            column: 7,
+                        },
+                    },
+                ],
+                unexpected: [],
+                body: [
+                    Other {
+                        command: Token {
+                            text: "else",
+                            location_in_file: Location {
+                                line: 3,
+                                column: 4,
+                            },
+                        },
+                        args: [],
+                    },
+                    Other {
+                        command: Token {
+                            text: "echo",
+                            location_in_file: Location {
+                                line: 4,
+                                column: 4,
+                            },
+                        },
+                        args: [
+                            Token {
+                                text: "foo",
+                                location_in_file: Location {
+                                    line: 4,
+                                    column: 9,
+                                },
+                            },
+                        ],
+                    },
+                ],
+                end: Some(
+                    Token {
+                        text: "end",
+                        location_in_file: Location {
+                            line: 5,
+                            column: 0,
+                        },
+                    },
+                ),
+            }
+        "#]],
+        );
+    }
+
+    #[test]
+    fn define_prefix() {
+        let script = r#"
+define-prefix my-prefix
+define my-prefix say_hi
+end
+        "#;
+
+        check_lex_and_parse(
+            script,
+            expect![[r#"
+            DefinePrefix {
+                define_prefix: Token {
+                    text: "define-prefix",
+                    location_in_file: Location {
+                        line: 1,
+                        column: 0,
+                    },
+                },
+                identifier: [
+                    Token {
+                        text: "my-prefix",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 14,
+                        },
+                    },
+                ],
+            }
+            Define {
+                define: Token {
+                    text: "define",
+                    location_in_file: Location {
+                        line: 2,
+                        column: 0,
+                    },
+                },
+                identifier: [
+                    Token {
+                        text: "my-prefix",
+                        location_in_file: Location {
+                            line: 2,
+                            column: 7,
+                        },
+                    },
+                    Token {
+                        text: "say_hi",
+                        location_in_file: Location {
+                            line: 2,
+                            column: 17,
+                        },
+                    },
+                ],
+                unexpected: [],
+                body: [],
+                end: Some(
+                    Token {
+                        text: "end",
+                        location_in_file: Location {
+                            line: 3,
+                            column: 0,
+                        },
+                    },
+                ),
+            }
+        "#]],
+        );
+    }
+
+    #[test]
+    fn shell_command() {
+        check_lex_and_parse(
+            "shell ls -la",
+            expect![[r#"
+                Shell {
+                    shell: Token {
+                        text: "shell",
+                        location_in_file: Location {
+                            line: 0,
+                            column: 0,
+                        },
+                    },
+                    command: Some(
+                        Token {
+                            text: "ls -la",
+                            location_in_file: Location {
+                                line: 0,
+                                column: 6,
+                            },
+                        },
+                    ),
+                }
+            "#]],
+        );
+    }
+
+    #[test]
+    fn shell_escape() {
+        check_lex_and_parse(
+            "!echo hi # not a comment; echo still the shell\n! echo spaced\n!",
+            expect![[r##"
+                Shell {
+                    shell: Token {
+                        text: "!",
+                        location_in_file: Location {
+                            line: 0,
+                            column: 0,
+                        },
+                    },
+                    command: Some(
+                        Token {
+                            text: "echo hi # not a comment; echo still the shell",
+                            location_in_file: Location {
+                                line: 0,
+                                column: 1,
+                            },
+                        },
+                    ),
+                }
+                Shell {
+                    shell: Token {
+                        text: "!",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 0,
+                        },
+                    },
+                    command: Some(
+                        Token {
+                            text: "echo spaced",
+                            location_in_file: Location {
+                                line: 1,
+                                column: 2,
+                            },
+                        },
+                    ),
+                }
+                Shell {
+                    shell: Token {
+                        text: "!",
+                        location_in_file: Location {
+                            line: 2,
+                            column: 0,
+                        },
+                    },
+                    command: None,
+                }
+            "##]],
+        );
+    }
+
+    #[test]
+    fn shell_after_command_separator() {
+        check_lex_and_parse(
+            "echo a; shell ls; echo b\nshell ls \\\n  -la",
+            expect![[r#"
+                Other {
+                    command: Token {
+                        text: "echo",
+                        location_in_file: Location {
+                            line: 0,
+                            column: 0,
+                        },
+                    },
+                    args: [
+                        Token {
+                            text: "a",
+                            location_in_file: Location {
+                                line: 0,
+                                column: 5,
+                            },
+                        },
+                    ],
+                }
+                Shell {
+                    shell: Token {
+                        text: "shell",
+                        location_in_file: Location {
+                            line: 0,
+                            column: 8,
+                        },
+                    },
+                    command: Some(
+                        Token {
+                            text: "ls; echo b",
+                            location_in_file: Location {
+                                line: 0,
+                                column: 14,
+                            },
+                        },
+                    ),
+                }
+                Shell {
+                    shell: Token {
+                        text: "shell",
+                        location_in_file: Location {
+                            line: 1,
+                            column: 0,
+                        },
+                    },
+                    command: Some(
+                        Token {
+                            text: "ls \\\n  -la",
+                            location_in_file: Location {
+                                line: 1,
+                                column: 6,
+                            },
+                        },
+                    ),
+                }
+            "#]],
+        );
+    }
+
+    /// Builds random scripts out of fragments which are significant to the
+    /// parser, so unusual combinations of them are likely to be generated.
+    fn random_scripts(count: usize) -> impl Iterator<Item = String> {
+        const FRAGMENTS: &[&str] = &[
+            "define",
+            "define-prefix",
+            "document",
+            "end",
+            "if",
+            "else",
+            "while",
+            "commands",
+            "python",
+            "source",
+            "-s",
+            "set",
+            "var",
+            "$x",
+            "=",
+            "silent",
+            "shell",
+            "!",
+            "echo",
+            "foo",
+            " ",
+            "  ",
+            "\t",
+            "\n",
+            "\r\n",
+            "\r",
+            "\\",
+            "\\\n",
+            "\\\r\n",
+            "\"",
+            ";",
+            "#",
+            "é",
+            "😀",
+            "\u{0}",
+        ];
+
+        // A fixed seed keeps failures reproducible.
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        };
+
+        (0..count).map(move |_| {
+            let len = next() % 40;
+            (0..len)
+                .map(|_| FRAGMENTS[next() % FRAGMENTS.len()])
+                .collect()
+        })
+    }
+
+    #[test]
+    fn parse_random_scripts() {
+        for script in random_scripts(5_000) {
+            let commands = parse(&script);
+
+            for command in &commands {
+                let raw_text = command.raw_text(&script);
+                assert!(
+                    !raw_text.is_empty(),
+                    "empty command {:?} in {:?}",
+                    command,
+                    script
+                );
+            }
+
+            // Each token should be the text at its location in the script.
+            for line in iters::lines(&script) {
+                let line_tokens = iters::statements(&line).into_iter().flatten();
+                for token in line_tokens.chain(iters::comment(&line)) {
+                    let start = offset_of(&script, token.location_in_file, PositionEncoding::Utf16);
+                    assert_eq!(
+                        script.get(start..start + token.text.len()),
+                        Some(token.text),
+                        "token {:?} in {:?}",
+                        token,
+                        script
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn offsets_and_locations() {
+        let source = "echo a\r\n  é😀 b\n\nend";
+        let format_offset = |line, column, position_encoding| {
+            let offset = offset_of(source, Location { line, column }, position_encoding);
+            format!("{}:{} {} {:?}", line, column, offset, &source[offset..])
+        };
+
+        expect![[r#"
+            0:0 0 "echo a\r\n  é😀 b\n\nend"
+            0:6 6 "\r\n  é😀 b\n\nend"
+            1:0 8 "  é😀 b\n\nend"
+            1:7 18 "\n\nend"
+            2:0 19 "\nend"
+            3:0 20 "end"
+            3:3 23 ""
+            1:3 12 "😀 b\n\nend"
+            1:4 16 " b\n\nend"
+            1:5 16 " b\n\nend"
+            1:6 17 "b\n\nend"
+            0:7 6 "\r\n  é😀 b\n\nend"
+            2:1 19 "\nend"
+            3:4 23 ""
+            4:0 23 """#]]
+        .assert_eq(
+            &[
+                // The start and end of each line.
+                (0, 0),
+                (0, 6),
+                (1, 0),
+                (1, 7),
+                (2, 0),
+                (3, 0),
+                (3, 3),
+                // Multibyte characters, which are measured in UTF-16 code units.
+                (1, 3),
+                (1, 4),
+                (1, 5),
+                (1, 6),
+                // Past the end of a line, or of the file.
+                (0, 7),
+                (2, 1),
+                (3, 4),
+                (4, 0),
+            ]
+            .iter()
+            .map(|&(line, column)| format_offset(line, column, PositionEncoding::Utf16))
+            .collect::<Vec<String>>()
+            .join("\n"),
+        );
+
+        // In UTF-8, columns are measured in bytes.
+        expect![[r#"
+            1:4 12 "😀 b\n\nend"
+            1:5 16 " b\n\nend"
+            1:10 18 "\n\nend""#]]
+        .assert_eq(
+            &[(1, 4), (1, 5), (1, 10)]
+                .iter()
+                .map(|&(line, column)| format_offset(line, column, PositionEncoding::Utf8))
+                .collect::<Vec<String>>()
+                .join("\n"),
+        );
+
+        // Offsets of locations within the source map back to those locations.
+        for (line, column) in [(0, 0), (0, 6), (1, 3), (1, 7), (2, 0), (3, 3)] {
+            let location = Location { line, column };
+            let offset = offset_of(source, location, PositionEncoding::Utf16);
+            assert_eq!(Some(location), location_of(source, offset));
+        }
+        assert_eq!(None, location_of(source, 7));
+        assert_eq!(None, location_of(source, 11));
+        assert_eq!(None, location_of(source, source.len() + 1));
+    }
 }
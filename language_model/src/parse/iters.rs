@@ -1,119 +1,349 @@
-use super::{CommandLine, Location, Token};
+use super::{location_of, offset_of, utf16_len, CommandLine, Location, Token, EMBEDDED_LANGUAGES};
+use crate::PositionEncoding;
 
-pub(crate) fn lines(text: &str) -> impl Iterator<Item = CommandLine> {
+/// Splits the text of a file into command lines. Each line of the file, as an
+/// editor numbers them, belongs to exactly one command line, so the text after
+/// the last newline is always returned as a final command line even when it is
+/// empty. This means a file ending with a newline ends with an empty command
+/// line, and an empty file is a single empty command line. Blank lines are
+/// returned like any other, including several at the end of the file.
+///
+/// A backslash at the end of a line continues the command line onto the next,
+/// unless it is within double quotes, where it escapes the next character
+/// instead. Quotes are tracked the same way `scan` tracks them when splitting
+/// the command line into tokens, so a quote within a comment doesn't count.
+pub(crate) fn lines(text: &str) -> impl Iterator<Item = CommandLine<'_>> {
     let mut lines = vec![];
 
     let mut span_start = 0;
     let mut line_number = 0;
+    let mut num_lines = 1;
+    let mut continued = false;
     let mut escaped = false;
+    let mut in_quotes = false;
+    let mut in_comment = false;
+    let mut token_start = true;
 
     for (index, character) in text.char_indices() {
-        if character == '\n' && !escaped {
-            let span = span_start..index + 1;
-            lines.push(CommandLine {
-                text: &text[span.clone()],
-                start_line_in_file: line_number,
-            });
+        if character == '\n' {
+            if continued {
+                num_lines += 1;
+            } else {
+                let span = span_start..index + 1;
+                lines.push(CommandLine {
+                    text: &text[span],
+                    start_line_in_file: line_number,
+                    num_lines,
+                });
 
-            span_start = index + 1;
-            line_number += 1;
+                span_start = index + 1;
+                line_number += num_lines;
+                num_lines = 1;
+                // An unterminated string ends at the end of the line.
+                in_quotes = false;
+                in_comment = false;
+            }
+            continued = false;
+            escaped = false;
+            token_start = true;
+            continue;
         }
 
-        if character == '\\' {
-            escaped = true;
+        if in_quotes {
+            if character == '"' && !escaped {
+                in_quotes = false;
+            }
+            escaped = character == '\\' && !escaped;
         } else {
-            escaped = false;
+            // A backslash escapes the newline of a `\r\n` line ending too.
+            continued = character == '\\' || (continued && character == '\r');
+            if !in_comment {
+                if character == '#' && token_start {
+                    in_comment = true;
+                } else if character == '"' && !escaped {
+                    in_quotes = true;
+                }
+            }
+            escaped = character == '\\';
         }
+        token_start = character.is_whitespace();
+    }
+
+    lines.push(CommandLine {
+        text: &text[span_start..],
+        start_line_in_file: line_number,
+        num_lines,
+    });
+
+    lines.into_iter()
+}
+
+/// Returns the command line which includes the given line of the file, from
+/// the command lines of the file in order, as returned by `lines`. This may be
+/// a command line which starts above it and continues onto it.
+pub(crate) fn line_at<'a>(lines: &[CommandLine<'a>], line: usize) -> Option<CommandLine<'a>> {
+    let following = lines.partition_point(|command_line| command_line.start_line_in_file <= line);
+    let command_line = lines.get(following.checked_sub(1)?)?;
+    if line <= command_line.end_line() {
+        Some(command_line.clone())
+    } else {
+        None
     }
+}
+
+pub(crate) fn tokens<'line>(line: &CommandLine<'line>) -> impl Iterator<Item = Token<'line>> {
+    scan(line).0.into_iter()
+}
+
+/// Splits a command line into statements at each `;` which is not quoted or
+/// escaped, returning the tokens of each statement which has any. Lines running
+/// code in another language, such as `python print(1); print(2)`, are not split
+/// since the separator belongs to that code.
+///
+/// A shell command, as in `shell ls; ls` or `!ls`, takes the rest of the line.
+/// Its statement is the `shell` or `!`, followed by the command as a single
+/// token if there is one.
+pub(crate) fn statements<'line>(line: &CommandLine<'line>) -> Vec<Vec<Token<'line>>> {
+    let line_tokens = tokens(line).collect::<Vec<Token>>();
+    if line_tokens
+        .first()
+        .is_some_and(|token| EMBEDDED_LANGUAGES.contains(&token.text))
+    {
+        return vec![line_tokens];
+    }
+
+    let mut statements = vec![];
+    let mut statement = vec![];
+    for token in line_tokens {
+        if statement.is_empty() && is_shell_escape(token.text) {
+            statement = shell_statement(line, &token);
+            break;
+        }
+
+        let mut piece_start = 0;
+        let mut in_quotes = false;
+        let mut escaped = false;
+        for (index, character) in token.text.char_indices() {
+            if character == '"' && !escaped {
+                in_quotes = !in_quotes;
+            } else if character == ';' && !escaped && !in_quotes {
+                push_piece(&token, piece_start..index, &mut statement);
+                statements.push(std::mem::take(&mut statement));
+                piece_start = index + 1;
+            }
+            escaped = character == '\\' && !escaped;
+        }
+        push_piece(&token, piece_start..token.text.len(), &mut statement);
+    }
+    statements.push(statement);
+
+    statements.retain(|statement| !statement.is_empty());
+    statements
+}
 
-    if span_start <= text.len() {
-        let span = span_start..text.len();
-        lines.push(CommandLine {
-            text: &text[span.clone()],
-            start_line_in_file: line_number,
+/// Returns true if a statement starting with the given token runs a shell
+/// command.
+fn is_shell_escape(text: &str) -> bool {
+    text == "shell" || text.starts_with('!')
+}
+
+/// Returns the statement of the shell command starting with the given token,
+/// which runs to the end of the command line without being tokenized.
+fn shell_statement<'line>(line: &CommandLine<'line>, first: &Token<'line>) -> Vec<Token<'line>> {
+    // The command may directly follow the `!`, as in `!ls`.
+    let escape_len = if first.text.starts_with('!') {
+        1
+    } else {
+        first.text.len()
+    };
+    let location_in_line = Location {
+        line: first.location_in_file.line - line.start_line_in_file,
+        column: first.location_in_file.column,
+    };
+    let start = offset_of(line.text, location_in_line, PositionEncoding::Utf16) + escape_len;
+    let rest = &line.text[start..];
+    let command_text = rest.trim();
+
+    let mut statement = vec![Token {
+        text: &first.text[..escape_len],
+        location_in_file: first.location_in_file,
+    }];
+    if !command_text.is_empty() {
+        let command_start = start + (rest.len() - rest.trim_start().len());
+        let location = location_of(line.text, command_start).expect("offset is within the line");
+        statement.push(Token {
+            text: command_text,
+            location_in_file: Location {
+                line: line.start_line_in_file + location.line,
+                column: location.column,
+            },
         });
     }
 
-    lines.into_iter()
+    statement
+}
+
+/// Pushes the part of the given token in the given byte range onto the
+/// statement, unless it is empty.
+fn push_piece<'line>(
+    token: &Token<'line>,
+    range: std::ops::Range<usize>,
+    statement: &mut Vec<Token<'line>>,
+) {
+    if range.is_empty() {
+        return;
+    }
+
+    statement.push(Token {
+        text: &token.text[range.clone()],
+        location_in_file: Location {
+            line: token.location_in_file.line,
+            column: token.location_in_file.column + utf16_len(&token.text[..range.start]),
+        },
+    });
+}
+
+/// Returns the comment on the given command line, if it has one. The returned
+/// token runs from the `#` to the end of the line in the file it starts on.
+pub(crate) fn comment<'line>(line: &CommandLine<'line>) -> Option<Token<'line>> {
+    let comment = scan(line).1?;
+    // A `#` in a shell command is passed to the shell along with the rest of it.
+    if statements(line)
+        .iter()
+        .any(|statement| is_shell_escape(statement[0].text))
+    {
+        return None;
+    }
+
+    Some(comment)
 }
 
-pub(crate) fn tokens<'a, 'line>(
-    line: &'a CommandLine<'line>,
-) -> impl Iterator<Item = Token<'line>> {
+/// Splits a command line into its tokens, along with the comment which ends it
+/// if there is one.
+fn scan<'line>(line: &CommandLine<'line>) -> (Vec<Token<'line>>, Option<Token<'line>>) {
     let mut tokens = vec![];
 
-    let mut span_start = match line.text.find(|c: char| !c.is_whitespace()) {
-        Some(offset) => offset,
-        None => return tokens.into_iter(),
-    };
-    let mut currently_in_whitespace = false;
+    let mut span_start = 0;
+    let mut currently_in_whitespace = true;
     let mut escaped = false;
+    // A backslash before a newline continues the line unless it is quoted,
+    // matching how `lines` splits the file.
+    let mut continued = false;
+    // Whitespace within double quotes does not split tokens.
+    let mut in_quotes = false;
     let mut line_start_column = 0;
     let mut line_number = 0;
 
-    for (index, character) in line
-        .text
-        .char_indices()
-        .skip_while(|(_, c)| c.is_whitespace())
-    {
-        if character == '\n' && escaped {
+    for (index, character) in line.text.char_indices() {
+        // The `\r` of an escaped `\r\n` line ending is part of the line
+        // continuation, rather than whitespace ending the backslash token.
+        if character == '\r' && continued {
+            continue;
+        }
+        if character == '\n' && continued {
+            // A token running into the escaped newline ends before the backslash.
+            let backslash_index = line.text[..index].trim_end_matches('\r').len() - 1;
+            if !currently_in_whitespace && span_start < backslash_index {
+                tokens.push(Token {
+                    text: &line.text[span_start..backslash_index],
+                    location_in_file: Location {
+                        line: line.start_line_in_file + line_number,
+                        column: utf16_len(&line.text[line_start_column..span_start]),
+                    },
+                });
+            }
             escaped = false;
+            continued = false;
             currently_in_whitespace = true;
+            span_start = index + 1;
             line_start_column = index + 1;
             line_number += 1;
             continue;
         }
+        continued = character == '\\' && !in_quotes;
+
+        if in_quotes {
+            if character == '\n' {
+                // An unterminated string ends at the end of the line.
+                in_quotes = false;
+            } else {
+                if character == '"' && !escaped {
+                    in_quotes = false;
+                }
+                escaped = character == '\\' && !escaped;
+                continue;
+            }
+        }
 
         if character.is_whitespace() {
-            if !currently_in_whitespace {
+            if !currently_in_whitespace && span_start < index {
                 let span_in_line = span_start..index;
                 tokens.push(Token {
                     text: &line.text[span_in_line],
                     location_in_file: Location {
                         line: line.start_line_in_file + line_number,
-                        column: span_start - line_start_column,
+                        column: utf16_len(&line.text[line_start_column..span_start]),
                     },
                 });
             }
 
             currently_in_whitespace = true;
-        } else {
-            if currently_in_whitespace {
-                currently_in_whitespace = false;
-                span_start = index;
+        } else if currently_in_whitespace {
+            // A `#` at the start of a token begins a comment, which runs to the
+            // end of the command line.
+            if character == '#' {
+                let comment_text = &line.text[index..];
+                let comment_len = comment_text.find('\n').unwrap_or(comment_text.len());
+                let comment = Token {
+                    text: comment_text[..comment_len].trim_end_matches('\r'),
+                    location_in_file: Location {
+                        line: line.start_line_in_file + line_number,
+                        column: utf16_len(&line.text[line_start_column..index]),
+                    },
+                };
+                return (tokens, Some(comment));
             }
+
+            currently_in_whitespace = false;
+            span_start = index;
         }
 
-        if character == '\\' {
-            escaped = true;
-        } else {
-            escaped = false;
+        if character == '"' && !escaped {
+            in_quotes = true;
         }
+
+        escaped = character == '\\';
     }
-    if !currently_in_whitespace {
-        let span_in_line = span_start..line.text.len();
+    // A backslash at the end of the file continues the line onto nothing, so it
+    // is left out of the last token just as it is before a newline.
+    let end = if continued {
+        line.text.trim_end_matches('\r').len() - 1
+    } else {
+        line.text.len()
+    };
+    if !currently_in_whitespace && span_start < end {
+        let span_in_line = span_start..end;
         tokens.push(Token {
             text: &line.text[span_in_line],
             location_in_file: Location {
                 line: line.start_line_in_file + line_number,
-                column: span_start - line_start_column,
+                column: utf16_len(&line.text[line_start_column..span_start]),
             },
         });
     }
 
-    tokens.into_iter()
+    (tokens, None)
 }
 
 #[cfg(test)]
 mod tests {
     use expect_test::{expect, Expect};
 
-    use super::{lines, tokens, Token};
+    use super::{comment, line_at, lines, statements, tokens, CommandLine, Token};
 
     fn check_lines_and_tokens(input: &str, expect_parse: Expect) {
         expect_parse.assert_eq(
             &lines(input)
-                .into_iter()
                 .map(|line| tokens(&line))
                 .map(|s| format!("{:#?}\n", s.collect::<Vec<Token>>()))
                 .collect::<Vec<String>>()
@@ -248,6 +478,121 @@ command_with_one_arg \
         );
     }
 
+    #[test]
+    fn lines_and_tokens_escaped_newline_indentation() {
+        let format_tokens = |input| {
+            lines(input)
+                .flat_map(|line| tokens(&line).collect::<Vec<Token>>())
+                .map(|token| {
+                    format!(
+                        "{}@{}:{}",
+                        token.text, token.location_in_file.line, token.location_in_file.column
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+
+        expect![[r#"echo@0:0 a@0:5 b@1:0 c@1:2"#]].assert_eq(&format_tokens("echo a \\\nb c"));
+        expect![[r#"echo@0:0 a@0:5 b@1:1 c@1:3"#]].assert_eq(&format_tokens("echo a \\\n b c"));
+        expect![[r#"echo@0:0 a@0:5 b@1:8 c@1:10"#]]
+            .assert_eq(&format_tokens("echo a \\\n        b c"));
+        expect![[r#"echo@0:2 a@0:7 b@1:2 c@2:8"#]]
+            .assert_eq(&format_tokens("  echo a \\\n  b \\\n        c"));
+        expect![[r#"echo@0:0 a@0:5 b@1:1"#]].assert_eq(&format_tokens("echo a\\\n b"));
+    }
+
+    fn format_tokens(script: &str) -> String {
+        lines(script)
+            .map(|line| {
+                tokens(&line)
+                    .map(|token| {
+                        format!(
+                            "{:?}@{}:{}",
+                            token.text, token.location_in_file.line, token.location_in_file.column
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn lines_and_tokens_backslash_within_quotes() {
+        // Found by `parse_random_scripts`. Within quotes a backslash escapes the
+        // next character rather than the newline, so neither of these lines is
+        // continued.
+        expect![[r#"
+            "echo"@0:0 "\"a \\"@0:5
+            "b"@1:0 "c"@1:2
+            "echo"@2:0 "\"a \\\\"@2:5
+            "b"@3:0 "c"@3:2
+        "#]]
+        .assert_eq(&format_tokens("echo \"a \\\nb c\necho \"a \\\\\nb c\n"));
+    }
+
+    #[test]
+    fn lines_and_tokens_quoted_value_ending_with_backslash() {
+        let script = "echo \"ends with backslash\\\\\"\nb c\necho \"a\\\"\\\nb c\n";
+
+        expect![[r#"
+            "echo"@0:0 "\"ends with backslash\\\\\""@0:5
+            "b"@1:0 "c"@1:2
+            "echo"@2:0 "\"a\\\"\\"@2:5
+            "b"@3:0 "c"@3:2
+        "#]]
+        .assert_eq(&format_tokens(script));
+    }
+
+    #[test]
+    fn lines_and_tokens_backslash_at_end_of_file() {
+        // A backslash at the end of the file continues the line onto nothing, so
+        // it is tokenized as if it were followed by a newline.
+        expect![[r#""echo"@0:0 "hi"@0:5"#]].assert_eq(&format_tokens("echo hi \\"));
+        expect![[r#""echo"@0:0 "hi"@0:5"#]].assert_eq(&format_tokens("echo hi \\\n"));
+        expect![[r#""echo"@0:0 "hi"@0:5 "\\"@0:8"#]].assert_eq(&format_tokens("echo hi \\\\"));
+        expect![[r#""echo"@0:0 "hi"@0:5 "\\"@0:8"#]].assert_eq(&format_tokens("echo hi \\\\\n"));
+        expect![[r#""echo"@0:0 "hi"@0:5"#]].assert_eq(&format_tokens("echo hi\\"));
+        expect![[r#""#]].assert_eq(&format_tokens("\\"));
+        expect![[r#""#]].assert_eq(&format_tokens("\\\r"));
+    }
+
+    #[test]
+    fn lines_and_tokens_backslash_after_quotes() {
+        // A backslash after a string, or after a quote in a comment, still
+        // continues the line.
+        let script = "echo \"a\" \\\nb\necho a # \"quoted \\\nb\n";
+
+        expect![[r#"
+            "echo"@0:0 "\"a\""@0:5 "b"@1:0
+            "echo"@2:0 "a"@2:5
+        "#]]
+        .assert_eq(&format_tokens(script));
+    }
+
+    #[test]
+    fn lines_and_tokens_crlf() {
+        let script = "define foo\n    echo \"a b\" c \\\n        d # note\nend\n";
+        let format_lines = |input| {
+            lines(input)
+                .map(|line| {
+                    format!(
+                        "{:?} {:?}\n",
+                        tokens(&line).collect::<Vec<Token>>(),
+                        comment(&line)
+                    )
+                })
+                .collect::<String>()
+        };
+
+        assert_eq!(
+            format_lines(&script.replace('\n', "\r\n")),
+            format_lines(script)
+        );
+    }
+
     #[test]
     fn lines_and_tokens_empty_script() {
         let script = "";
@@ -259,4 +604,342 @@ command_with_one_arg \
         "#]],
         );
     }
+
+    #[test]
+    fn lines_at_end_of_file() {
+        let format_lines = |input| {
+            lines(input)
+                .map(|line| {
+                    format!(
+                        "{}+{} {:?}",
+                        line.start_line_in_file, line.num_lines, line.text
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+
+        expect![[r#"0+1 "echo a""#]].assert_eq(&format_lines("echo a"));
+        expect![[r#"0+1 "echo a\n" 1+1 """#]].assert_eq(&format_lines("echo a\n"));
+        expect![[r#"0+1 "echo a\n" 1+1 "\n" 2+1 "\n" 3+1 """#]]
+            .assert_eq(&format_lines("echo a\n\n\n"));
+        expect![[r#"0+1 "echo a\n" 1+1 "  \n" 2+1 "\t\n" 3+1 """#]]
+            .assert_eq(&format_lines("echo a\n  \n\t\n"));
+        expect![[r#"0+1 "echo a\r\n" 1+1 "\r\n" 2+1 """#]]
+            .assert_eq(&format_lines("echo a\r\n\r\n"));
+        expect![[r#"0+2 "echo a \\\n""#]].assert_eq(&format_lines("echo a \\\n"));
+        expect![[r#"0+1 "\n" 1+1 """#]].assert_eq(&format_lines("\n"));
+
+        // The empty last line of a file is still a line the cursor can be on.
+        let file_lines = lines("echo a\n").collect::<Vec<CommandLine>>();
+        assert_eq!(line_at(&file_lines, 1).map(|line| line.text), Some(""));
+        assert!(line_at(&file_lines, 2).is_none());
+    }
+
+    #[test]
+    fn line_at_continued_lines() {
+        let file_lines = lines("echo a \\\n  b\necho c\n").collect::<Vec<CommandLine>>();
+        let line_texts = (0..5)
+            .map(|line| format!("{:?}", line_at(&file_lines, line).map(|line| line.text)))
+            .collect::<Vec<String>>();
+
+        expect![[r#"
+            Some("echo a \\\n  b\n")
+            Some("echo a \\\n  b\n")
+            Some("echo c\n")
+            Some("")
+            None"#]]
+        .assert_eq(&line_texts.join("\n"));
+    }
+
+    #[test]
+    fn lines_and_tokens_comments() {
+        let script = r#"
+# full line comment
+define say_hi
+    # indented comment
+    echo hi # trailing comment
+end
+        "#;
+
+        check_lines_and_tokens(
+            script,
+            expect![[r#"
+            []
+            []
+            [
+                Token {
+                    text: "define",
+                    location_in_file: Location {
+                        line: 2,
+                        column: 0,
+                    },
+                },
+                Token {
+                    text: "say_hi",
+                    location_in_file: Location {
+                        line: 2,
+                        column: 7,
+                    },
+                },
+            ]
+            []
+            [
+                Token {
+                    text: "echo",
+                    location_in_file: Location {
+                        line: 4,
+                        column: 4,
+                    },
+                },
+                Token {
+                    text: "hi",
+                    location_in_file: Location {
+                        line: 4,
+                        column: 9,
+                    },
+                },
+            ]
+            [
+                Token {
+                    text: "end",
+                    location_in_file: Location {
+                        line: 5,
+                        column: 0,
+                    },
+                },
+            ]
+            []
+        "#]],
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let script = r##"
+# full line comment
+    echo hi # trailing comment
+echo "# not a comment"
+        "##;
+
+        expect![[r##"
+            Token {
+                text: "# full line comment",
+                location_in_file: Location {
+                    line: 1,
+                    column: 0,
+                },
+            }
+            Token {
+                text: "# trailing comment",
+                location_in_file: Location {
+                    line: 2,
+                    column: 12,
+                },
+            }
+        "##]]
+        .assert_eq(
+            &lines(script)
+                .filter_map(|line| comment(&line))
+                .map(|comment| format!("{:#?}\n", comment))
+                .collect::<Vec<String>>()
+                .join(""),
+        );
+    }
+
+    #[test]
+    fn lines_and_tokens_quoted_arg() {
+        check_lines_and_tokens(
+            r#"echo "hi""#,
+            expect![[r#"
+            [
+                Token {
+                    text: "echo",
+                    location_in_file: Location {
+                        line: 0,
+                        column: 0,
+                    },
+                },
+                Token {
+                    text: "\"hi\"",
+                    location_in_file: Location {
+                        line: 0,
+                        column: 5,
+                    },
+                },
+            ]
+        "#]],
+        );
+    }
+
+    #[test]
+    fn lines_and_tokens_quoted_arg_with_spaces() {
+        check_lines_and_tokens(
+            r#"printf "%s \"%d\"\n" foo 1"#,
+            expect![[r#"
+            [
+                Token {
+                    text: "printf",
+                    location_in_file: Location {
+                        line: 0,
+                        column: 0,
+                    },
+                },
+                Token {
+                    text: "\"%s \\\"%d\\\"\\n\"",
+                    location_in_file: Location {
+                        line: 0,
+                        column: 7,
+                    },
+                },
+                Token {
+                    text: "foo",
+                    location_in_file: Location {
+                        line: 0,
+                        column: 21,
+                    },
+                },
+                Token {
+                    text: "1",
+                    location_in_file: Location {
+                        line: 0,
+                        column: 25,
+                    },
+                },
+            ]
+        "#]],
+        );
+    }
+
+    #[test]
+    fn lines_and_tokens_unterminated_quote() {
+        let script = r#"
+echo "hello world
+echo done
+        "#;
+
+        check_lines_and_tokens(
+            script,
+            expect![[r#"
+            []
+            [
+                Token {
+                    text: "echo",
+                    location_in_file: Location {
+                        line: 1,
+                        column: 0,
+                    },
+                },
+                Token {
+                    text: "\"hello world",
+                    location_in_file: Location {
+                        line: 1,
+                        column: 5,
+                    },
+                },
+            ]
+            [
+                Token {
+                    text: "echo",
+                    location_in_file: Location {
+                        line: 2,
+                        column: 0,
+                    },
+                },
+                Token {
+                    text: "done",
+                    location_in_file: Location {
+                        line: 2,
+                        column: 5,
+                    },
+                },
+            ]
+            []
+        "#]],
+        );
+    }
+
+    #[test]
+    fn lines_continued_command() {
+        let script = "command \\\n    foo \\\n    bar\nnext_command";
+
+        let lines = lines(script).collect::<Vec<CommandLine>>();
+
+        assert_eq!(2, lines.len());
+        assert_eq!(0, lines[0].start_line_in_file);
+        assert_eq!(3, lines[0].num_lines);
+        assert_eq!(2, lines[0].end_line());
+        assert_eq!(3, lines[1].start_line_in_file);
+        assert_eq!(1, lines[1].num_lines);
+    }
+
+    #[test]
+    fn lines_and_tokens_non_ascii() {
+        // `é` is two bytes in UTF-8 but a single UTF-16 code unit, while `😀` is
+        // four bytes in UTF-8 and two UTF-16 code units.
+        check_lines_and_tokens(
+            "echo é 😀 foo",
+            expect![[r#"
+                [
+                    Token {
+                        text: "echo",
+                        location_in_file: Location {
+                            line: 0,
+                            column: 0,
+                        },
+                    },
+                    Token {
+                        text: "é",
+                        location_in_file: Location {
+                            line: 0,
+                            column: 5,
+                        },
+                    },
+                    Token {
+                        text: "😀",
+                        location_in_file: Location {
+                            line: 0,
+                            column: 7,
+                        },
+                    },
+                    Token {
+                        text: "foo",
+                        location_in_file: Location {
+                            line: 0,
+                            column: 10,
+                        },
+                    },
+                ]
+            "#]],
+        );
+    }
+
+    #[test]
+    fn statements_skip_quoted_and_escaped_separators() {
+        let format_statements = |input| {
+            lines(input)
+                .flat_map(|line| statements(&line))
+                .map(|statement| {
+                    statement
+                        .iter()
+                        .map(|token| format!("{}@{}", token.text, token.location_in_file.column))
+                        .collect::<Vec<String>>()
+                        .join(" ")
+                })
+                .collect::<Vec<String>>()
+                .join("\n")
+        };
+
+        expect![[r#"
+            echo@0 a@5
+            b@7
+            echo@12 "c;d"@17 e\;f@23
+            python@29 print(1)@36
+            print(2)@46"#]]
+        .assert_eq(&format_statements(
+            r#"echo a;b ;; echo "c;d" e\;f; python print(1); print(2)"#,
+        ));
+        expect![[r#"python@0 print(1);@7 print(2)@17"#]]
+            .assert_eq(&format_statements("python print(1); print(2)"));
+    }
 }
@@ -0,0 +1,70 @@
+//! A script stored alongside its parsed commands and command lines, so each
+//! file is parsed once when its text is set rather than on every query. Finding
+//! the command line or text of a line of the file is a lookup in these, rather
+//! than a scan of the text.
+//!
+//! The parsed commands borrow from the text, which makes this a self-referencing
+//! struct. The text is kept in an `Arc<str>`, so its heap allocation does not
+//! move when the `ParsedFile` does, and it is never modified. The commands are
+//! stored with a `'static` lifetime, but are only handed out with the lifetime
+//! of a borrow of the `ParsedFile`, so they can never outlive the text.
+
+use std::sync::Arc;
+
+use crate::parse::{iters, parse, Command, CommandLine};
+
+pub(crate) struct ParsedFile {
+    // These are declared before `text` so that they are dropped first.
+    commands: Vec<Command<'static>>,
+    lines: Vec<CommandLine<'static>>,
+    /// The byte offset in the text at which each line of the file starts.
+    line_starts: Vec<usize>,
+    text: Arc<str>,
+}
+
+impl ParsedFile {
+    pub(crate) fn new(text: String) -> Self {
+        let text: Arc<str> = Arc::from(text);
+        // SAFETY: The text is never modified or dropped while the commands
+        // exist, and the commands are never exposed with a lifetime longer than
+        // a borrow of `self`. See the module documentation.
+        let static_text: &'static str = unsafe { &*(&*text as *const str) };
+
+        Self {
+            commands: parse(static_text),
+            lines: iters::lines(static_text).collect(),
+            line_starts: std::iter::once(0)
+                .chain(static_text.match_indices('\n').map(|(index, _)| index + 1))
+                .collect(),
+            text,
+        }
+    }
+
+    pub(crate) fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub(crate) fn commands(&self) -> &[Command<'_>] {
+        &self.commands
+    }
+
+    pub(crate) fn lines(&self) -> &[CommandLine<'_>] {
+        &self.lines
+    }
+
+    /// Returns the text of the given line of the file, without its line ending.
+    pub(crate) fn line_text(&self, line: usize) -> Option<&str> {
+        let start = *self.line_starts.get(line)?;
+        let end = self
+            .line_starts
+            .get(line + 1)
+            .copied()
+            .unwrap_or_else(|| self.text.len());
+        let text = &self.text[start..end];
+
+        Some(match text.strip_suffix('\n') {
+            Some(text) => text.strip_suffix('\r').unwrap_or(text),
+            None => text,
+        })
+    }
+}
@@ -1,35 +1,49 @@
-use crate::parse::Location;
+use crate::parse::{utf16_len, Location};
 
 const CURSOR_SYMBOL: &str = "<|>";
 
 pub(crate) fn parse_cursor_position(script_containing_cursor: &str) -> (String, Location) {
-    let location = script_containing_cursor
-        .lines()
-        .enumerate()
-        .find_map(|(line, line_text)| {
-            // Find returns the byte offset, which isn't what we would want if we needed to
-            // support UTF-8, but for now we are only supporting ASCII.
-            let column = line_text.find(CURSOR_SYMBOL)?;
-            Some(Location { line, column })
-        })
-        .expect("script should contain cursor");
-
-    // We specifically only replace the first cursor instance here so we can check
-    // for multiple cursors below.
-    let script_without_cursor = script_containing_cursor.replacen(CURSOR_SYMBOL, "", 1);
-
-    if script_without_cursor.contains(CURSOR_SYMBOL) {
+    let (script_without_cursor, locations) = parse_cursor_positions(script_containing_cursor);
+
+    if locations.len() > 1 {
         panic!("script should only contain single cursor");
     }
 
-    (script_without_cursor, location)
+    (script_without_cursor, locations[0])
+}
+
+/// Like `parse_cursor_position`, but for scripts containing any number of
+/// cursors. The locations are in order, and are the positions in the returned
+/// script, so earlier cursors on the same line don't affect later columns.
+pub(crate) fn parse_cursor_positions(script_containing_cursors: &str) -> (String, Vec<Location>) {
+    let mut locations = vec![];
+    for (line, line_text) in script_containing_cursors.lines().enumerate() {
+        let mut rest = line_text;
+        let mut column = 0;
+        // Find returns the byte offset, but columns are measured in UTF-16 code
+        // units.
+        while let Some(byte_offset) = rest.find(CURSOR_SYMBOL) {
+            column += utf16_len(&rest[..byte_offset]);
+            locations.push(Location { line, column });
+            rest = &rest[byte_offset + CURSOR_SYMBOL.len()..];
+        }
+    }
+
+    if locations.is_empty() {
+        panic!("script should contain cursor");
+    }
+
+    (
+        script_containing_cursors.replace(CURSOR_SYMBOL, ""),
+        locations,
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use crate::parse::Location;
 
-    use super::parse_cursor_position;
+    use super::{parse_cursor_position, parse_cursor_positions};
 
     #[test]
     #[should_panic]
@@ -82,4 +96,47 @@ mod tests {
         assert_eq!("foo\nbar\nbaz", script);
         assert_eq!(Location { line: 1, column: 0 }, cursor_location);
     }
+
+    #[test]
+    fn after_non_ascii() {
+        let (script, cursor_location) = parse_cursor_position("é 😀 <|>bar");
+
+        assert_eq!("é 😀 bar", script);
+        assert_eq!(Location { line: 0, column: 5 }, cursor_location);
+    }
+
+    #[test]
+    fn two_cursors_on_one_line() {
+        let (script, cursor_locations) = parse_cursor_positions("<|>foo é<|>bar<|>");
+
+        assert_eq!("foo ébar", script);
+        assert_eq!(
+            vec![
+                Location { line: 0, column: 0 },
+                Location { line: 0, column: 5 },
+                Location { line: 0, column: 8 },
+            ],
+            cursor_locations
+        );
+    }
+
+    #[test]
+    fn cursors_on_different_lines() {
+        let (script, cursor_locations) = parse_cursor_positions("foo <|>\n\nbar\n  <|>baz");
+
+        assert_eq!("foo \n\nbar\n  baz", script);
+        assert_eq!(
+            vec![
+                Location { line: 0, column: 4 },
+                Location { line: 3, column: 2 },
+            ],
+            cursor_locations
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn multiple_cursors_panics_on_missing_cursor() {
+        parse_cursor_positions("no cursor");
+    }
 }